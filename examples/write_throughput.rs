@@ -0,0 +1,57 @@
+//! Measures write throughput with and without pipelined writes.
+//!
+//! `cargo run --release --example write_throughput [threads] [writes per thread]`
+
+use pipengine::{Db, Options, WriteBatch, WriteOptions};
+use std::env;
+use std::fs;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+fn run(pipelined_write: bool, sync: bool, threads: usize, writes: usize) -> f64 {
+    let path = format!("./DB_bench_write_throughput_{}_{}", pipelined_write, sync);
+    let _ = fs::remove_dir_all(&path);
+    let db = Arc::new(Db::create(&path, Options {
+        pipelined_write,
+        // the default is tiny, which would have the writes spend their time flushing.
+        write_buffer_size: 4 << 20,
+        ..Options::default()
+    }).unwrap());
+
+    let start = Instant::now();
+    let handles: Vec<_> = (0..threads).map(|thread| {
+        let db = db.clone();
+        thread::spawn(move || {
+            for i in 0..writes {
+                let mut batch = WriteBatch::new();
+                batch.put(format!("key-{}-{}", thread, i).as_bytes(), &[b'x'; 100]);
+                db.write_opt(batch, &WriteOptions { sync }).unwrap();
+            }
+        })
+    }).collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    let elapsed = start.elapsed();
+
+    drop(db);
+    fs::remove_dir_all(&path).unwrap();
+    (threads * writes) as f64 / elapsed.as_secs_f64()
+}
+
+fn main() {
+    let mut args = env::args().skip(1).map(|arg| arg.parse::<usize>().unwrap());
+    let threads = args.next().unwrap_or(8);
+    let writes = args.next().unwrap_or(20_000);
+
+    println!("{} threads, {} writes each", threads, writes);
+    for sync in [false, true] {
+        // synced writes are slow, fewer of them do.
+        let writes = if sync { writes / 20 } else { writes };
+        for pipelined_write in [false, true] {
+            let ops = run(pipelined_write, sync, threads, writes);
+            println!("sync: {:5}  pipelined: {:5}  {:>10.0} writes/s", sync, pipelined_write, ops);
+        }
+    }
+}
@@ -0,0 +1,499 @@
+use crate::comparator::{Comparator, OrderedKey};
+use crate::disk_service::{self, DiskService};
+use crate::error::{Error, Result};
+use crate::mem_table::{MemTable, MemTableEntry};
+use crate::merge_operator::MergeOperator;
+use crate::write_batch::BatchOp;
+use std::collections::BTreeMap;
+use std::fs;
+use std::iter;
+use std::mem;
+use std::ops::Bound::{self, Included};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub const DEFAULT_COLUMN_FAMILY: u32 = 0;
+pub const DEFAULT_COLUMN_FAMILY_NAME: &str = "default";
+const DEFAULT_WRITE_BUFFER_SIZE: usize = 128;
+
+/// Identifies a column family in `Db` calls and `WriteBatch` operations.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColumnFamilyHandle {
+    pub(crate) id: u32,
+    name: String,
+}
+
+impl ColumnFamilyHandle {
+    pub(crate) fn new(id: u32, name: &str) -> ColumnFamilyHandle {
+        ColumnFamilyHandle {
+            id,
+            name: name.to_owned(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+#[derive(Clone)]
+pub struct ColumnFamilyOptions {
+    /// Operator combining the operands written by `merge` into this column family.
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+    /// Size in bytes the mem_table may grow to before it gets flushed.
+    pub write_buffer_size: usize,
+}
+
+impl Default for ColumnFamilyOptions {
+    fn default() -> ColumnFamilyOptions {
+        ColumnFamilyOptions {
+            merge_operator: None,
+            write_buffer_size: DEFAULT_WRITE_BUFFER_SIZE,
+        }
+    }
+}
+
+/// A version of a key, as `Db::history` returns it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Version {
+    /// When it was written, in microseconds since the Unix epoch.
+    pub timestamp: u128,
+    /// The value, with merge operands applied. None for a delete.
+    pub value: Option<Vec<u8>>,
+    /// When a value written with a TTL expires.
+    pub expires_at: Option<u128>,
+}
+
+/// A keyspace of the db with its own mem_tables and disk files.
+/// All column families share the db's WAL.
+///
+/// Writes go to `mem_table`. Once full it becomes immutable and waits, with any
+/// older immutable ones, to be written to a disk file by a `FlushJob`.
+pub struct ColumnFamily {
+    pub(crate) handle: ColumnFamilyHandle,
+    pub(crate) options: ColumnFamilyOptions,
+    pub(crate) mem_table: MemTable,
+    // oldest first.
+    pub(crate) immutable_mem_tables: Vec<Arc<MemTable>>,
+    pub(crate) disk_service: DiskService,
+    dir: PathBuf,
+}
+
+impl ColumnFamily {
+    /// Open the column family stored in `dir`, creating the directory if needed.
+    /// Its keys are ordered by the comparator of `mem_table`.
+    pub fn open(handle: ColumnFamilyHandle, dir: &Path, mem_table: MemTable) -> Result<ColumnFamily> {
+        let comparator = mem_table.comparator().clone();
+        let disk_service = if dir.exists() {
+            DiskService::recover(dir, comparator)?
+        } else {
+            DiskService::new(dir, comparator)?
+        };
+        Ok(ColumnFamily::with_disk_service(handle, dir, mem_table, disk_service))
+    }
+
+    /// Open the column family stored in `dir`, which has to exist, without changing anything.
+    pub fn open_existing(handle: ColumnFamilyHandle, dir: &Path, mem_table: MemTable) -> Result<ColumnFamily> {
+        let disk_service = DiskService::open(dir, mem_table.comparator().clone())?;
+        Ok(ColumnFamily::with_disk_service(handle, dir, mem_table, disk_service))
+    }
+
+    fn with_disk_service(handle: ColumnFamilyHandle, dir: &Path, mem_table: MemTable,
+                         disk_service: DiskService) -> ColumnFamily {
+        ColumnFamily {
+            handle,
+            options: ColumnFamilyOptions::default(),
+            mem_table,
+            immutable_mem_tables: vec![],
+            disk_service,
+            dir: dir.to_owned(),
+        }
+    }
+
+    /// The disk files of the column family as they are in its directory now, which
+    /// differ from `disk_service` if someone else wrote to it.
+    pub fn open_disk_service(&self) -> Result<DiskService> {
+        DiskService::open(&self.dir, self.comparator().clone())
+    }
+
+    pub fn comparator(&self) -> &Arc<dyn Comparator> {
+        self.mem_table.comparator()
+    }
+
+    pub fn apply(&mut self, op: &BatchOp, timestamp: u128) {
+        self.mem_table.apply(op, timestamp);
+    }
+
+    pub fn get(&self, key: &[u8], now: u128) -> Result<Option<Vec<u8>>> {
+        let (entries, on_disk) = self.mem_entries(key);
+        let below = if on_disk {
+            self.disk_service.get(key)?.and_then(|entry| entry.live_value(now).cloned())
+        } else {
+            None
+        };
+        self.resolve_entries(&entries, below, now)
+    }
+
+    /// `get` for every key of `keys`, in the same order. The keys are looked up
+    /// sorted, so each disk file is read at most once.
+    pub fn multi_get(&self, keys: &[&[u8]], now: u128) -> Result<Vec<Option<Vec<u8>>>> {
+        let comparator = self.comparator();
+        let mut sorted = keys.to_vec();
+        sorted.sort_by(|a, b| comparator.compare(a, b));
+        sorted.dedup_by(|a, b| comparator.compare(a, b).is_eq());
+
+        let mut mem_entries = vec![];
+        let mut disk_keys = vec![];
+        for key in sorted.iter() {
+            let (entries, on_disk) = self.mem_entries(key);
+            if on_disk {
+                disk_keys.push(*key);
+            }
+            mem_entries.push((entries, on_disk));
+        }
+
+        let mut disk_entries = self.disk_service.multi_get(&disk_keys)?.into_iter();
+        let mut values = vec![];
+        for (entries, on_disk) in mem_entries {
+            let below = if on_disk {
+                disk_entries.next().unwrap().and_then(|entry| entry.live_value(now).cloned())
+            } else {
+                None
+            };
+            values.push(self.resolve_entries(&entries, below, now)?);
+        }
+        Ok(keys.iter()
+            .map(|key| values[sorted.binary_search_by(|probe| comparator.compare(probe, key)).unwrap()].clone())
+            .collect())
+    }
+
+    pub fn range(&self, min_key: &[u8], max_key: &[u8], now: u128) -> Result<Vec<Vec<u8>>> {
+        Ok(self.scan(Included(min_key), Included(max_key), now)?
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect())
+    }
+
+    /// Live key-value pairs within the bounds, in key order.
+    pub fn scan(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>, now: u128) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let comparator = self.comparator();
+        let ordered = |key: &[u8]| OrderedKey::new(key, comparator);
+        let mut values = BTreeMap::new();
+
+        let disk_entries = self.disk_service.scan(lower, upper)?;
+        for entry in disk_entries {
+            values.insert(ordered(&entry.key), entry.live_value(now).cloned());
+        }
+        // each mem_table, oldest first, goes on top of everything older than it.
+        let mem_tables = self.immutable_mem_tables.iter().map(AsRef::as_ref).chain(iter::once(&self.mem_table));
+        for mem_table in mem_tables {
+            for tombstone in mem_table.range_tombstones() {
+                if comparator.compare(&tombstone.start, &tombstone.end).is_lt() {
+                    for (_, value) in values.range_mut(ordered(&tombstone.start)..ordered(&tombstone.end)) {
+                        *value = None;
+                    }
+                }
+            }
+            for entry in mem_table.scan(lower, upper) {
+                let key = ordered(&entry.key);
+                let older = || Ok(values.get(&key).cloned().flatten());
+                let value = resolve_entry(self.options.merge_operator.as_ref(), &entry, now, older)?;
+                values.insert(key, value);
+            }
+        }
+
+        Ok(values.into_iter()
+            .filter_map(|(key, value)| value.map(|value| (key.bytes, value)))
+            .collect())
+    }
+
+    /// The value `key` had at `timestamp`, as far as the versions kept go back.
+    pub fn get_at(&self, key: &[u8], timestamp: u128) -> Result<Option<Vec<u8>>> {
+        Ok(self.history(key)?.into_iter()
+            .find(|version| version.timestamp <= timestamp)
+            .filter(|version| version.expires_at.is_none_or(|expires_at| expires_at > timestamp))
+            .and_then(|version| version.value))
+    }
+
+    /// The versions of `key` kept in the mem_tables and disk files, newest first.
+    /// Deletes right after another delete are left out, as they change nothing.
+    pub fn history(&self, key: &[u8]) -> Result<Vec<Version>> {
+        let comparator = self.comparator();
+        let mut versions = vec![];
+        for entry in self.disk_service.history(key)? {
+            push_version(&mut versions, Version { timestamp: entry.timestamp, value: entry.value, expires_at: entry.expires_at });
+        }
+        let mem_tables = self.immutable_mem_tables.iter().map(AsRef::as_ref).chain(iter::once(&self.mem_table));
+        for mem_table in mem_tables {
+            let mut entries: Vec<MemTableEntry> = mem_table.range_tombstones().iter()
+                .filter(|tombstone| {
+                    comparator.compare(&tombstone.start, key).is_le() && comparator.compare(key, &tombstone.end).is_lt()
+                })
+                .map(|tombstone| disk_service::deleted_at(key, tombstone.timestamp))
+                .collect();
+            entries.extend(mem_table.older_versions(key).iter().cloned());
+            entries.extend(mem_table.get(key).cloned());
+            entries.sort_by_key(|entry| entry.timestamp);
+
+            // merge operands that need an older value apply to what lies underneath
+            // their mem_table, or to nothing past a range tombstone.
+            let mut below = match (versions.last(), entries.first()) {
+                (Some(Version { value, expires_at, .. }), Some(first)) if expires_at.is_none_or(|at| at > first.timestamp) => {
+                    value.clone()
+                }
+                _ => None,
+            };
+            for entry in entries {
+                let value = resolve_entry(self.options.merge_operator.as_ref(), &entry, entry.timestamp,
+                                          || Ok(below.clone()))?;
+                if entry.deleted {
+                    below = None;
+                }
+                push_version(&mut versions, Version { timestamp: entry.timestamp, value, expires_at: entry.expires_at });
+            }
+        }
+        versions.reverse();
+        Ok(versions)
+    }
+
+    /// Timestamp of the newest write to `key`, deletes included.
+    pub fn latest_timestamp(&self, key: &[u8]) -> Result<Option<u128>> {
+        for mem_table in self.mem_tables() {
+            if let Some(entry) = mem_table.get(key) {
+                return Ok(Some(entry.timestamp));
+            }
+            if let Some(timestamp) = mem_table.covering_tombstone(key) {
+                return Ok(Some(timestamp));
+            }
+        }
+        Ok(self.disk_service.get(key)?.map(|entry| entry.timestamp))
+    }
+
+    pub fn is_over_weight(&self) -> bool {
+        self.mem_table.is_over_weight(self.options.write_buffer_size)
+    }
+
+    /// Make the mem_table immutable and start a new one.
+    pub fn seal_mem_table(&mut self) {
+        let empty = MemTable::new(self.comparator().clone()).keep_history(self.mem_table.keeps_history());
+        let mem_table = mem::replace(&mut self.mem_table, empty);
+        self.immutable_mem_tables.push(Arc::new(mem_table));
+    }
+
+    /// Job writing the oldest immutable mem_table to disk, if there's one.
+    pub fn flush_job(&self) -> Option<FlushJob> {
+        let mem_table = self.immutable_mem_tables.first()?;
+        Some(FlushJob {
+            id: self.handle.id,
+            mem_table: mem_table.clone(),
+            disk_service: self.disk_service.clone(),
+            merge_operator: self.options.merge_operator.clone(),
+        })
+    }
+
+    /// Swap in the disk files written by the flush job of the oldest immutable mem_table.
+    pub fn finish_flush(&mut self, disk_service: DiskService) {
+        self.disk_service = disk_service;
+        self.immutable_mem_tables.remove(0);
+    }
+
+    /// Delete the column family's disk files.
+    pub fn destroy(self) -> Result<()> {
+        fs::remove_dir_all(&self.dir)?;
+        Ok(())
+    }
+
+    pub fn check_merge_operator(&self) -> Result<()> {
+        if self.options.merge_operator.is_none() {
+            return Err(Error::InvalidArgument(
+                format!("no merge operator is set for column family {}", self.handle.name()),
+            ));
+        }
+        Ok(())
+    }
+
+    // the mutable mem_table, then the immutable ones, newest first.
+    fn mem_tables(&self) -> impl Iterator<Item = &MemTable> {
+        iter::once(&self.mem_table).chain(self.immutable_mem_tables.iter().rev().map(AsRef::as_ref))
+    }
+
+    // entries of `key` in the mem_tables, newest first, down to the first one that
+    // doesn't build on what lies underneath it. The flag tells whether the disk
+    // files lie underneath and have to be read.
+    fn mem_entries(&self, key: &[u8]) -> (Vec<&MemTableEntry>, bool) {
+        let mut entries = vec![];
+        for mem_table in self.mem_tables() {
+            if let Some(entry) = mem_table.get(key) {
+                entries.push(entry);
+                if !needs_older(entry) {
+                    return (entries, false);
+                }
+            }
+            // a range tombstone hides everything older than its mem_table.
+            if mem_table.covering_tombstone(key).is_some() {
+                return (entries, false);
+            }
+        }
+        (entries, true)
+    }
+
+    // value of a key from its mem_entries and the value underneath them.
+    fn resolve_entries(&self, entries: &[&MemTableEntry], below: Option<Vec<u8>>, now: u128) -> Result<Option<Vec<u8>>> {
+        let mut value = below;
+        for entry in entries.iter().rev() {
+            value = resolve_entry(self.options.merge_operator.as_ref(), entry, now, || Ok(value.take()))?;
+        }
+        Ok(value)
+    }
+}
+
+/// Writes an immutable mem_table to a new disk file. It works on its own copy of
+/// the column family's disk files, so reads and writes can go on in the meantime.
+pub struct FlushJob {
+    pub(crate) id: u32,
+    mem_table: Arc<MemTable>,
+    disk_service: DiskService,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+}
+
+impl FlushJob {
+    /// Write the file and return the disk files with it, for `ColumnFamily::finish_flush`.
+    pub fn run(mut self) -> Result<DiskService> {
+        if self.mem_table.is_empty() {
+            return Ok(self.disk_service);
+        }
+        let collapsed = self.collapse_merge_operands()?;
+        self.disk_service.write_mem_table_to_disk(collapsed.as_ref().unwrap_or(&self.mem_table))?;
+        Ok(self.disk_service)
+    }
+
+    // disk files only hold full values, so pending merge operands are
+    // folded into one before the mem_table is written out, in every version
+    // kept. The mem_table is the oldest one, so the disk files hold what the
+    // operands apply to.
+    fn collapse_merge_operands(&self) -> Result<Option<MemTable>> {
+        let mut merged = vec![];
+        for (key, entry) in self.mem_table.iter() {
+            let versions = self.mem_table.older_versions(key).iter().chain(iter::once(entry));
+            if versions.clone().all(|version| version.merge_operands.is_empty()) {
+                continue;
+            }
+            let mut collapsed = vec![];
+            for version in versions {
+                if version.merge_operands.is_empty() {
+                    collapsed.push(version.clone());
+                    continue;
+                }
+                // the operands apply to the value as it was when they were written,
+                // and the merged value expires when that value does.
+                let base = if needs_older(version) {
+                    self.disk_service.get(key)?.filter(|entry| !entry.is_expired(version.timestamp))
+                } else {
+                    None
+                };
+                let expires_at = base.as_ref().map_or(version.expires_at, |base| base.expires_at);
+                let older = || Ok(base.and_then(|base| base.value));
+                if let Some(value) = resolve_entry(self.merge_operator.as_ref(), version, version.timestamp, older)? {
+                    collapsed.push(MemTableEntry {
+                        value: Some(value),
+                        deleted: false,
+                        merge_operands: vec![],
+                        expires_at,
+                        ..version.clone()
+                    });
+                }
+            }
+            merged.push((key.to_owned(), collapsed));
+        }
+        if merged.is_empty() {
+            return Ok(None);
+        }
+        let mut mem_table = (*self.mem_table).clone();
+        for (key, versions) in merged {
+            mem_table.set_versions(&key, versions);
+        }
+        Ok(Some(mem_table))
+    }
+}
+
+// add `version` to versions, oldest first, unless it deletes what's deleted already.
+fn push_version(versions: &mut Vec<Version>, version: Version) {
+    if version.value.is_some() || versions.last().is_none_or(|older| older.value.is_some()) {
+        versions.push(version);
+    }
+}
+
+// whether the entry is made of merge operands applying to an older value.
+fn needs_older(entry: &MemTableEntry) -> bool {
+    !entry.merge_operands.is_empty() && entry.value.is_none() && !entry.deleted
+}
+
+// live value of a mem_table entry with its merge operands applied. `older` gives
+// the value underneath the entry and is only called when the operands need it.
+fn resolve_entry(merge_operator: Option<&Arc<dyn MergeOperator>>, entry: &MemTableEntry, now: u128,
+                 older: impl FnOnce() -> Result<Option<Vec<u8>>>) -> Result<Option<Vec<u8>>> {
+    if entry.merge_operands.is_empty() {
+        return Ok(entry.live_value(now).cloned());
+    }
+    let merge_operator = merge_operator.ok_or_else(|| {
+        Error::InvalidArgument("merge operands found but no merge operator is set".to_owned())
+    })?;
+    // operands merged onto a value with a ttl expire along with it.
+    if entry.is_expired(now) {
+        return Ok(None);
+    }
+    let existing = if needs_older(entry) {
+        older()?
+    } else {
+        entry.live_value(now).cloned()
+    };
+    Ok(Some(merge_operator.full_merge(&entry.key, existing.as_deref(), &entry.merge_operands)))
+}
+
+/// The column families other than the default one, as listed in the manifest.
+///
+/// next_id
+/// id--name
+/// ...
+pub struct ColumnFamilyManifest {
+    pub next_id: u32,
+    pub families: Vec<(u32, String)>,
+}
+
+impl ColumnFamilyManifest {
+    pub fn load(path: &Path) -> Result<ColumnFamilyManifest> {
+        if !path.exists() {
+            return Ok(ColumnFamilyManifest {
+                next_id: DEFAULT_COLUMN_FAMILY + 1,
+                families: vec![],
+            });
+        }
+        let content = fs::read_to_string(path)?;
+        let corrupted = |line: &str| Error::Corruption {
+            file: path.to_owned(),
+            offset: (line.as_ptr() as usize - content.as_ptr() as usize) as u64,
+        };
+        let mut lines = content.lines();
+        let first = lines.next().unwrap_or(&content);
+        let next_id = first.parse().map_err(|_| corrupted(first))?;
+        let mut families = vec![];
+        for line in lines {
+            let (id, name) = line.split_once(' ').ok_or_else(|| corrupted(line))?;
+            families.push((id.parse().map_err(|_| corrupted(line))?, name.to_owned()));
+        }
+        Ok(ColumnFamilyManifest { next_id, families })
+    }
+
+    /// Replace the manifest at `path` in one rename, so it is never seen half written.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut content = format!("{}\n", self.next_id);
+        for (id, name) in self.families.iter() {
+            content.push_str(&format!("{} {}\n", id, name));
+        }
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, content)?;
+        fs::File::open(&tmp_path)?.sync_all()?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+}
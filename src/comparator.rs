@@ -0,0 +1,165 @@
+use std::cmp::Ordering;
+use std::ops::Bound::{self, Excluded, Included, Unbounded};
+use std::sync::Arc;
+
+/// Orders the keys of a db, in the mem_tables, disk files, iterators and compactions alike.
+///
+/// It's set with `Options::comparator`. Its name is stored with the db when it's created,
+/// and opening the db with a comparator of another name fails.
+pub trait Comparator: Send + Sync {
+    /// Two comparators of the same name have to order keys the same.
+    fn name(&self) -> &str;
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+
+    /// A key `k`, as short as possible, with `start <= k < limit`. `start` itself will do.
+    fn find_shortest_separator(&self, start: &[u8], _limit: &[u8]) -> Vec<u8> {
+        start.to_vec()
+    }
+
+    /// A key `k`, as short as possible, with `key <= k`. `key` itself will do.
+    fn find_short_successor(&self, key: &[u8]) -> Vec<u8> {
+        key.to_vec()
+    }
+}
+
+/// Orders keys by their bytes, shorter keys first when one is a prefix of the other.
+/// It's the default.
+pub struct BytewiseComparator;
+
+impl Comparator for BytewiseComparator {
+    fn name(&self) -> &str {
+        "pipengine.BytewiseComparator"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+
+    fn find_shortest_separator(&self, start: &[u8], limit: &[u8]) -> Vec<u8> {
+        let common = start.iter().zip(limit).take_while(|(a, b)| a == b).count();
+        // one is a prefix of the other: no shorter key lies in between.
+        if common < start.len() && common < limit.len() {
+            let byte = start[common];
+            if byte < u8::MAX && byte + 1 < limit[common] {
+                let mut separator = start[..=common].to_vec();
+                separator[common] += 1;
+                return separator;
+            }
+        }
+        start.to_vec()
+    }
+
+    fn find_short_successor(&self, key: &[u8]) -> Vec<u8> {
+        match key.iter().position(|byte| *byte != u8::MAX) {
+            Some(i) => {
+                let mut successor = key[..=i].to_vec();
+                successor[i] += 1;
+                successor
+            }
+            None => key.to_vec(),
+        }
+    }
+}
+
+/// Orders keys like `BytewiseComparator`, backwards.
+pub struct ReverseBytewiseComparator;
+
+impl Comparator for ReverseBytewiseComparator {
+    fn name(&self) -> &str {
+        "pipengine.ReverseBytewiseComparator"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        b.cmp(a)
+    }
+}
+
+// a key that sorts by a comparator, to keep keys in sorted collections.
+#[derive(Clone)]
+pub(crate) struct OrderedKey {
+    pub(crate) bytes: Vec<u8>,
+    comparator: Arc<dyn Comparator>,
+}
+
+impl OrderedKey {
+    pub(crate) fn new(bytes: &[u8], comparator: &Arc<dyn Comparator>) -> OrderedKey {
+        OrderedKey {
+            bytes: bytes.to_owned(),
+            comparator: comparator.clone(),
+        }
+    }
+}
+
+impl PartialEq for OrderedKey {
+    fn eq(&self, other: &OrderedKey) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for OrderedKey {}
+
+impl PartialOrd for OrderedKey {
+    fn partial_cmp(&self, other: &OrderedKey) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OrderedKey {
+    fn cmp(&self, other: &OrderedKey) -> Ordering {
+        self.comparator.compare(&self.bytes, &other.bytes)
+    }
+}
+
+// `bound` over ordered keys, for ranges of sorted collections.
+pub(crate) fn ordered_bound(bound: Bound<&[u8]>, comparator: &Arc<dyn Comparator>) -> Bound<OrderedKey> {
+    match bound {
+        Included(key) => Included(OrderedKey::new(key, comparator)),
+        Excluded(key) => Excluded(OrderedKey::new(key, comparator)),
+        Unbounded => Unbounded,
+    }
+}
+
+// whether `key` lies below the lower bound `lower`.
+pub(crate) fn below_lower(comparator: &dyn Comparator, lower: Bound<&[u8]>, key: &[u8]) -> bool {
+    match lower {
+        Included(lower) => comparator.compare(key, lower).is_lt(),
+        Excluded(lower) => comparator.compare(key, lower).is_le(),
+        Unbounded => false,
+    }
+}
+
+// whether `key` lies above the upper bound `upper`.
+pub(crate) fn above_upper(comparator: &dyn Comparator, upper: Bound<&[u8]>, key: &[u8]) -> bool {
+    match upper {
+        Included(upper) => comparator.compare(key, upper).is_gt(),
+        Excluded(upper) => comparator.compare(key, upper).is_ge(),
+        Unbounded => false,
+    }
+}
+
+// whether the bounds leave no key in between, which sorted collections panic on.
+pub(crate) fn is_empty_range(comparator: &dyn Comparator, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> bool {
+    match (lower, upper) {
+        (Included(lower), Included(upper)) => comparator.compare(lower, upper).is_gt(),
+        (Included(lower) | Excluded(lower), Included(upper) | Excluded(upper)) => comparator.compare(lower, upper).is_ge(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::comparator::{BytewiseComparator, Comparator};
+
+    #[test]
+    fn test_bytewise_shortening() {
+        let comparator = BytewiseComparator;
+        assert_eq!(comparator.find_shortest_separator(b"abcd", b"abzz"), b"abd");
+        // no byte to bump, or start is a prefix of limit.
+        assert_eq!(comparator.find_shortest_separator(b"abcd", b"abde"), b"abcd");
+        assert_eq!(comparator.find_shortest_separator(b"ab", b"abcd"), b"ab");
+        assert_eq!(comparator.find_short_successor(b"abcd"), b"b");
+        assert_eq!(comparator.find_short_successor(b"\xff\xffa"), b"\xff\xffb");
+        assert_eq!(comparator.find_short_successor(b"\xff\xff"), b"\xff\xff");
+    }
+}
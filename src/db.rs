@@ -1,153 +1,1307 @@
-use crate::disk_service::DiskService;
-use crate::mem_table::{MemTable, MemTableEntry};
-use std::io::{Error, ErrorKind};
-use std::path::PathBuf;
-use std::fs;
+use crate::column_family::{
+    ColumnFamily, ColumnFamilyHandle, ColumnFamilyManifest, ColumnFamilyOptions, Version,
+    DEFAULT_COLUMN_FAMILY, DEFAULT_COLUMN_FAMILY_NAME,
+};
+use crate::comparator::{BytewiseComparator, Comparator};
+use crate::error::{Error, Result};
+use crate::iterator::DbIterator;
+use crate::mem_table::MemTable;
+use crate::lock_manager::LockManager;
+use crate::merge_operator::MergeOperator;
+use crate::options::{Options, ReadOptions, WriteOptions};
+use crate::stats::{Stats, WriteStallCause, WriteStallCondition, WriteStallLimits};
+use crate::transaction::{PessimisticTransaction, Transaction, TransactionOptions};
+use crate::write_batch::{BatchOp, WriteBatch};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::io;
-use std::fs::OpenOptions;
-use std::time::{SystemTime, UNIX_EPOCH};
-use crate::wal::WAL;
+use std::mem;
+use std::ops::Bound::{Excluded, Included, Unbounded};
+use std::path::{Path, PathBuf};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use crate::wal::{WALTail, WAL};
 use crate::util;
 
-struct db {
+// a write group stops growing once its batches hold this many bytes of keys and values.
+const MAX_WRITE_GROUP_SIZE: usize = 1 << 20;
+// how long a write group is held back past a slowdown limit.
+const WRITE_SLOWDOWN_DELAY: Duration = Duration::from_millis(1);
+// how often stopped writes look again, in case they missed the background thread's notification.
+const WRITE_STOP_RECHECK: Duration = Duration::from_millis(10);
+
+/// An open db. It is `Send + Sync`, so threads can share it behind an `Arc`.
+///
+/// Reads run concurrently against the mem_tables and disk files. Writes are
+/// committed in groups, see `write_opt`. A background thread writes full mem_tables
+/// to disk and compacts disk files, without blocking reads or writes. When it falls
+/// behind, writes are slowed down and then stopped, see the write stall limits of `Options`.
+pub struct Db {
+    core: Arc<DbCore>,
+    background_thread: Option<JoinHandle<()>>,
+    flush_on_close: bool,
+    pipelined_write: bool,
+    read_only: bool,
+    closed: bool,
+    // the LOCK file, holding the exclusive lock on the db directory until dropped.
+    // None if the db is open read-only, which doesn't lock it.
+    _lock_file: Option<File>,
+    // the primary's wal as read so far, if the db is open as a secondary.
+    secondary: Option<Mutex<WALTail>>,
+}
+
+// the part of the db shared with the background thread.
+struct DbCore {
     dir_db: PathBuf,
-    disk_service: DiskService,
-    mem_table: MemTable,
-    wal: WAL,
+    // column families by id, the default one included.
+    column_families: RwLock<BTreeMap<u32, ColumnFamily>>,
+    writer: Mutex<Writer>,
+    write_queue: Mutex<WriteQueue>,
+    write_queue_changed: Condvar,
+    pipeline: Mutex<Pipeline>,
+    pipeline_changed: Condvar,
+    // held while changing the disk files of a column family, which flushes,
+    // compactions and dropping a column family do.
+    disk_work: Mutex<()>,
+    background: Mutex<Background>,
+    background_changed: Condvar,
+    lock_manager: Arc<LockManager>,
+    comparator: Arc<dyn Comparator>,
+    // see `Options::history_retention`, in microseconds.
+    history_retention: Option<u128>,
+    limits: WriteStallLimits,
+    // see `Options::disk_file_compaction_trigger`.
+    compaction_trigger: usize,
+}
+
+// state of the write path, owned by whoever holds the writer lock.
+struct Writer {
+    // None if the db is open read-only.
+    wal: Option<WAL>,
+    // timestamp of the latest write, every new write gets a strictly greater one.
+    last_timestamp: u128,
+    next_column_family_id: u32,
+    // number of write groups logged so far.
+    groups: u64,
+    // prepared transactions by name, kept around to hold their locks until decided.
+    prepared_transactions: HashMap<String, PessimisticTransaction>,
+}
+
+// writers waiting for a leader to commit their batches.
+struct WriteQueue {
+    waiting: VecDeque<QueuedWrite>,
+    next_id: u64,
+    // whether some writer is committing a group right now.
+    leading: bool,
+    // results of committed writes, until their writers pick them up.
+    finished: HashMap<u64, Result<()>>,
 }
 
-impl db {
-    pub fn new() -> io::Result<db> {
-        let dir_db = PathBuf::from(format!("./{}", "DB"));
+// how far the logged write groups got into the mem_tables.
+struct Pipeline {
+    // groups are applied in the order they were logged, this many so far.
+    applied: u64,
+    // timestamp of the latest applied write. Everything up to it is readable.
+    visible_timestamp: u128,
+}
+
+struct QueuedWrite {
+    id: u64,
+    batch: WriteBatch,
+    sync: bool,
+}
+
+struct Background {
+    // wal files of the sealed mem_tables, oldest first. Every seal gives each column
+    // family an immutable mem_table, logged in the wal file it pushes here.
+    sealed_wals: VecDeque<PathBuf>,
+    // set by writers stopped on disk files, as only a compaction helps them.
+    compaction_requested: bool,
+    shutting_down: bool,
+    delayed_writes: u64,
+    stopped_writes: u64,
+    stall_micros: u64,
+    // a failed flush or compaction stops the background thread and fails every later write.
+    error: Option<String>,
+}
+
+impl Db {
+    /// Open the db in `./DB`, creating it if needed.
+    pub fn new() -> Result<Db> {
+        Db::open("./DB", Options {
+            create_if_missing: true,
+            ..Options::default()
+        })
+    }
+
+    /// Create a new, empty db in the directory `path`, failing if there's one already.
+    pub fn create(path: impl AsRef<Path>, options: Options) -> Result<Db> {
+        Db::open(path, Options {
+            create_if_missing: true,
+            error_if_exists: true,
+            ..options
+        })
+    }
+
+    pub fn put(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put(key.as_ref(), value.as_ref());
+        self.write(batch)
+    }
+
+    /// Set the operator combining the operands written by `merge`. It has to be
+    /// set again, to the same operator, every time the db is opened.
+    pub fn set_merge_operator(&self, merge_operator: Arc<dyn MergeOperator>) {
+        self.update_default_cf(|cf| cf.options.merge_operator = Some(merge_operator));
+    }
+
+    /// Record `operand` for `key` without reading it. The operands are combined
+    /// with the current value by the merge operator when the key is read.
+    pub fn merge(&self, key: impl AsRef<[u8]>, operand: impl AsRef<[u8]>) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.merge(key.as_ref(), operand.as_ref());
+        self.write(batch)
+    }
+
+    /// Apply every operation of `batch` atomically: one WAL record, one timestamp.
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        self.write_opt(batch, &WriteOptions::default())
+    }
+
+    /// Concurrent calls are committed in groups: the first waiting writer becomes the
+    /// leader, logs the batches of everyone waiting with one WAL append and at most
+    /// one sync, applies them, and hands every follower its result.
+    ///
+    /// With `Options::pipelined_write`, a leader steps down once its group is in the
+    /// WAL, so the next group is logged while the previous one goes into the
+    /// mem_tables. Groups are still applied in WAL order, so readers never see a
+    /// write without every write logged before it.
+    pub fn write_opt(&self, batch: WriteBatch, options: &WriteOptions) -> Result<()> {
+        self.check_writable()?;
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let mut queue = self.core.write_queue.lock().unwrap();
+        let id = queue.next_id;
+        queue.next_id += 1;
+        queue.waiting.push_back(QueuedWrite { id, batch, sync: options.sync });
+        loop {
+            if let Some(result) = queue.finished.remove(&id) {
+                return result;
+            }
+            // writes are drained from the front, so once the front is past `id`,
+            // it's in some leader's group already.
+            let queued = queue.waiting.front().is_some_and(|write| write.id <= id);
+            if queue.leading || !queued {
+                queue = self.core.write_queue_changed.wait(queue).unwrap();
+                continue;
+            }
+            queue.leading = true;
+            drop(queue);
+            let results = self.commit_group();
+            queue = self.core.write_queue.lock().unwrap();
+            queue.finished.extend(results);
+            self.core.write_queue_changed.notify_all();
+        }
+    }
+
+    /// Write `batch` if `check` returns true, with no other write in between.
+    /// Returns whether it was written.
+    pub(crate) fn write_if(&self, batch: WriteBatch, check: impl FnOnce(&Db) -> Result<bool>) -> Result<bool> {
+        self.throttle()?;
+        let mut writer = self.lock_writer()?;
+        // the check has to see every logged write.
+        drop(self.core.wait_applied(writer.groups));
+        if !check(self)? {
+            return Ok(false);
+        }
+        self.write_locked(&mut writer, &batch, false)?;
+        Ok(true)
+    }
+
+    /// Write a value that reads as absent once `ttl` has passed,
+    /// and is dropped from disk by the next `compact` after that.
+    pub fn put_with_ttl(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>, ttl: Duration) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put_with_ttl_in(DEFAULT_COLUMN_FAMILY, key.as_ref(), value.as_ref(), ttl.as_micros());
+        self.write(batch)
+    }
+
+    /// Write `value` only if `key` has no value yet. Returns whether it was written.
+    pub fn put_if_absent(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<bool> {
+        self.compare_and_swap(key, None, value)
+    }
+
+    /// Replace the value of `key` with `new` only if it currently is `expected`,
+    /// `None` meaning absent. Returns whether the swap happened.
+    pub fn compare_and_swap(&self, key: impl AsRef<[u8]>, expected: Option<&[u8]>,
+                            new: impl AsRef<[u8]>) -> Result<bool> {
+        let key = key.as_ref();
+        let mut batch = WriteBatch::new();
+        batch.put(key, new.as_ref());
+        self.write_if(batch, |db| Ok(db.get(key)?.as_deref() == expected))
+    }
+
+    /// Delete `key` only if its current value is `expected`. Returns whether it was deleted.
+    pub fn delete_if_equals(&self, key: impl AsRef<[u8]>, expected: impl AsRef<[u8]>) -> Result<bool> {
+        let key = key.as_ref();
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        self.write_if(batch, |db| Ok(db.get(key)?.as_deref() == Some(expected.as_ref())))
+    }
+
+    /// Start an optimistic transaction. Its writes are buffered until `commit`,
+    /// which fails if a key it read was written after this call.
+    pub fn begin_transaction(&self) -> Transaction {
+        Transaction::new(self.core.pipeline.lock().unwrap().visible_timestamp)
+    }
+
+    /// Start a pessimistic transaction that locks the keys it writes.
+    pub fn begin_pessimistic_transaction(&self, options: TransactionOptions) -> PessimisticTransaction {
+        PessimisticTransaction::new(self.core.lock_manager.clone(), options)
+    }
+
+    pub(crate) fn prepare_transaction(&self, name: &str, txn: PessimisticTransaction) -> Result<()> {
+        self.throttle()?;
+        let mut writer = self.lock_writer()?;
+        writer.wal().prepare(name, &txn.write_batch())?;
+        writer.prepared_transactions.insert(name.to_owned(), txn);
+        Ok(())
+    }
+
+    /// Apply the prepared transaction `name`, which may have been prepared before a restart.
+    pub fn commit_prepared(&self, name: &str) -> Result<()> {
+        self.throttle()?;
+        let mut writer = self.lock_writer()?;
+        let timestamp = writer.next_timestamp();
+        let batch = writer.wal().commit_prepared(name, timestamp)?;
+        writer.groups += 1;
+        self.core.apply_in_order(writer.groups, &[(&batch, timestamp)]);
+        writer.prepared_transactions.remove(name);
+        if let Err(err) = self.seal_if_over_weight(&mut writer) {
+            self.core.fail_background(&err);
+        }
+        Ok(())
+    }
+
+    /// Drop the prepared transaction `name` without applying its writes, releasing its locks.
+    pub fn rollback_prepared(&self, name: &str) -> Result<()> {
+        let mut writer = self.lock_writer()?;
+        writer.wal().rollback_prepared(name)?;
+        writer.prepared_transactions.remove(name);
+        Ok(())
+    }
+
+    /// Names of the prepared transactions still waiting for a decision.
+    pub fn prepared_transactions(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.core.writer.lock().unwrap().prepared_transactions.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        self.read_default_cf(|cf| cf.get(key.as_ref(), util::get_timestamp()))
+    }
+
+    /// `get` for values stored as UTF-8 strings. Fails with `InvalidArgument` on any other value.
+    pub fn get_string(&self, key: impl AsRef<[u8]>) -> Result<Option<String>> {
+        self.get(key)?.map(into_string).transpose()
+    }
+
+    pub fn delete(&self, key: impl AsRef<[u8]>) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete(key.as_ref());
+        self.write(batch)
+    }
+
+    /// Look up many keys at once, returning their values in the order of `keys`.
+    /// Cheaper than calling `get` in a loop, as every disk file is read at most once.
+    pub fn multi_get<K: AsRef<[u8]>>(&self, keys: &[K]) -> Result<Vec<Option<Vec<u8>>>> {
+        let keys: Vec<&[u8]> = keys.iter().map(|key| key.as_ref()).collect();
+        self.read_default_cf(|cf| cf.multi_get(&keys, util::get_timestamp()))
+    }
+
+    pub fn multi_get_strings<K: AsRef<[u8]>>(&self, keys: &[K]) -> Result<Vec<Option<String>>> {
+        self.multi_get(keys)?.into_iter()
+            .map(|value| value.map(into_string).transpose())
+            .collect()
+    }
+
+    /// Delete every key in [start, end) with a single range tombstone,
+    /// however many keys the range holds.
+    pub fn delete_range(&self, start: impl AsRef<[u8]>, end: impl AsRef<[u8]>) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete_range(start.as_ref(), end.as_ref());
+        self.write(batch)
+    }
+
+    pub fn range(&self, min_key: impl AsRef<[u8]>, max_key: impl AsRef<[u8]>) -> Result<Vec<Vec<u8>>> {
+        self.read_default_cf(|cf| cf.range(min_key.as_ref(), max_key.as_ref(), util::get_timestamp()))
+    }
+
+    pub fn range_strings(&self, min_key: impl AsRef<[u8]>, max_key: impl AsRef<[u8]>) -> Result<Vec<String>> {
+        self.range(min_key, max_key)?.into_iter().map(into_string).collect()
+    }
+
+    /// Iterate over the default column family within the bounds of `options`.
+    pub fn iter(&self, options: &ReadOptions) -> Result<DbIterator> {
+        Ok(DbIterator::new(self.read_default_cf(|cf| scan(cf, options))?))
+    }
+
+    pub fn iter_cf(&self, cf: &ColumnFamilyHandle, options: &ReadOptions) -> Result<DbIterator> {
+        Ok(DbIterator::new(self.read_cf(cf, |cf| scan(cf, options))??))
+    }
+
+    /// Create a new, empty column family. Its writes share the db's WAL,
+    /// so one `WriteBatch` can atomically update several column families.
+    pub fn create_column_family(&self, name: &str, options: ColumnFamilyOptions) -> Result<ColumnFamilyHandle> {
+        if name.is_empty() || name.contains('\n') {
+            return Err(Error::InvalidArgument(format!("invalid column family name {:?}", name)));
+        }
+        let mut writer = self.lock_writer()?;
+        if self.column_family(name).is_some() {
+            return Err(Error::AlreadyExists(format!("column family {}", name)));
+        }
+        let handle = ColumnFamilyHandle::new(writer.next_column_family_id, name);
+        writer.next_column_family_id += 1;
+        let mut cf = ColumnFamily::open(handle.clone(), &self.column_family_dir(handle.id),
+                                        self.core.new_mem_table())?;
+        cf.options = options;
+        self.core.column_families.write().unwrap().insert(handle.id, cf);
+        self.save_column_families(&writer)?;
+        Ok(handle)
+    }
+
+    /// Drop a column family with all its data. The default column family can't be dropped.
+    pub fn drop_column_family(&self, cf: &ColumnFamilyHandle) -> Result<()> {
+        if cf.id == DEFAULT_COLUMN_FAMILY {
+            return Err(Error::InvalidArgument("can't drop the default column family".to_owned()));
+        }
+        let writer = self.lock_writer()?;
+        let _disk_work = self.core.disk_work.lock().unwrap();
+        let column_family = self.core.column_families.write().unwrap().remove(&cf.id)
+            .ok_or_else(|| unknown_column_family(cf.id))?;
+        // once out of the manifest, its records left in the wal are ignored.
+        self.save_column_families(&writer)?;
+        column_family.destroy()
+    }
+
+    pub fn column_family(&self, name: &str) -> Option<ColumnFamilyHandle> {
+        self.core.column_families.read().unwrap().values()
+            .find(|cf| cf.handle.name() == name)
+            .map(|cf| cf.handle.clone())
+    }
+
+    pub fn list_column_families(&self) -> Vec<String> {
+        self.core.column_families.read().unwrap().values()
+            .map(|cf| cf.handle.name().to_owned())
+            .collect()
+    }
+
+    /// Replace the options of a column family. Like the merge operator,
+    /// they aren't persisted and have to be set again after every open.
+    pub fn set_column_family_options(&self, cf: &ColumnFamilyHandle, options: ColumnFamilyOptions) -> Result<()> {
+        self.core.column_families.write().unwrap().get_mut(&cf.id)
+            .ok_or_else(|| unknown_column_family(cf.id))?
+            .options = options;
+        Ok(())
+    }
+
+    pub fn put_cf(&self, cf: &ColumnFamilyHandle, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put_cf(cf, key.as_ref(), value.as_ref());
+        self.write(batch)
+    }
+
+    pub fn delete_cf(&self, cf: &ColumnFamilyHandle, key: impl AsRef<[u8]>) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete_cf(cf, key.as_ref());
+        self.write(batch)
+    }
+
+    pub fn delete_range_cf(&self, cf: &ColumnFamilyHandle, start: impl AsRef<[u8]>,
+                           end: impl AsRef<[u8]>) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete_range_cf(cf, start.as_ref(), end.as_ref());
+        self.write(batch)
+    }
+
+    pub fn merge_cf(&self, cf: &ColumnFamilyHandle, key: impl AsRef<[u8]>, operand: impl AsRef<[u8]>) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.merge_cf(cf, key.as_ref(), operand.as_ref());
+        self.write(batch)
+    }
+
+    pub fn get_cf(&self, cf: &ColumnFamilyHandle, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        self.read_cf(cf, |cf| cf.get(key.as_ref(), util::get_timestamp()))?
+    }
+
+    pub fn multi_get_cf<K: AsRef<[u8]>>(&self, cf: &ColumnFamilyHandle, keys: &[K]) -> Result<Vec<Option<Vec<u8>>>> {
+        let keys: Vec<&[u8]> = keys.iter().map(|key| key.as_ref()).collect();
+        self.read_cf(cf, |cf| cf.multi_get(&keys, util::get_timestamp()))?
+    }
+
+    pub fn range_cf(&self, cf: &ColumnFamilyHandle, min_key: impl AsRef<[u8]>,
+                    max_key: impl AsRef<[u8]>) -> Result<Vec<Vec<u8>>> {
+        self.read_cf(cf, |cf| cf.range(min_key.as_ref(), max_key.as_ref(), util::get_timestamp()))?
+    }
+
+    /// The value `key` had at `timestamp`, in microseconds since the Unix epoch like
+    /// the timestamps `history` returns. Reliable back to `Options::history_retention`
+    /// ago; older versions may have been compacted away.
+    pub fn get_at(&self, key: impl AsRef<[u8]>, timestamp: u128) -> Result<Option<Vec<u8>>> {
+        self.read_default_cf(|cf| cf.get_at(key.as_ref(), timestamp))
+    }
+
+    /// The versions of `key` still kept, deletes included, newest first.
+    pub fn history(&self, key: impl AsRef<[u8]>) -> Result<Vec<Version>> {
+        self.read_default_cf(|cf| cf.history(key.as_ref()))
+    }
+
+    pub fn get_at_cf(&self, cf: &ColumnFamilyHandle, key: impl AsRef<[u8]>, timestamp: u128) -> Result<Option<Vec<u8>>> {
+        self.read_cf(cf, |cf| cf.get_at(key.as_ref(), timestamp))?
+    }
+
+    pub fn history_cf(&self, cf: &ColumnFamilyHandle, key: impl AsRef<[u8]>) -> Result<Vec<Version>> {
+        self.read_cf(cf, |cf| cf.history(key.as_ref()))?
+    }
+
+    /// Merge all disk files of every column family into one, dropping
+    /// overwritten, deleted and expired entries. Sealed mem_tables are flushed
+    /// first, so they take part. Reads and writes go on meanwhile.
+    pub fn compact(&self) -> Result<()> {
+        self.check_writable()?;
+        while self.core.flush_sealed()? {}
+        self.core.compact()
+    }
+
+    /// Sizes the write stall limits apply to, whether writes are stalled, and how often they were.
+    pub fn stats(&self) -> Stats {
+        self.core.stats()
+    }
+
+    /// Stop the background thread, write the mem_tables out if `flush_on_close` is set,
+    /// sync the WAL and release the db directory. Dropping the db does the same but
+    /// can't report errors.
+    pub fn close(mut self) -> Result<()> {
+        self.shutdown()
+    }
+
+    /// Open the db in the directory `path`, replaying its WAL. Whether a missing
+    /// db gets created or an existing one is an error depends on `options`.
+    pub fn open(path: impl AsRef<Path>, options: Options) -> Result<Db> {
+        let dir_db = path.as_ref().to_owned();
         let dir_file = dir_db.join("DISK_FILE");
         let dir_wal = dir_db.join("WAL");
 
-        fs::create_dir(&dir_db)?;
-        fs::create_dir(&dir_wal)?;
+        let exists = dir_file.exists() || dir_wal.exists();
+        if exists && options.error_if_exists {
+            return Err(Error::AlreadyExists(format!("db {}", dir_db.display())));
+        }
+        if !exists && !options.create_if_missing {
+            return Err(Error::NotFound(format!("db {}", dir_db.display())));
+        }
+        // the disk file directory is created along with the default column family.
+        fs::create_dir_all(&dir_wal)?;
+        let lock_file = lock_dir(&dir_db)?;
+        if !exists {
+            save_comparator(&dir_db, &*options.comparator)?;
+        }
+        check_comparator(&dir_db, &*options.comparator)?;
+
+        let (wal, mem_tables) = WAL::recover_column_families(&dir_wal, &options.comparator,
+                                                             options.history_retention.is_some())?;
+        let prepared = wal.prepared().clone();
+        Db::start(dir_db, options, mem_tables, &prepared, Some(wal), Some(lock_file))
+    }
+
+    /// Open the db in the directory `path` for reading only. Neither the directory nor any
+    /// file in it is touched, and it isn't locked, so the db may be open elsewhere. Writes
+    /// fail with `NotSupported`.
+    ///
+    /// Reads see the db as it was when opened. If it's open elsewhere, disk files compacted
+    /// away after that can make reads fail, until the db is opened read-only again.
+    pub fn open_read_only(path: impl AsRef<Path>, options: Options) -> Result<Db> {
+        Db::open_unlocked(path.as_ref(), options).map(|(db, _)| db)
+    }
+
+    /// Open the db in the directory `path` as a secondary of the primary that has it open,
+    /// e.g. in another process. Like with `open_read_only`, nothing is locked or changed and
+    /// writes fail, but `try_catch_up_with_primary` brings the reads up to date.
+    pub fn open_as_secondary(path: impl AsRef<Path>, options: Options) -> Result<Db> {
+        let (mut db, tail) = Db::open_unlocked(path.as_ref(), options)?;
+        db.secondary = Some(Mutex::new(tail));
+        Ok(db)
+    }
+
+    /// Catch a db opened with `open_as_secondary` up with its primary: pick up the disk
+    /// files it flushed or compacted, and the writes it logged since the last call. Reads
+    /// see all of a catch up or none of it.
+    ///
+    /// Disk files the primary compacts away can make reads fail until the next call.
+    pub fn try_catch_up_with_primary(&self) -> Result<()> {
+        let Some(tail) = &self.secondary else {
+            return Err(Error::NotSupported(format!("db {} isn't open as a secondary", self.core.dir_db.display())));
+        };
+        let mut tail = tail.lock().unwrap();
+        let manifest = self.core.load_manifest()?;
+        let same_families = {
+            let column_families = self.core.column_families.read().unwrap();
+            manifest.families.len() + 1 == column_families.len()
+                && manifest.families.iter().all(|(id, _)| column_families.contains_key(id))
+        };
+        // unless the wal was rolled, disk files can only have been compacted, which keeps
+        // what they hold: they can be opened before the wal is read.
+        if same_families && self.catch_up_with_wal(&mut tail)? {
+            return self.core.reload_prepared(tail.prepared());
+        }
+
+        let mut column_families = retry_not_found(|| {
+            let mem_tables = tail.replay()?;
+            let manifest = self.core.load_manifest()?;
+            open_column_families(&self.core.dir_db, &manifest, &self.core.comparator,
+                                 self.core.history_retention.is_some(), mem_tables, true)
+        })?;
+        let mut current = self.core.column_families.write().unwrap();
+        for (id, cf) in column_families.iter_mut() {
+            if let Some(current_cf) = current.get(id) {
+                cf.options = current_cf.options.clone();
+            }
+        }
+        *current = column_families;
+        drop(current);
+        self.core.reload_prepared(tail.prepared())
+    }
+
+    // reopen the disk files and replay what was appended to the wal, if no wal file came
+    // or went since the last catch up. Returns whether it was done.
+    fn catch_up_with_wal(&self, tail: &mut WALTail) -> Result<bool> {
+        let disk_services = retry_not_found(|| {
+            self.core.column_families.read().unwrap().iter()
+                .map(|(id, cf)| Ok((*id, cf.open_disk_service()?)))
+                .collect::<Result<BTreeMap<_, _>>>()
+        })?;
+        let mut column_families = self.core.column_families.write().unwrap();
+        let mut mem_tables: BTreeMap<u32, MemTable> = column_families.iter_mut()
+            .map(|(id, cf)| (*id, mem::replace(&mut cf.mem_table, self.core.new_mem_table())))
+            .collect();
+        let appended = tail.replay_appended(&mut mem_tables);
+        for (id, cf) in column_families.iter_mut() {
+            cf.mem_table = mem_tables.remove(id).unwrap_or_else(|| self.core.new_mem_table());
+        }
+        if !appended? {
+            return Ok(false);
+        }
+        for (id, disk_service) in disk_services {
+            if let Some(cf) = column_families.get_mut(&id) {
+                cf.disk_service = disk_service;
+            }
+        }
+        Ok(true)
+    }
+
+    // open the db without locking it or changing any of its files. Also returns its wal as
+    // read so far.
+    fn open_unlocked(dir_db: &Path, options: Options) -> Result<(Db, WALTail)> {
+        let dir_wal = dir_db.join("WAL");
+        if !dir_db.join("DISK_FILE").exists() || !dir_wal.exists() {
+            return Err(Error::NotFound(format!("db {}", dir_db.display())));
+        }
+        check_comparator(dir_db, &*options.comparator)?;
+        let mut tail = WALTail::new(&dir_wal, options.comparator.clone(), options.history_retention.is_some());
+        let db = retry_not_found(|| {
+            let mem_tables = tail.replay()?;
+            Db::start(dir_db.to_owned(), options.clone(), mem_tables, tail.prepared(), None, None)
+        })?;
+        Ok((db, tail))
+    }
+
+    // open the column families over the replayed `mem_tables`, and start the background
+    // thread unless the db is read-only, which it is without a `wal`.
+    fn start(dir_db: PathBuf, options: Options, mem_tables: BTreeMap<u32, MemTable>,
+             prepared: &BTreeMap<String, WriteBatch>, wal: Option<WAL>, lock_file: Option<File>) -> Result<Db> {
+        let read_only = wal.is_none();
+        // whatever was flushed to disk files was written before now, so transactions
+        // begun from here on see it.
+        let last_timestamp = mem_tables.values()
+            .flat_map(|mem_table| mem_table.iter())
+            .map(|(_, entry)| entry.timestamp)
+            .max()
+            .unwrap_or(0)
+            .max(util::get_timestamp());
 
-        let disk_service = DiskService::new(&dir_file)?;
-        let mem_table = MemTable::new();
-        let wal = WAL::new(&dir_wal).unwrap();
-        Ok(db {
+        let manifest = ColumnFamilyManifest::load(&dir_db.join("COLUMN_FAMILY").join("MANIFEST"))?;
+        let mut column_families = open_column_families(&dir_db, &manifest, &options.comparator,
+                                                       options.history_retention.is_some(), mem_tables, read_only)?;
+        column_families.get_mut(&DEFAULT_COLUMN_FAMILY).unwrap().options = options.default_column_family();
+
+        let lock_manager = Arc::new(LockManager::new());
+        let prepared_transactions = recover_prepared(&lock_manager, prepared)?;
+        let core = Arc::new(DbCore {
             dir_db,
-            disk_service,
-            mem_table,
-            wal,
+            column_families: RwLock::new(column_families),
+            writer: Mutex::new(Writer {
+                wal,
+                last_timestamp,
+                next_column_family_id: manifest.next_id,
+                groups: 0,
+                prepared_transactions,
+            }),
+            write_queue: Mutex::new(WriteQueue {
+                waiting: VecDeque::new(),
+                next_id: 0,
+                leading: false,
+                finished: HashMap::new(),
+            }),
+            write_queue_changed: Condvar::new(),
+            pipeline: Mutex::new(Pipeline {
+                applied: 0,
+                visible_timestamp: last_timestamp,
+            }),
+            pipeline_changed: Condvar::new(),
+            disk_work: Mutex::new(()),
+            background: Mutex::new(Background {
+                sealed_wals: VecDeque::new(),
+                compaction_requested: false,
+                shutting_down: false,
+                delayed_writes: 0,
+                stopped_writes: 0,
+                stall_micros: 0,
+                error: None,
+            }),
+            background_changed: Condvar::new(),
+            lock_manager,
+            comparator: options.comparator.clone(),
+            history_retention: options.history_retention.map(|retention| retention.as_micros()),
+            limits: WriteStallLimits::new(&options),
+            compaction_trigger: options.disk_file_compaction_trigger,
+        });
+        let background_thread = if read_only {
+            None
+        } else {
+            let core = core.clone();
+            Some(thread::Builder::new()
+                .name("pipengine-background".to_owned())
+                .spawn(move || core.run_background())?)
+        };
+        Ok(Db {
+            core,
+            background_thread,
+            flush_on_close: options.flush_on_close,
+            pipelined_write: options.pipelined_write,
+            read_only,
+            closed: false,
+            _lock_file: lock_file,
+            secondary: None,
         })
     }
 
-    pub fn put(&mut self, key: &str, value: &str) {
-        let timestamp = util::get_timestamp();
-        self.wal.put(key.as_bytes(), value.as_bytes(), timestamp);
-        self.mem_table.put(key.as_bytes(), value.as_bytes(), timestamp);
-        //todo if mem_table.size > max_size
-        if self.mem_table.is_over_weight() {
-            //write mem_table to disk.
-            //fresh wal.
-            self.disk_service.write_mem_table_to_disk(&mut self.mem_table);
-            self.mem_table.clear();
-            self.wal.fresh();
+    /// The order of the keys.
+    #[cfg(feature = "typed")]
+    pub(crate) fn comparator(&self) -> &dyn Comparator {
+        &*self.core.comparator
+    }
+
+    /// Timestamp of the newest write to `key`, deletes included.
+    pub(crate) fn latest_timestamp(&self, key: &[u8]) -> Result<Option<u128>> {
+        self.read_default_cf(|cf| cf.latest_timestamp(key))
+    }
+
+    // lock the write path. Writing fails for good once a background flush has failed.
+    fn lock_writer(&self) -> Result<MutexGuard<'_, Writer>> {
+        self.check_writable()?;
+        let writer = self.core.writer.lock().unwrap();
+        if let Some(error) = &self.core.background.lock().unwrap().error {
+            return Err(background_failed(error));
+        }
+        Ok(writer)
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::NotSupported(format!("db {} is open read-only", self.core.dir_db.display())));
+        }
+        Ok(())
+    }
+
+    // hold back writes while background work lags behind: past a slowdown limit every
+    // write group is delayed a little, past a stop limit writes wait for the background
+    // thread to get things back under it.
+    fn throttle(&self) -> Result<()> {
+        let start = Instant::now();
+        let mut stopped = false;
+        let mut background = loop {
+            let condition = self.core.limits.condition(&self.core.stats());
+            let mut background = self.core.background.lock().unwrap();
+            if let Some(error) = &background.error {
+                return Err(background_failed(error));
+            }
+            match condition {
+                WriteStallCondition::Normal => break background,
+                WriteStallCondition::Delayed(_) if stopped => break background,
+                WriteStallCondition::Delayed(_) => {
+                    drop(background);
+                    thread::sleep(WRITE_SLOWDOWN_DELAY);
+                    let mut background = self.core.background.lock().unwrap();
+                    background.delayed_writes += 1;
+                    background.stall_micros += start.elapsed().as_micros() as u64;
+                    return Ok(());
+                }
+                WriteStallCondition::Stopped(cause) => {
+                    stopped = true;
+                    if cause != WriteStallCause::ImmutableMemTables {
+                        background.compaction_requested = true;
+                        self.core.background_changed.notify_all();
+                    }
+                    drop(self.core.background_changed.wait_timeout(background, WRITE_STOP_RECHECK).unwrap());
+                }
+            }
+        };
+        if stopped {
+            background.stopped_writes += 1;
+            background.stall_micros += start.elapsed().as_micros() as u64;
+        }
+        Ok(())
+    }
+
+    fn read_default_cf<T>(&self, read: impl FnOnce(&ColumnFamily) -> T) -> T {
+        read(&self.core.column_families.read().unwrap()[&DEFAULT_COLUMN_FAMILY])
+    }
+
+    fn update_default_cf<T>(&self, update: impl FnOnce(&mut ColumnFamily) -> T) -> T {
+        update(self.core.column_families.write().unwrap().get_mut(&DEFAULT_COLUMN_FAMILY).unwrap())
+    }
+
+    fn read_cf<T>(&self, cf: &ColumnFamilyHandle, read: impl FnOnce(&ColumnFamily) -> T) -> Result<T> {
+        let column_families = self.core.column_families.read().unwrap();
+        let column_family = column_families.get(&cf.id).ok_or_else(|| unknown_column_family(cf.id))?;
+        Ok(read(column_family))
+    }
+
+    fn column_family_dir(&self, id: u32) -> PathBuf {
+        self.core.dir_db.join("COLUMN_FAMILY").join(id.to_string())
+    }
+
+    // takes the writer, which guards next_column_family_id.
+    fn save_column_families(&self, writer: &Writer) -> Result<()> {
+        let dir = self.core.dir_db.join("COLUMN_FAMILY");
+        fs::create_dir_all(&dir)?;
+        let manifest = ColumnFamilyManifest {
+            next_id: writer.next_column_family_id,
+            families: self.core.column_families.read().unwrap().values()
+                .filter(|cf| cf.handle.id != DEFAULT_COLUMN_FAMILY)
+                .map(|cf| (cf.handle.id, cf.handle.name().to_owned()))
+                .collect(),
+        };
+        manifest.save(&dir.join("MANIFEST"))
+    }
+
+    // write `batch` holding the writer lock.
+    fn write_locked(&self, writer: &mut Writer, batch: &WriteBatch, sync: bool) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.check_batch(batch)?;
+        let timestamp = writer.next_timestamp();
+        writer.wal().write_batch(batch, timestamp)?;
+        if sync {
+            writer.wal().sync()?;
         }
+        writer.groups += 1;
+        self.core.apply_in_order(writer.groups, &[(batch, timestamp)]);
+        self.seal_if_over_weight(writer)
     }
 
-    pub fn get(&self, key: &str) -> Option<String> {
-        //in mem_table
-        return if let Some(entry) = self.mem_table.get(key.as_bytes()) {
-            self.get_value_from_mem_entry(entry)
+    // done by the leader of a write group: take the writer lock, then as many queued
+    // writes as fit in a group, log them together and apply them. Steps down as
+    // leader as soon as the group is logged, if writes are pipelined, or else once
+    // it's applied. Returns the result of each write.
+    fn commit_group(&self) -> Vec<(u64, Result<()>)> {
+        let writer = self.throttle().and_then(|()| self.lock_writer());
+        let group = {
+            let mut queue = self.core.write_queue.lock().unwrap();
+            let mut size = 0;
+            let mut len = 0;
+            for write in queue.waiting.iter() {
+                if len > 0 && size + write.batch.data_size() > MAX_WRITE_GROUP_SIZE {
+                    break;
+                }
+                size += write.batch.data_size();
+                len += 1;
+            }
+            queue.waiting.drain(..len).collect::<Vec<_>>()
+        };
+        let mut writer = match writer {
+            Ok(writer) => writer,
+            Err(err) => {
+                self.step_down();
+                return group.into_iter().map(|write| (write.id, Err(copy_error(&err)))).collect();
+            }
+        };
+
+        let mut results = vec![];
+        let mut batches = vec![];
+        let mut accepted = vec![];
+        let mut sync = false;
+        for write in group.iter() {
+            match self.check_batch(&write.batch) {
+                Ok(()) => {
+                    batches.push((&write.batch, writer.next_timestamp()));
+                    accepted.push(write.id);
+                    sync |= write.sync;
+                }
+                Err(err) => results.push((write.id, Err(err))),
+            }
+        }
+        if let Err(err) = writer.wal().write_group(&batches, sync) {
+            self.step_down();
+            results.extend(accepted.into_iter().map(|id| (id, Err(copy_error(&err)))));
+            return results;
+        }
+        writer.groups += 1;
+        let group_number = writer.groups;
+        let writer = if self.pipelined_write {
+            drop(writer);
+            self.step_down();
+            None
         } else {
-            //TODO if not in mem_table,search in disk.
-            if let Some(entry) = self.disk_service.get(key.as_bytes()) {
-                self.get_value_from_mem_entry(entry)
-            } else {
-                None
+            Some(writer)
+        };
+
+        self.core.apply_in_order(group_number, &batches);
+        let sealed = match writer {
+            Some(mut writer) => {
+                let sealed = self.seal_if_over_weight(&mut writer);
+                drop(writer);
+                self.step_down();
+                sealed
             }
+            None if self.is_over_weight() => self.lock_writer().and_then(|mut writer| self.seal_if_over_weight(&mut writer)),
+            None => Ok(()),
         };
+        // the group's writes have landed, so sealing failing afterwards fails the
+        // writes to come instead, like a failed flush.
+        if let Err(err) = sealed {
+            self.core.fail_background(&err);
+        }
+        results.extend(accepted.into_iter().map(|id| (id, Ok(()))));
+        results
     }
 
-    pub fn delete(&mut self, key: &str) {
-        let timestamp = util::get_timestamp();
-        self.wal.delete(key.as_bytes(), timestamp).unwrap();
-        self.mem_table.delete(key.as_bytes(), timestamp);
+    // let the next waiting writer lead a group.
+    fn step_down(&self) {
+        self.core.write_queue.lock().unwrap().leading = false;
+        self.core.write_queue_changed.notify_all();
     }
 
-    pub fn range(&self, min_key: &str, max_key: &str) -> Vec<String> {
-        let mut vec_range = Vec::new();
+    // make sure every op of `batch` can be applied, before any of it is logged.
+    fn check_batch(&self, batch: &WriteBatch) -> Result<()> {
+        let column_families = self.core.column_families.read().unwrap();
+        for op in batch.iter() {
+            let cf = column_families.get(&op.column_family())
+                .ok_or_else(|| unknown_column_family(op.column_family()))?;
+            match op {
+                BatchOp::Merge { .. } => cf.check_merge_operator()?,
+                BatchOp::DeleteRange { start, end, .. } if self.core.comparator.compare(start, end).is_gt() => {
+                    return Err(Error::InvalidArgument("range start is after its end".to_owned()));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
 
-        let mem_entries = self.mem_table
-            .range(min_key.as_bytes(), max_key.as_bytes());
-        self.vec_range_push(&mem_entries, &mut vec_range);
-        //todo search in disk file.
-        let disk_entries = self.disk_service.
-            range(min_key.as_bytes(), max_key.as_bytes());
-        self.vec_range_push(&disk_entries, &mut vec_range);
-        vec_range
+    fn is_over_weight(&self) -> bool {
+        self.core.column_families.read().unwrap().values().any(|cf| cf.is_over_weight())
     }
 
-    pub fn close(self) {}
+    // all column families share the wal, so they are sealed together
+    // and the wal file can go once all of them are flushed.
+    fn seal_if_over_weight(&self, writer: &mut Writer) -> Result<()> {
+        if self.is_over_weight() {
+            // every group in the wal file has to be in the mem_tables sealed with it.
+            drop(self.core.wait_applied(writer.groups));
+            self.core.seal_mem_tables(writer)?;
+        }
+        Ok(())
+    }
 
-    pub fn open(path: &PathBuf) -> io::Result<db> {
-        let dir_db = PathBuf::from(path);
-        let dir_file = dir_db.join("DISK_FILE");
-        let dir_wal = dir_db.join("WAL");
+    fn shutdown(&mut self) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+        self.core.background.lock().unwrap().shutting_down = true;
+        self.core.background_changed.notify_all();
+        if let Some(background_thread) = self.background_thread.take() {
+            let _ = background_thread.join();
+        }
 
-        if !dir_file.exists() || !dir_wal.exists() {
-            return Err(io::Error::new(ErrorKind::NotFound, "Not Found"));
+        if self.read_only {
+            return Ok(());
+        }
+        let mut writer = self.core.writer.lock().unwrap();
+        // without flushing, the mem_tables are rebuilt from the wal files on the next open.
+        if self.flush_on_close {
+            let unflushed = self.core.column_families.read().unwrap().values()
+                .any(|cf| !cf.mem_table.is_empty());
+            if unflushed {
+                self.core.seal_mem_tables(&mut writer)?;
+            }
+            while self.core.flush_sealed()? {}
         }
+        writer.wal().sync()
+    }
+}
 
-        let disk_service = DiskService::open(&dir_file)?;
-        let (wal, mem_table) = WAL::recover(&dir_wal)?;
-        Ok(db {
-            dir_db,
-            disk_service,
-            mem_table,
-            wal,
-        })
+impl Drop for Db {
+    fn drop(&mut self) {
+        let _ = self.shutdown();
     }
+}
 
-    fn get_value_from_mem_entry(&self, entry: &MemTableEntry) -> Option<String> {
-        if let Some(value) = &entry.value {
-            let value = value.to_owned();
-            Some(String::from_utf8(value).unwrap())
-        } else {
-            None
+impl DbCore {
+    fn load_manifest(&self) -> Result<ColumnFamilyManifest> {
+        ColumnFamilyManifest::load(&self.dir_db.join("COLUMN_FAMILY").join("MANIFEST"))
+    }
+
+    // replace the prepared transactions by those the wal of a secondary's primary holds now.
+    fn reload_prepared(&self, prepared: &BTreeMap<String, WriteBatch>) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        // dropped first, so their locks are released.
+        writer.prepared_transactions.clear();
+        writer.prepared_transactions = recover_prepared(&self.lock_manager, prepared)?;
+        Ok(())
+    }
+
+    // wait until write group `group` and every one before it is in the mem_tables.
+    fn wait_applied(&self, group: u64) -> MutexGuard<'_, Pipeline> {
+        let mut pipeline = self.pipeline.lock().unwrap();
+        while pipeline.applied < group {
+            pipeline = self.pipeline_changed.wait(pipeline).unwrap();
+        }
+        pipeline
+    }
+
+    // second stage of the write path: apply the batches of write group `group`
+    // once the group logged before it is applied.
+    fn apply_in_order(&self, group: u64, batches: &[(&WriteBatch, u128)]) {
+        let mut pipeline = self.wait_applied(group - 1);
+        let mut column_families = self.column_families.write().unwrap();
+        for (batch, timestamp) in batches.iter() {
+            apply_batch(&mut column_families, batch, *timestamp);
+        }
+        drop(column_families);
+        pipeline.applied = group;
+        if let Some((_, timestamp)) = batches.last() {
+            pipeline.visible_timestamp = *timestamp;
+        }
+        self.pipeline_changed.notify_all();
+    }
+
+    // make every column family's mem_table immutable and start a new wal file,
+    // leaving the mem_tables and the old wal file to the background thread.
+    fn seal_mem_tables(&self, writer: &mut Writer) -> Result<()> {
+        let sealed_wal = writer.wal().roll()?;
+        for cf in self.column_families.write().unwrap().values_mut() {
+            cf.seal_mem_table();
+        }
+        self.background.lock().unwrap().sealed_wals.push_back(sealed_wal);
+        self.background_changed.notify_all();
+        Ok(())
+    }
+
+    // write the oldest immutable mem_table of every column family to disk and delete
+    // the wal file that logged them. Returns false if nothing was sealed.
+    fn flush_sealed(&self) -> Result<bool> {
+        let _disk_work = self.disk_work.lock().unwrap();
+        let sealed_wal = match self.background.lock().unwrap().sealed_wals.front() {
+            Some(sealed_wal) => sealed_wal.clone(),
+            None => return Ok(false),
+        };
+        // the jobs work on copies, so the lock isn't held while writing the files.
+        let jobs: Vec<_> = self.column_families.read().unwrap().values()
+            .filter_map(|cf| cf.flush_job())
+            .collect();
+        let mut flushed = vec![];
+        for job in jobs {
+            let id = job.id;
+            flushed.push((id, job.run()?));
+        }
+        {
+            let mut column_families = self.column_families.write().unwrap();
+            for (id, disk_service) in flushed {
+                // column families are only dropped holding disk_work, so it's still there.
+                column_families.get_mut(&id).unwrap().finish_flush(disk_service);
+            }
+        }
+        WAL::mark_flushed(&sealed_wal)?;
+        fs::remove_file(&sealed_wal)?;
+        self.background.lock().unwrap().sealed_wals.pop_front();
+        self.background_changed.notify_all();
+        Ok(true)
+    }
+
+    // merge the disk files of every column family, see `Db::compact`.
+    fn compact(&self) -> Result<()> {
+        let _disk_work = self.disk_work.lock().unwrap();
+        // versions replaced before the horizon can't be read anymore.
+        let horizon = util::get_timestamp().saturating_sub(self.history_retention.unwrap_or(0));
+        let disk_services: Vec<_> = self.column_families.read().unwrap().values()
+            .map(|cf| (cf.handle.id, cf.disk_service.clone()))
+            .collect();
+        for (id, mut disk_service) in disk_services {
+            let replaced = disk_service.compact(horizon)?;
+            if replaced.is_empty() {
+                continue;
+            }
+            // column families are only dropped holding disk_work, so it's still there.
+            self.column_families.write().unwrap().get_mut(&id).unwrap().disk_service = disk_service;
+            for path in replaced {
+                fs::remove_file(path)?;
+            }
+        }
+        self.background_changed.notify_all();
+        Ok(())
+    }
+
+    // fail every later write with `err`, keeping the first error if there's one already.
+    fn fail_background(&self, err: &Error) {
+        self.background.lock().unwrap().error.get_or_insert_with(|| err.to_string());
+        self.background_changed.notify_all();
+    }
+
+    fn new_mem_table(&self) -> MemTable {
+        MemTable::new(self.comparator.clone()).keep_history(self.history_retention.is_some())
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = Stats::default();
+        for cf in self.column_families.read().unwrap().values() {
+            stats.immutable_mem_tables = stats.immutable_mem_tables.max(cf.immutable_mem_tables.len());
+            stats.disk_files = stats.disk_files.max(cf.disk_service.file_count());
+            stats.pending_compaction_bytes = stats.pending_compaction_bytes.max(cf.disk_service.pending_compaction_bytes());
         }
+        stats.write_stall = self.limits.condition(&stats);
+        let background = self.background.lock().unwrap();
+        stats.delayed_writes = background.delayed_writes;
+        stats.stopped_writes = background.stopped_writes;
+        stats.stall_micros = background.stall_micros;
+        stats
     }
 
-    fn vec_range_push(&self, entries: &Vec<MemTableEntry>
-                      , vec_range: &mut Vec<String>) {
-        for entry in entries.iter() {
-            if let Some(value) = &entry.value {
-                vec_range.push(String::from_utf8(value.clone()).unwrap());
+    fn needs_compaction(&self) -> bool {
+        let stats = self.stats();
+        (self.compaction_trigger > 0 && stats.disk_files >= self.compaction_trigger)
+            || self.limits.needs_compaction(&stats)
+    }
+
+    // body of the background thread: flush sealed mem_tables, and compact when
+    // there are too many disk files, until shut down.
+    fn run_background(&self) {
+        loop {
+            let compaction_requested = {
+                let mut background = self.background.lock().unwrap();
+                while background.sealed_wals.is_empty() && !background.compaction_requested
+                    && !background.shutting_down {
+                    background = self.background_changed.wait(background).unwrap();
+                }
+                if background.shutting_down {
+                    return;
+                }
+                mem::take(&mut background.compaction_requested)
+            };
+            let result = self.flush_sealed().and_then(|_| {
+                if compaction_requested || self.needs_compaction() {
+                    self.compact()?;
+                }
+                Ok(())
+            });
+            if let Err(err) = result {
+                self.fail_background(&err);
+                return;
             }
         }
     }
+}
+
+impl Writer {
+    // only reachable through `Db::lock_writer`, which fails for read-only dbs.
+    fn wal(&mut self) -> &mut WAL {
+        self.wal.as_mut().unwrap()
+    }
+
+    fn next_timestamp(&mut self) -> u128 {
+        self.last_timestamp = std::cmp::max(util::get_timestamp(), self.last_timestamp + 1);
+        self.last_timestamp
+    }
+}
+
+// ops of dropped column families are skipped.
+fn apply_batch(column_families: &mut BTreeMap<u32, ColumnFamily>, batch: &WriteBatch, timestamp: u128) {
+    for op in batch.iter() {
+        if let Some(cf) = column_families.get_mut(&op.column_family()) {
+            cf.apply(op, timestamp);
+        }
+    }
+}
+
+fn background_failed(error: &str) -> Error {
+    Error::Io(io::Error::other(format!("background work failed: {}", error)))
+}
+
+// open the default column family and those of `manifest` over their replayed `mem_tables`.
+// Read-only, no directory is created, so a column family dropped meanwhile is not found.
+fn open_column_families(dir_db: &Path, manifest: &ColumnFamilyManifest, comparator: &Arc<dyn Comparator>,
+                        keep_history: bool, mut mem_tables: BTreeMap<u32, MemTable>,
+                        read_only: bool) -> Result<BTreeMap<u32, ColumnFamily>> {
+    let open = if read_only { ColumnFamily::open_existing } else { ColumnFamily::open };
+    let new_mem_table = || MemTable::new(comparator.clone()).keep_history(keep_history);
+    let mut column_families = BTreeMap::new();
+    let default_handle = ColumnFamilyHandle::new(DEFAULT_COLUMN_FAMILY, DEFAULT_COLUMN_FAMILY_NAME);
+    let default_mem_table = mem_tables.remove(&DEFAULT_COLUMN_FAMILY).unwrap_or_else(new_mem_table);
+    column_families.insert(DEFAULT_COLUMN_FAMILY, open(default_handle, &dir_db.join("DISK_FILE"), default_mem_table)?);
+    for (id, name) in manifest.families.iter() {
+        let handle = ColumnFamilyHandle::new(*id, name);
+        let mem_table = mem_tables.remove(id).unwrap_or_else(new_mem_table);
+        let dir = dir_db.join("COLUMN_FAMILY").join(id.to_string());
+        column_families.insert(*id, open(handle, &dir, mem_table)?);
+    }
+    Ok(column_families)
+}
+
+fn recover_prepared(lock_manager: &Arc<LockManager>, prepared: &BTreeMap<String, WriteBatch>)
+                    -> Result<HashMap<String, PessimisticTransaction>> {
+    let mut prepared_transactions = HashMap::new();
+    for (name, batch) in prepared {
+        let txn = PessimisticTransaction::from_write_batch(lock_manager.clone(), batch)?;
+        prepared_transactions.insert(name.to_owned(), txn);
+    }
+    Ok(prepared_transactions)
+}
+
+// open a db that may be open elsewhere, by reading its wal and then its disk files: a wal file
+// flushed and deleted meanwhile is found on disk after. A disk file compacted away in between
+// makes opening fail, which is retried.
+fn retry_not_found<T>(mut open: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut attempts = 0;
+    loop {
+        match open() {
+            Err(Error::Io(err)) if err.kind() == io::ErrorKind::NotFound && attempts < 3 => attempts += 1,
+            opened => return opened,
+        }
+    }
+}
+
+// a write group fails as a whole, so every writer in it gets a copy of the error.
+fn copy_error(err: &Error) -> Error {
+    match err {
+        Error::Io(err) => Error::Io(io::Error::new(err.kind(), err.to_string())),
+        Error::Corruption { file, offset } => Error::Corruption { file: file.clone(), offset: *offset },
+        Error::InvalidArgument(message) => Error::InvalidArgument(message.clone()),
+        Error::NotFound(message) => Error::NotFound(message.clone()),
+        Error::AlreadyExists(message) => Error::AlreadyExists(message.clone()),
+        Error::Busy(message) => Error::Busy(message.clone()),
+        Error::Conflict(message) => Error::Conflict(message.clone()),
+        Error::TimedOut(message) => Error::TimedOut(message.clone()),
+        Error::Deadlock(message) => Error::Deadlock(message.clone()),
+        Error::NotSupported(message) => Error::NotSupported(message.clone()),
+    }
+}
+
+fn scan(cf: &ColumnFamily, options: &ReadOptions) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let lower = options.iterate_lower_bound.as_deref().map_or(Unbounded, Included);
+    let upper = options.iterate_upper_bound.as_deref().map_or(Unbounded, Excluded);
+    cf.scan(lower, upper, util::get_timestamp())
+}
+
+// take the exclusive lock on the LOCK file of `dir`, so no other process
+// can open the db while it's held. The OS releases it when the file is closed.
+fn lock_dir(dir: &Path) -> Result<File> {
+    let lock_file = OpenOptions::new().read(true).write(true).create(true).truncate(false)
+        .open(dir.join("LOCK"))?;
+    match lock_file.try_lock() {
+        Ok(()) => Ok(lock_file),
+        Err(TryLockError::WouldBlock) => {
+            Err(Error::Busy(format!("db {} is already open", dir.display())))
+        }
+        Err(TryLockError::Error(err)) => Err(err.into()),
+    }
+}
+
+// record the name of the comparator a new db is created with, in one rename like the manifest.
+fn save_comparator(dir: &Path, comparator: &dyn Comparator) -> Result<()> {
+    let path = dir.join("COMPARATOR");
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, comparator.name())?;
+    File::open(&tmp_path)?.sync_all()?;
+    fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
+// fail unless `comparator` is the one the db was created with. Dbs that
+// don't record it are from before comparators, and ordered bytewise.
+fn check_comparator(dir: &Path, comparator: &dyn Comparator) -> Result<()> {
+    let name = match fs::read_to_string(dir.join("COMPARATOR")) {
+        Ok(name) => name,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => BytewiseComparator.name().to_owned(),
+        Err(err) => return Err(err.into()),
+    };
+    if name != comparator.name() {
+        return Err(Error::InvalidArgument(format!(
+            "db {} was created with comparator {}, not {}", dir.display(), name, comparator.name(),
+        )));
+    }
+    Ok(())
+}
 
+fn into_string(value: Vec<u8>) -> Result<String> {
+    String::from_utf8(value).map_err(|err| Error::InvalidArgument(format!("value is not UTF-8: {}", err)))
+}
+
+fn unknown_column_family(id: u32) -> Error {
+    Error::NotFound(format!("column family with id {}", id))
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::db::db;
+    use crate::comparator::ReverseBytewiseComparator;
+    use crate::column_family::ColumnFamilyOptions;
+    use crate::db::Db;
+    use crate::options::{Options, ReadOptions, WriteOptions};
+    use crate::transaction::TransactionOptions;
+    use crate::error::Result;
+    use crate::stats::{WriteStallCause, WriteStallCondition};
+    use crate::write_batch::WriteBatch;
+    use crate::merge_operator::MergeOperator;
+    use crate::error::Error;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Duration;
     use std::path::PathBuf;
     use std::fs;
 
     #[test]
-    fn test_put_get_range() {        let mut handler_db = db::new().unwrap();
-        handler_db.put("a", "value-a");
-        handler_db.put("b", "value-b");
-        handler_db.put("a", "value-a2");
-        handler_db.put("c", "value-c");
-        handler_db.put("d", "value-d");
-        let a = handler_db.get("a").unwrap();
-        let b = handler_db.get("b").unwrap();
-        let c = handler_db.get("c").unwrap();
+    fn test_put_get_range() {
+        let handler_db = Db::create(PathBuf::from("./DB_test_put_get_range"), Options::default()).unwrap();
+        handler_db.put("a", "value-a").unwrap();
+        handler_db.put("b", "value-b").unwrap();
+        handler_db.put("a", "value-a2").unwrap();
+        handler_db.put("c", "value-c").unwrap();
+        handler_db.put("d", "value-d").unwrap();
+        let a = handler_db.get("a").unwrap().unwrap();
+        let b = handler_db.get("b").unwrap().unwrap();
+        let c = handler_db.get("c").unwrap().unwrap();
 
-        assert_eq!(a, "value-a2".to_string());
-        assert_eq!(b, "value-b".to_string());
-        assert_eq!(c, "value-c".to_string());
+        assert_eq!(a, "value-a2".as_bytes());
+        assert_eq!(b, "value-b".as_bytes());
+        assert_eq!(c, "value-c".as_bytes());
 
         let test_data_range =
-            vec!["value-a2".to_string(), "value-b".to_string(), "value-c".to_string()];
-        let vec_range = handler_db.range("a", "c");
+            vec!["value-a2".as_bytes(), "value-b".as_bytes(), "value-c".as_bytes()];
+        let vec_range = handler_db.range("a", "c").unwrap();
 
         let mut test_iter = test_data_range.into_iter();
         for val in vec_range {
@@ -155,36 +1309,940 @@ mod tests {
             //println!("{}",val);
         }
 
-        handler_db.put("e", "value-e");
-        handler_db.put("f", "value-f");
-        handler_db.put("g", "value-g");
-        handler_db.put("h", "value-h");
+        handler_db.put("e", "value-e").unwrap();
+        handler_db.put("f", "value-f").unwrap();
+        handler_db.put("g", "value-g").unwrap();
+        handler_db.put("h", "value-h").unwrap();
+
+        let path = handler_db.core.dir_db.clone();
+        handler_db.close().unwrap();
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_binary_values() {
+        let path = PathBuf::from("./DB_test_binary_values");
+        let handler_db = Db::create(&path, Options::default()).unwrap();
+
+        let key = 7u64.to_be_bytes();
+        let value = [0xff, 0x00, 0xfe, 0x80];
+        handler_db.put(key, value).unwrap();
+        handler_db.put("text", "plain value").unwrap();
+        assert_eq!(handler_db.get(key).unwrap().unwrap(), value);
+        assert_eq!(handler_db.range(0u64.to_be_bytes(), 100u64.to_be_bytes()).unwrap(), vec![value.to_vec()]);
+        assert_eq!(handler_db.get_string("text").unwrap().unwrap(), "plain value");
+        assert!(handler_db.get_string("missing").unwrap().is_none());
+        let err = handler_db.get_string(key).unwrap_err();
+        assert!(matches!(err, Error::InvalidArgument(_)));
 
-        fs::remove_dir_all(handler_db.dir_db);
+        handler_db.close().unwrap();
+        let handler_db = Db::open(&path, Options::default()).unwrap();
+        assert_eq!(handler_db.get(key).unwrap().unwrap(), value);
+        assert_eq!(handler_db.multi_get_strings(&["text", "missing"]).unwrap(),
+                   vec![Some("plain value".to_string()), None]);
+        handler_db.close().unwrap();
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_open_options() {
+        let path = PathBuf::from("./DB_test_open_options");
+        let err = Db::open(&path, Options::default()).err().unwrap();
+        assert!(matches!(err, Error::NotFound(_)));
+        assert!(!path.exists());
+
+        let options = Options {
+            create_if_missing: true,
+            ..Options::default()
+        };
+        let handler_db = Db::open(&path, options.clone()).unwrap();
+        handler_db.put("a", "value-a").unwrap();
+        handler_db.close().unwrap();
+        assert!(path.join("DISK_FILE").is_dir());
+        assert!(path.join("WAL").is_dir());
+
+        let handler_db = Db::open(&path, options.clone()).unwrap();
+        assert_eq!(handler_db.get("a").unwrap().unwrap(), b"value-a");
+        handler_db.close().unwrap();
+        let options = Options {
+            error_if_exists: true,
+            ..options
+        };
+        assert!(matches!(Db::open(&path, options).err().unwrap(), Error::AlreadyExists(_)));
+        assert!(matches!(Db::create(&path, Options::default()).err().unwrap(), Error::AlreadyExists(_)));
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_lock() {
+        let path = PathBuf::from("./DB_test_lock");
+        let handler_db = Db::create(&path, Options::default()).unwrap();
+        assert!(path.join("LOCK").exists());
+        let err = Db::open(&path, Options::default()).err().unwrap();
+        assert!(matches!(err, Error::Busy(_)));
+
+        // the lock goes away with the db, whether closed or dropped.
+        handler_db.close().unwrap();
+        let handler_db = Db::open(&path, Options::default()).unwrap();
+        drop(handler_db);
+        Db::open(&path, Options::default()).unwrap();
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_flush_on_close() {
+        let path = PathBuf::from("./DB_test_flush_on_close");
+        let options = Options {
+            flush_on_close: true,
+            ..Options::default()
+        };
+        let handler_db = Db::create(&path, options.clone()).unwrap();
+        handler_db.put("a", "value-a").unwrap();
+        handler_db.put("b", "value-b").unwrap();
+        handler_db.close().unwrap();
+
+        // everything went to a disk file, leaving nothing in the wal to replay.
+        let wal_sizes: Vec<u64> = fs::read_dir(path.join("WAL")).unwrap()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.path().extension().is_some_and(|extension| extension == "wal"))
+            .map(|entry| entry.metadata().unwrap().len())
+            .collect();
+        assert_eq!(wal_sizes, vec![0]);
+        let handler_db = Db::open(&path, options).unwrap();
+        assert!(handler_db.read_default_cf(|cf| cf.mem_table.is_empty()));
+        assert_eq!(handler_db.get("a").unwrap().unwrap(), b"value-a");
+
+        // dropping without close shuts down the same way.
+        handler_db.put("c", "value-c").unwrap();
+        drop(handler_db);
+        let handler_db = Db::open(&path, Options::default()).unwrap();
+        assert!(handler_db.read_default_cf(|cf| cf.mem_table.is_empty()));
+        assert_eq!(handler_db.get("c").unwrap().unwrap(), b"value-c");
+        handler_db.close().unwrap();
+
+        fs::remove_dir_all(&path).unwrap();
     }
 
     #[test]
     fn test_close_and_open() {
-        let mut handler_db = db::new().unwrap();
-        handler_db.put("a", "value-a");
-        handler_db.put("b", "value-b");
-        handler_db.put("a", "value-a2");
-        handler_db.put("c", "value-c");
-        handler_db.put("d", "value-d");
-        let a = handler_db.get("a").unwrap();
-        let b = handler_db.get("b").unwrap();
-        let c = handler_db.get("c").unwrap();
-        handler_db.close();
-        let path = PathBuf::from(format!("./{}", "DB"));
-        let new_handler_db = db::open(&path).unwrap();
-
-        let a = new_handler_db.get("a").unwrap();
-        let b = new_handler_db.get("b").unwrap();
-        let c = new_handler_db.get("c").unwrap();
-
-        assert_eq!(a, "value-a2".to_string());
-        assert_eq!(b, "value-b".to_string());
-        assert_eq!(c, "value-c".to_string());
-        fs::remove_dir_all(new_handler_db.dir_db);
-    }
-}
\ No newline at end of file
+        let handler_db = Db::create(PathBuf::from("./DB_test_close_and_open"), Options::default()).unwrap();
+        handler_db.put("a", "value-a").unwrap();
+        handler_db.put("b", "value-b").unwrap();
+        handler_db.put("a", "value-a2").unwrap();
+        handler_db.put("c", "value-c").unwrap();
+        handler_db.put("d", "value-d").unwrap();
+        let _a = handler_db.get("a").unwrap().unwrap();
+        let _b = handler_db.get("b").unwrap().unwrap();
+        let _c = handler_db.get("c").unwrap().unwrap();
+        handler_db.close().unwrap();
+        let path = PathBuf::from(format!("./{}", "DB_test_close_and_open"));
+        let new_handler_db = Db::open(&path, Options::default()).unwrap();
+
+        let a = new_handler_db.get("a").unwrap().unwrap();
+        let b = new_handler_db.get("b").unwrap().unwrap();
+        let c = new_handler_db.get("c").unwrap().unwrap();
+
+        assert_eq!(a, "value-a2".as_bytes());
+        assert_eq!(b, "value-b".as_bytes());
+        assert_eq!(c, "value-c".as_bytes());
+        new_handler_db.close().unwrap();
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_conditional_writes() {
+        let path = PathBuf::from("./DB_test_conditional_writes");
+        let handler_db = Db::create(&path, Options::default()).unwrap();
+
+        assert!(handler_db.put_if_absent("lease", "owner-1").unwrap());
+        assert!(!handler_db.put_if_absent("lease", "owner-2").unwrap());
+        assert_eq!(handler_db.get("lease").unwrap().unwrap(), b"owner-1");
+
+        assert!(!handler_db.compare_and_swap("counter", Some("1".as_bytes()), "2").unwrap());
+        assert!(handler_db.compare_and_swap("counter", None, "1").unwrap());
+        assert!(handler_db.compare_and_swap("counter", Some("1".as_bytes()), "2").unwrap());
+        assert_eq!(handler_db.get("counter").unwrap().unwrap(), b"2");
+
+        // push both keys out of the mem_table, conditions are checked against disk.
+        for i in 0..10 {
+            handler_db.put(format!("filler-{}", i), "some filler value").unwrap();
+        }
+        assert!(handler_db.read_default_cf(|cf| cf.mem_table.get(b"lease").is_none()));
+        assert!(!handler_db.put_if_absent("lease", "owner-2").unwrap());
+        assert!(!handler_db.delete_if_equals("lease", "owner-2").unwrap());
+        assert!(handler_db.delete_if_equals("lease", "owner-1").unwrap());
+        assert!(handler_db.get("lease").unwrap().is_none());
+        assert!(handler_db.compare_and_swap("counter", Some("2".as_bytes()), "3").unwrap());
+        assert_eq!(handler_db.get("counter").unwrap().unwrap(), b"3");
+
+        handler_db.close().unwrap();
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    struct CounterAdd;
+
+    impl MergeOperator for CounterAdd {
+        fn name(&self) -> &str {
+            "counter_add"
+        }
+
+        fn full_merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[Vec<u8>]) -> Vec<u8> {
+            let parse = |value: &[u8]| String::from_utf8(value.to_vec()).unwrap().parse::<i64>().unwrap();
+            let total = existing.map_or(0, parse)
+                + operands.iter().map(|operand| parse(operand)).sum::<i64>();
+            total.to_string().into_bytes()
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let path = PathBuf::from("./DB_test_merge");
+        let handler_db = Db::create(&path, Options::default()).unwrap();
+        assert!(handler_db.merge("counter", "1").is_err());
+        handler_db.set_merge_operator(Arc::new(CounterAdd));
+
+        handler_db.merge("counter", "1").unwrap();
+        handler_db.merge("counter", "2").unwrap();
+        assert_eq!(handler_db.get("counter").unwrap().unwrap(), b"3");
+        handler_db.put("base", "10").unwrap();
+        handler_db.merge("base", "5").unwrap();
+        assert_eq!(handler_db.range_strings("base", "counter").unwrap(), vec!["15", "3"]);
+
+        // operands written before a restart are replayed from the wal.
+        handler_db.close().unwrap();
+        let handler_db = Db::open(&path, Options::default()).unwrap();
+        handler_db.set_merge_operator(Arc::new(CounterAdd));
+        assert_eq!(handler_db.get("counter").unwrap().unwrap(), b"3");
+
+        // flushing collapses the operands, later ones merge onto the value on disk.
+        for i in 0..10 {
+            handler_db.put(format!("filler-{}", i), "some filler value").unwrap();
+        }
+        assert!(handler_db.read_default_cf(|cf| cf.mem_table.get(b"counter").is_none()));
+        handler_db.merge("counter", "4").unwrap();
+        assert_eq!(handler_db.get("counter").unwrap().unwrap(), b"7");
+        assert_eq!(handler_db.range_strings("counter", "counter").unwrap(), vec!["7"]);
+        handler_db.delete("counter").unwrap();
+        handler_db.merge("counter", "1").unwrap();
+        assert_eq!(handler_db.get("counter").unwrap().unwrap(), b"1");
+        handler_db.close().unwrap();
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_flushed_wal_left_behind() {
+        let path = PathBuf::from("./DB_test_flushed_wal_left_behind");
+        let options = Options {
+            merge_operator: Some(Arc::new(CounterAdd)),
+            flush_on_close: true,
+            ..Options::default()
+        };
+        let handler_db = Db::create(&path, options.clone()).unwrap();
+        handler_db.merge("counter", "1").unwrap();
+        let wal_files: Vec<_> = fs::read_dir(path.join("WAL")).unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                (path.clone(), fs::read(path).unwrap())
+            })
+            .collect();
+        handler_db.close().unwrap();
+
+        // as if the flush crashed before deleting the wal it flushed.
+        for (wal_path, content) in wal_files {
+            fs::write(wal_path, content).unwrap();
+        }
+        let handler_db = Db::open(&path, options.clone()).unwrap();
+        assert_eq!(handler_db.get("counter").unwrap().unwrap(), b"1");
+        handler_db.merge("counter", "2").unwrap();
+        handler_db.close().unwrap();
+        let handler_db = Db::open(&path, options).unwrap();
+        assert_eq!(handler_db.get("counter").unwrap().unwrap(), b"3");
+
+        handler_db.close().unwrap();
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_put_with_ttl() {
+        let path = PathBuf::from("./DB_test_put_with_ttl");
+        let handler_db = Db::create(&path, Options::default()).unwrap();
+
+        handler_db.put_with_ttl("session", "user-1", Duration::from_millis(20)).unwrap();
+        handler_db.put_with_ttl("long-session", "user-2", Duration::from_secs(3600)).unwrap();
+        assert_eq!(handler_db.get("session").unwrap().unwrap(), b"user-1");
+        thread::sleep(Duration::from_millis(30));
+        assert!(handler_db.get("session").unwrap().is_none());
+        assert_eq!(handler_db.range_strings("a", "z").unwrap(), vec!["user-2"]);
+        assert!(handler_db.put_if_absent("session", "user-3").unwrap());
+        handler_db.put_with_ttl("session", "user-4", Duration::from_millis(20)).unwrap();
+
+        // expiry times survive a restart and a flush.
+        handler_db.close().unwrap();
+        let handler_db = Db::open(&path, Options::default()).unwrap();
+        assert_eq!(handler_db.get("session").unwrap().unwrap(), b"user-4");
+        for i in 0..10 {
+            handler_db.put(format!("filler-{}", i), "some filler value").unwrap();
+        }
+        assert!(handler_db.read_default_cf(|cf| cf.mem_table.get(b"session").is_none()));
+        assert_eq!(handler_db.get("long-session").unwrap().unwrap(), b"user-2");
+        thread::sleep(Duration::from_millis(30));
+        assert!(handler_db.get("session").unwrap().is_none());
+
+        handler_db.compact().unwrap();
+        assert!(handler_db.read_default_cf(|cf| cf.disk_service.get(b"session").unwrap().is_none()));
+        assert_eq!(handler_db.get("long-session").unwrap().unwrap(), b"user-2");
+        handler_db.close().unwrap();
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_merge_with_ttl() {
+        let path = PathBuf::from("./DB_test_merge_with_ttl");
+        let options = Options {
+            merge_operator: Some(Arc::new(CounterAdd)),
+            flush_on_close: true,
+            ..Options::default()
+        };
+        let handler_db = Db::create(&path, options.clone()).unwrap();
+        handler_db.put_with_ttl("expired", "5", Duration::from_millis(20)).unwrap();
+        handler_db.put_with_ttl("expiring", "5", Duration::from_millis(300)).unwrap();
+        thread::sleep(Duration::from_millis(30));
+        // an expired value is absent to the operands merged onto it.
+        handler_db.merge("expired", "1").unwrap();
+        handler_db.merge("expiring", "1").unwrap();
+        assert_eq!(handler_db.get("expired").unwrap().unwrap(), b"1");
+        assert_eq!(handler_db.get("expiring").unwrap().unwrap(), b"6");
+
+        // and stays so once flushed, while a merged value expires with its base.
+        handler_db.close().unwrap();
+        let handler_db = Db::open(&path, options).unwrap();
+        assert_eq!(handler_db.get("expired").unwrap().unwrap(), b"1");
+        assert_eq!(handler_db.get("expiring").unwrap().unwrap(), b"6");
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(handler_db.get("expired").unwrap().unwrap(), b"1");
+        assert!(handler_db.get("expiring").unwrap().is_none());
+
+        handler_db.close().unwrap();
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_delete_range() {
+        let path = PathBuf::from("./DB_test_delete_range");
+        let handler_db = Db::create(&path, Options::default()).unwrap();
+
+        for key in ["k1", "k2", "k3", "k4"].iter() {
+            handler_db.put(key, format!("{}-old", key)).unwrap();
+        }
+        // push the keys to disk, so the tombstone has to shadow a table.
+        for i in 0..10 {
+            handler_db.put(format!("filler-{}", i), "some filler value").unwrap();
+        }
+        handler_db.put("k2", "k2-mem").unwrap();
+        handler_db.delete_range("k2", "k4").unwrap();
+        handler_db.put("k3", "k3-new").unwrap();
+        assert!(handler_db.delete_range("k4", "k2").is_err());
+
+        assert_eq!(handler_db.get("k1").unwrap().unwrap(), b"k1-old");
+        assert!(handler_db.get("k2").unwrap().is_none());
+        assert_eq!(handler_db.get("k3").unwrap().unwrap(), b"k3-new");
+        assert_eq!(handler_db.get("k4").unwrap().unwrap(), b"k4-old");
+        assert_eq!(handler_db.range_strings("k1", "k4").unwrap(), vec!["k1-old", "k3-new", "k4-old"]);
+
+        // the tombstone survives a restart and a flush.
+        handler_db.close().unwrap();
+        let handler_db = Db::open(&path, Options::default()).unwrap();
+        assert!(handler_db.get("k2").unwrap().is_none());
+        for i in 0..10 {
+            handler_db.put(format!("filler-{}", i), "some other filler value").unwrap();
+        }
+        assert!(handler_db.read_default_cf(|cf| cf.mem_table.is_empty()));
+        assert!(handler_db.get("k2").unwrap().is_none());
+        assert_eq!(handler_db.range_strings("k1", "k4").unwrap(), vec!["k1-old", "k3-new", "k4-old"]);
+
+        handler_db.compact().unwrap();
+        assert!(handler_db.read_default_cf(|cf| cf.disk_service.get(b"k2").unwrap().is_none()));
+        assert_eq!(handler_db.get("k3").unwrap().unwrap(), b"k3-new");
+        assert_eq!(handler_db.range_strings("k1", "k4").unwrap(), vec!["k1-old", "k3-new", "k4-old"]);
+        handler_db.close().unwrap();
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_multi_get() {
+        let path = PathBuf::from("./DB_test_multi_get");
+        let handler_db = Db::create(&path, Options::default()).unwrap();
+        handler_db.set_merge_operator(Arc::new(CounterAdd));
+
+        handler_db.put("k1", "v1").unwrap();
+        handler_db.put("k2", "v2").unwrap();
+        handler_db.merge("counter", "2").unwrap();
+        for i in 0..10 {
+            handler_db.put(format!("filler-{}", i), "some filler value").unwrap();
+        }
+        handler_db.put("k3", "v3").unwrap();
+        handler_db.delete("k1").unwrap();
+        handler_db.merge("counter", "3").unwrap();
+
+        let keys = ["k3", "missing", "k2", "k1", "counter", "k2", "filler-4"];
+        let values = handler_db.multi_get(&keys).unwrap();
+        let expected: Vec<Option<Vec<u8>>> = keys.iter().map(|key| handler_db.get(key).unwrap()).collect();
+        assert_eq!(values, expected);
+        assert_eq!(values[0].as_deref(), Some("v3".as_bytes()));
+        assert_eq!(values[1], None);
+        assert_eq!(values[3], None);
+        assert_eq!(values[4].as_deref(), Some("5".as_bytes()));
+        assert_eq!(values[5].as_deref(), Some("v2".as_bytes()));
+        handler_db.close().unwrap();
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_column_families() {
+        let path = PathBuf::from("./DB_test_column_families");
+        let handler_db = Db::create(&path, Options::default()).unwrap();
+        let users = handler_db.create_column_family("users", ColumnFamilyOptions::default()).unwrap();
+        let counters = handler_db.create_column_family("counters", ColumnFamilyOptions {
+            merge_operator: Some(Arc::new(CounterAdd)),
+            ..ColumnFamilyOptions::default()
+        }).unwrap();
+        assert!(handler_db.create_column_family("users", ColumnFamilyOptions::default()).is_err());
+
+        handler_db.put("a", "default-a").unwrap();
+        handler_db.put_cf(&users, "a", "users-a").unwrap();
+        assert!(handler_db.merge("a", "1").is_err());
+        handler_db.merge_cf(&counters, "a", "1").unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.put(b"b", b"default-b");
+        batch.put_cf(&users, b"b", b"users-b");
+        batch.merge_cf(&counters, b"a", b"2");
+        handler_db.write(batch).unwrap();
+
+        assert_eq!(handler_db.get("a").unwrap().unwrap(), b"default-a");
+        assert_eq!(handler_db.get_cf(&users, "a").unwrap().unwrap(), b"users-a");
+        assert_eq!(handler_db.get_cf(&counters, "a").unwrap().unwrap(), b"3");
+        assert_eq!(handler_db.range_cf(&users, "a", "z").unwrap(), vec![b"users-a", b"users-b"]);
+        handler_db.close().unwrap();
+
+        let handler_db = Db::open(&path, Options::default()).unwrap();
+        assert_eq!(handler_db.list_column_families(), vec!["default", "users", "counters"]);
+        let users = handler_db.column_family("users").unwrap();
+        let counters = handler_db.column_family("counters").unwrap();
+        handler_db.set_column_family_options(&counters, ColumnFamilyOptions {
+            merge_operator: Some(Arc::new(CounterAdd)),
+            ..ColumnFamilyOptions::default()
+        }).unwrap();
+        assert_eq!(handler_db.get_cf(&users, "b").unwrap().unwrap(), b"users-b");
+        assert_eq!(handler_db.get_cf(&counters, "a").unwrap().unwrap(), b"3");
+
+        // a flush triggered by one family writes all of them out.
+        for i in 0..10 {
+            handler_db.put(format!("filler-{}", i), "some filler value").unwrap();
+        }
+        assert!(handler_db.read_cf(&users, |cf| cf.mem_table.is_empty()).unwrap());
+        assert_eq!(handler_db.get_cf(&users, "a").unwrap().unwrap(), b"users-a");
+        assert_eq!(handler_db.get_cf(&counters, "a").unwrap().unwrap(), b"3");
+
+        handler_db.put_cf(&users, "c", "users-c").unwrap();
+        handler_db.drop_column_family(&users).unwrap();
+        assert!(matches!(handler_db.get_cf(&users, "a").unwrap_err(), Error::NotFound(_)));
+        assert!(handler_db.put_cf(&users, "a", "users-a2").is_err());
+        handler_db.close().unwrap();
+
+        let handler_db = Db::open(&path, Options::default()).unwrap();
+        assert_eq!(handler_db.list_column_families(), vec!["default", "counters"]);
+        let users = handler_db.create_column_family("users", ColumnFamilyOptions::default()).unwrap();
+        assert!(handler_db.get_cf(&users, "c").unwrap().is_none());
+        assert_eq!(handler_db.get("b").unwrap().unwrap(), b"default-b");
+        handler_db.close().unwrap();
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_shared_between_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Db>();
+
+        let path = PathBuf::from("./DB_test_shared_between_threads");
+        let handler_db = Arc::new(Db::create(&path, Options {
+            write_buffer_size: 256,
+            ..Options::default()
+        }).unwrap());
+
+        // enough writes to seal mem_tables while the readers run against them.
+        let writers: Vec<_> = (0..4).map(|writer| {
+            let handler_db = handler_db.clone();
+            thread::spawn(move || {
+                for i in 0..100 {
+                    handler_db.put(format!("{}-{:03}", writer, i), format!("value-{}", i)).unwrap();
+                }
+            })
+        }).collect();
+        let readers: Vec<_> = (0..2).map(|_| {
+            let handler_db = handler_db.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    // a writer's keys show up in order, so what's visible is a prefix.
+                    let values = handler_db.range("0-000", "0-099").unwrap();
+                    let expected: Vec<Vec<u8>> = (0..values.len())
+                        .map(|i| format!("value-{}", i).into_bytes())
+                        .collect();
+                    assert_eq!(values, expected);
+                }
+            })
+        }).collect();
+        for handle in writers.into_iter().chain(readers) {
+            handle.join().unwrap();
+        }
+
+        for writer in 0..4 {
+            let values = handler_db.range(format!("{}-000", writer), format!("{}-099", writer)).unwrap();
+            assert_eq!(values.len(), 100);
+        }
+        let handler_db = Arc::try_unwrap(handler_db).ok().unwrap();
+        handler_db.close().unwrap();
+        let handler_db = Db::open(&path, Options::default()).unwrap();
+        assert_eq!(handler_db.get("3-099").unwrap().unwrap(), b"value-99");
+        handler_db.close().unwrap();
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_group_commit() {
+        let path = PathBuf::from("./DB_test_group_commit");
+        let handler_db = Arc::new(Db::create(&path, Options::default()).unwrap());
+
+        // hold the write path, so every writer piles up behind a single leader.
+        let writer = handler_db.core.writer.lock().unwrap();
+        let writers: Vec<_> = (0..8).map(|i| {
+            let handler_db = handler_db.clone();
+            thread::spawn(move || {
+                if i == 3 {
+                    handler_db.merge("counter", "1")
+                } else {
+                    handler_db.write_opt(batch_of(i), &WriteOptions { sync: i % 2 == 0 })
+                }
+            })
+        }).collect();
+        loop {
+            let queue = handler_db.core.write_queue.lock().unwrap();
+            if queue.waiting.len() == 8 {
+                assert!(queue.leading);
+                break;
+            }
+            drop(queue);
+            thread::sleep(Duration::from_millis(1));
+        }
+        drop(writer);
+
+        // the invalid write fails on its own, the rest of the group goes through.
+        let results: Vec<_> = writers.into_iter().map(|handle| handle.join().unwrap()).collect();
+        for (i, result) in results.into_iter().enumerate() {
+            if i == 3 {
+                assert!(matches!(result, Err(Error::InvalidArgument(_))));
+            } else {
+                result.unwrap();
+            }
+        }
+        let queue = handler_db.core.write_queue.lock().unwrap();
+        assert!(queue.waiting.is_empty() && queue.finished.is_empty() && !queue.leading);
+        drop(queue);
+
+        let handler_db = Arc::try_unwrap(handler_db).ok().unwrap();
+        handler_db.close().unwrap();
+        let handler_db = Db::open(&path, Options::default()).unwrap();
+        for i in (0..8).filter(|i| *i != 3) {
+            assert_eq!(handler_db.get(format!("key-{}", i)).unwrap().unwrap(), b"value");
+            assert_eq!(handler_db.get(format!("other-{}", i)).unwrap().unwrap(), b"value");
+        }
+        assert!(handler_db.get("counter").unwrap().is_none());
+        handler_db.close().unwrap();
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_pipelined_write() {
+        let path = PathBuf::from("./DB_test_pipelined_write");
+        let handler_db = Arc::new(Db::create(&path, Options::default()).unwrap());
+
+        // with the mem_table stage held up, a second group still gets into the wal.
+        let pipeline = handler_db.core.pipeline.lock().unwrap();
+        let writers: Vec<_> = (0..2).map(|i| {
+            let shared_db = handler_db.clone();
+            let handle = thread::spawn(move || shared_db.put(format!("key-{}", i), "value"));
+            while handler_db.core.writer.lock().unwrap().groups <= i {
+                thread::sleep(Duration::from_millis(1));
+            }
+            handle
+        }).collect();
+        assert_eq!(pipeline.applied, 0);
+        assert!(handler_db.get("key-0").unwrap().is_none());
+        drop(pipeline);
+        for handle in writers {
+            handle.join().unwrap().unwrap();
+        }
+        assert_eq!(handler_db.multi_get(&["key-0", "key-1"]).unwrap(), vec![Some(b"value".to_vec()); 2]);
+
+        // a reader seeing a write also sees every write that finished before it began.
+        let done = Arc::new(AtomicBool::new(false));
+        let noise: Vec<_> = (0..3).map(|writer| {
+            let (handler_db, done) = (handler_db.clone(), done.clone());
+            thread::spawn(move || {
+                let mut i = 0;
+                while !done.load(Ordering::Relaxed) {
+                    handler_db.put(format!("noise-{}-{}", writer, i % 50), "some noise").unwrap();
+                    i += 1;
+                }
+            })
+        }).collect();
+        let reader = {
+            let (handler_db, done) = (handler_db.clone(), done.clone());
+            thread::spawn(move || {
+                let read = |key: &str| handler_db.get(key).unwrap()
+                    .map_or(0, |value| String::from_utf8(value).unwrap().parse::<u32>().unwrap());
+                while !done.load(Ordering::Relaxed) {
+                    let second = read("second");
+                    assert!(read("first") >= second);
+                }
+            })
+        };
+        for i in 1..=300u32 {
+            handler_db.put("first", i.to_string()).unwrap();
+            handler_db.put("second", i.to_string()).unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        for handle in noise.into_iter().chain(Some(reader)) {
+            handle.join().unwrap();
+        }
+
+        let handler_db = Arc::try_unwrap(handler_db).ok().unwrap();
+        handler_db.close().unwrap();
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_seal_failure() {
+        for pipelined_write in [false, true].iter() {
+            let path = PathBuf::from(format!("./DB_test_seal_failure_{}", pipelined_write));
+            let handler_db = Db::create(&path, Options {
+                write_buffer_size: 64,
+                pipelined_write: *pipelined_write,
+                ..Options::default()
+            }).unwrap();
+            // the write is logged to the open wal file, but no new one can be rolled to.
+            fs::remove_dir_all(path.join("WAL")).unwrap();
+            handler_db.put("a", [b'x'; 64]).unwrap();
+            assert_eq!(handler_db.get("a").unwrap().unwrap(), [b'x'; 64]);
+            assert!(matches!(handler_db.put("b", "value-b"), Err(Error::Io(_))));
+
+            drop(handler_db);
+            fs::remove_dir_all(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_write_stall() {
+        let path = PathBuf::from("./DB_test_write_stall");
+        let handler_db = Arc::new(Db::create(&path, Options {
+            write_buffer_size: 64,
+            disk_file_compaction_trigger: 0,
+            slowdown_writes_immutable_mem_tables: 0,
+            stop_writes_immutable_mem_tables: 1,
+            stop_writes_disk_files: 3,
+            ..Options::default()
+        }).unwrap());
+        let value = [b'x'; 64];
+
+        // with flushes held up, the sealed mem_table stops the next write.
+        let disk_work = handler_db.core.disk_work.lock().unwrap();
+        handler_db.put("a", value).unwrap();
+        assert_eq!(handler_db.stats().write_stall, WriteStallCondition::Stopped(WriteStallCause::ImmutableMemTables));
+        let writer = {
+            let handler_db = handler_db.clone();
+            thread::spawn(move || handler_db.put("b", value))
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!writer.is_finished());
+        drop(disk_work);
+        writer.join().unwrap().unwrap();
+        assert_eq!(handler_db.stats().stopped_writes, 1);
+
+        // too many disk files stop writes until the background thread compacts them.
+        for i in 0..10 {
+            handler_db.put(format!("key-{}", i), value).unwrap();
+            assert!(handler_db.stats().disk_files <= 3);
+        }
+        assert!(handler_db.stats().stopped_writes > 1);
+        assert_eq!(handler_db.get("a").unwrap().unwrap(), value);
+        assert_eq!(handler_db.get("key-9").unwrap().unwrap(), value);
+
+        let handler_db = Arc::try_unwrap(handler_db).ok().unwrap();
+        handler_db.close().unwrap();
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_history() {
+        let path = PathBuf::from("./DB_test_history");
+        let options = Options {
+            merge_operator: Some(Arc::new(CounterAdd)),
+            history_retention: Some(Duration::from_secs(3600)),
+            ..Options::default()
+        };
+        let handler_db = Db::create(&path, options.clone()).unwrap();
+        handler_db.put("counter", "1").unwrap();
+        handler_db.put("counter", "2").unwrap();
+        handler_db.delete("counter").unwrap();
+        handler_db.merge("counter", "5").unwrap();
+        handler_db.merge("counter", "1").unwrap();
+        handler_db.put("other", "value").unwrap();
+
+        let values = |handler_db: &Db| -> Vec<Option<Vec<u8>>> {
+            handler_db.history("counter").unwrap().into_iter().map(|version| version.value).collect()
+        };
+        let expected = vec![Some(b"6".to_vec()), Some(b"5".to_vec()), None, Some(b"2".to_vec()), Some(b"1".to_vec())];
+        assert_eq!(values(&handler_db), expected);
+        let history = handler_db.history("counter").unwrap();
+        for version in history.iter() {
+            assert_eq!(handler_db.get_at("counter", version.timestamp).unwrap(), version.value);
+        }
+        let first = history.last().unwrap().timestamp;
+        assert_eq!(handler_db.get_at("counter", first - 1).unwrap(), None);
+        assert_eq!(handler_db.get("counter").unwrap().unwrap(), b"6");
+        assert_eq!(handler_db.history("missing").unwrap(), vec![]);
+
+        // versions survive flushes, compactions and reopening within the retention.
+        handler_db.compact().unwrap();
+        assert_eq!(handler_db.history("counter").unwrap(), history);
+        handler_db.close().unwrap();
+        let handler_db = Db::open(&path, options.clone()).unwrap();
+        assert_eq!(handler_db.history("counter").unwrap(), history);
+        assert_eq!(handler_db.get_at("counter", history[3].timestamp).unwrap().unwrap(), b"2");
+        handler_db.close().unwrap();
+
+        // without a retention, compaction keeps the latest version only.
+        let handler_db = Db::open(&path, Options {
+            history_retention: None,
+            ..options
+        }).unwrap();
+        handler_db.compact().unwrap();
+        assert_eq!(values(&handler_db), vec![Some(b"6".to_vec())]);
+        assert_eq!(handler_db.get_at("counter", first).unwrap(), None);
+
+        handler_db.close().unwrap();
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_write_slowdown() {
+        let path = PathBuf::from("./DB_test_write_slowdown");
+        let handler_db = Db::create(&path, Options {
+            write_buffer_size: 64,
+            disk_file_compaction_trigger: 0,
+            slowdown_writes_disk_files: 1,
+            ..Options::default()
+        }).unwrap();
+
+        handler_db.put("a", [b'x'; 64]).unwrap();
+        handler_db.compact().unwrap();
+        let stats = handler_db.stats();
+        assert_eq!(stats.disk_files, 1);
+        assert_eq!(stats.write_stall, WriteStallCondition::Delayed(WriteStallCause::DiskFiles));
+        assert_eq!(stats.delayed_writes, 0);
+        handler_db.put("b", "value-b").unwrap();
+        handler_db.delete("b").unwrap();
+        let stats = handler_db.stats();
+        assert_eq!(stats.delayed_writes, 2);
+        assert!(stats.stall_micros >= 2000);
+        // preparing a transaction writes to the WAL like any other write.
+        let mut txn = handler_db.begin_pessimistic_transaction(TransactionOptions::default());
+        txn.put("c", "value-c").unwrap();
+        txn.prepare(&handler_db, "xid").unwrap();
+        assert_eq!(handler_db.stats().delayed_writes, 3);
+
+        handler_db.close().unwrap();
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_open_read_only() {
+        let path = PathBuf::from("./DB_test_open_read_only");
+        assert!(matches!(Db::open_read_only(&path, Options::default()).err().unwrap(), Error::NotFound(_)));
+        assert!(!path.exists());
+
+        let handler_db = Db::create(&path, Options {
+            write_buffer_size: 64,
+            ..Options::default()
+        }).unwrap();
+        let users = handler_db.create_column_family("users", ColumnFamilyOptions::default()).unwrap();
+        handler_db.put("a", "value-a").unwrap();
+        for i in 0..10 {
+            handler_db.put(format!("filler-{}", i), "some filler value").unwrap();
+        }
+        handler_db.put("b", "value-b").unwrap();
+        handler_db.put_cf(&users, "u", "value-u").unwrap();
+        handler_db.begin_pessimistic_transaction(TransactionOptions::default()).prepare(&handler_db, "xid").unwrap();
+        let files = |dir: &str| {
+            let mut files: Vec<_> = fs::read_dir(path.join(dir)).unwrap()
+                .map(|entry| {
+                    let entry = entry.unwrap();
+                    (entry.file_name(), entry.metadata().unwrap().len())
+                })
+                .collect();
+            files.sort();
+            files
+        };
+        handler_db.compact().unwrap();
+        let (wal_files, disk_files) = (files("WAL"), files("DISK_FILE"));
+        assert!(!disk_files.is_empty());
+
+        // the db is open and locked, yet it can be read, from disk files and wal alike.
+        let read_only = Db::open_read_only(&path, Options::default()).unwrap();
+        assert_eq!(read_only.get("a").unwrap().unwrap(), b"value-a");
+        assert_eq!(read_only.get("b").unwrap().unwrap(), b"value-b");
+        let users = read_only.column_family("users").unwrap();
+        assert_eq!(read_only.get_cf(&users, "u").unwrap().unwrap(), b"value-u");
+        assert_eq!(read_only.prepared_transactions(), vec!["xid"]);
+
+        let is_not_supported = |result: Result<()>| matches!(result, Err(Error::NotSupported(_)));
+        assert!(is_not_supported(read_only.put("c", "value-c")));
+        assert!(is_not_supported(read_only.delete("a")));
+        assert!(is_not_supported(read_only.delete_range("a", "z")));
+        assert!(is_not_supported(read_only.write(WriteBatch::new()).and(read_only.put_cf(&users, "c", "value-c"))));
+        assert!(is_not_supported(read_only.compact()));
+        assert!(is_not_supported(read_only.commit_prepared("xid")));
+        assert!(is_not_supported(read_only.drop_column_family(&users)));
+        assert!(matches!(read_only.put_if_absent("c", "value-c"), Err(Error::NotSupported(_))));
+        assert!(matches!(read_only.create_column_family("more", ColumnFamilyOptions::default()),
+                         Err(Error::NotSupported(_))));
+        let mut txn = read_only.begin_transaction();
+        txn.put("c", "value-c");
+        assert!(is_not_supported(txn.commit(&read_only)));
+
+        // later writes of the primary aren't seen, and nothing on disk changed.
+        handler_db.put("a", "value-a2").unwrap();
+        assert_eq!(read_only.get("a").unwrap().unwrap(), b"value-a");
+        read_only.close().unwrap();
+        handler_db.close().unwrap();
+        assert_eq!(files("DISK_FILE"), disk_files);
+        let wal_names: Vec<_> = wal_files.iter().map(|(name, _)| name).collect();
+        assert_eq!(files("WAL").iter().map(|(name, _)| name).collect::<Vec<_>>(), wal_names);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_secondary() {
+        let path = PathBuf::from("./DB_test_secondary");
+        let handler_db = Db::create(&path, Options {
+            write_buffer_size: 256,
+            ..Options::default()
+        }).unwrap();
+        handler_db.put("a", "value-a").unwrap();
+
+        // the primary holds the lock all along.
+        let secondary = Db::open_as_secondary(&path, Options::default()).unwrap();
+        assert_eq!(secondary.get("a").unwrap().unwrap(), b"value-a");
+        assert!(matches!(secondary.put("b", "value-b"), Err(Error::NotSupported(_))));
+        assert!(matches!(handler_db.try_catch_up_with_primary(), Err(Error::NotSupported(_))));
+
+        // writes appended to the active wal.
+        handler_db.put("b", "value-b").unwrap();
+        handler_db.delete("a").unwrap();
+        assert!(secondary.get("b").unwrap().is_none());
+        secondary.try_catch_up_with_primary().unwrap();
+        assert_eq!(secondary.get("b").unwrap().unwrap(), b"value-b");
+        assert!(secondary.get("a").unwrap().is_none());
+
+        // flushed and compacted disk files, a rolled wal and a new column family.
+        let users = handler_db.create_column_family("users", ColumnFamilyOptions::default()).unwrap();
+        handler_db.put_cf(&users, "u", "value-u").unwrap();
+        for i in 0..10 {
+            handler_db.put(format!("filler-{}", i), "some filler value").unwrap();
+        }
+        handler_db.compact().unwrap();
+        handler_db.put("c", "value-c").unwrap();
+        secondary.try_catch_up_with_primary().unwrap();
+        for i in 0..10 {
+            assert!(secondary.get(format!("filler-{}", i)).unwrap().is_some());
+        }
+        assert_eq!(secondary.get("b").unwrap().unwrap(), b"value-b");
+        assert_eq!(secondary.get("c").unwrap().unwrap(), b"value-c");
+        let users = secondary.column_family("users").unwrap();
+        assert_eq!(secondary.get_cf(&users, "u").unwrap().unwrap(), b"value-u");
+
+        // compacting again replaces the disk files the secondary read.
+        handler_db.put("b", "value-b2").unwrap();
+        handler_db.compact().unwrap();
+        secondary.try_catch_up_with_primary().unwrap();
+        assert_eq!(secondary.get("b").unwrap().unwrap(), b"value-b2");
+        assert_eq!(secondary.iter(&ReadOptions::default()).unwrap().count(),
+                   handler_db.iter(&ReadOptions::default()).unwrap().count());
+
+        secondary.close().unwrap();
+        handler_db.close().unwrap();
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_comparator() {
+        let path = PathBuf::from("./DB_test_comparator");
+        let options = Options {
+            comparator: Arc::new(ReverseBytewiseComparator),
+            write_buffer_size: 128,
+            ..Options::default()
+        };
+        let handler_db = Db::create(&path, options.clone()).unwrap();
+        for key in ["a", "b", "c", "d", "e"].iter() {
+            handler_db.put(key, format!("value-{}", key)).unwrap();
+        }
+        // ranges run from the greater key to the lesser one.
+        handler_db.delete_range("d", "b").unwrap();
+        assert!(matches!(handler_db.delete_range("b", "d"), Err(Error::InvalidArgument(_))));
+        handler_db.compact().unwrap();
+        handler_db.put("f", "value-f").unwrap();
+
+        let keys: Vec<Vec<u8>> = handler_db.iter(&ReadOptions::default()).unwrap().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec![b"f", b"e", b"b", b"a"]);
+        let read_options = ReadOptions {
+            iterate_lower_bound: Some(b"e".to_vec()),
+            iterate_upper_bound: Some(b"a".to_vec()),
+        };
+        let keys: Vec<Vec<u8>> = handler_db.iter(&read_options).unwrap().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec![b"e", b"b"]);
+        assert_eq!(handler_db.range_strings("f", "b").unwrap(), vec!["value-f", "value-e", "value-b"]);
+        handler_db.close().unwrap();
+
+        let handler_db = Db::open(&path, options).unwrap();
+        assert_eq!(handler_db.multi_get_strings(&["a", "c", "f"]).unwrap(),
+                   vec![Some("value-a".to_string()), None, Some("value-f".to_string())]);
+        handler_db.close().unwrap();
+
+        match Db::open(&path, Options::default()) {
+            Err(err @ Error::InvalidArgument(_)) => {
+                assert!(err.to_string().contains("pipengine.ReverseBytewiseComparator"));
+            }
+            _ => panic!("opened with another comparator"),
+        }
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    fn batch_of(i: usize) -> WriteBatch {
+        let mut batch = WriteBatch::new();
+        batch.put(format!("key-{}", i).as_bytes(), b"value");
+        batch.put(format!("other-{}", i).as_bytes(), b"value");
+        batch
+    }
+}
@@ -1,241 +1,919 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use crate::comparator::{self, Comparator, OrderedKey};
+use crate::error::{Error, Result};
 use crate::mem_table::{MemTableEntry, MemTable};
 use crate::util;
-use std::io;
 use std::collections::BTreeMap;
+use std::ops::Bound;
 use std::fs::{self, File, OpenOptions};
-use std::io::{BufReader, Read, BufWriter, Write, Seek, SeekFrom};
-use std::time::{SystemTime, UNIX_EPOCH};
-use std::convert::TryFrom;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write, Seek, SeekFrom};
+use std::sync::Arc;
 
-const MAX_KEY_SIZE: usize = 128;
+// values of the tombstone byte of an entry.
+const ENTRY_PUT: u8 = 0;
+const ENTRY_DELETE: u8 = 1;
+// a put followed by its expiry time.
+const ENTRY_PUT_WITH_EXPIRY: u8 = 2;
+// laid out like a put, the key being the start and the value the end of the deleted range.
+const ENTRY_DELETE_RANGE: u8 = 3;
 
+#[derive(Clone)]
 pub struct DiskService {
     dir: PathBuf,
     files: Vec<FileService>,
+    // the order of the keys in every file.
+    comparator: Arc<dyn Comparator>,
 }
 
 //TODO range get compression
 impl DiskService {
-    pub fn new(dir: &PathBuf) -> io::Result<DiskService> {
-        fs::create_dir(dir);
+    pub fn new(dir: &Path, comparator: Arc<dyn Comparator>) -> Result<DiskService> {
+        fs::create_dir_all(dir)?;
         Ok(DiskService {
             dir: dir.to_owned(),
             files: vec![],
+            comparator,
         })
     }
 
+    /// Open the disk files in `dir` as the one writing to it, removing the file a
+    /// flush interrupted by a crash left half written.
+    pub fn recover(dir: &Path, comparator: Arc<dyn Comparator>) -> Result<DiskService> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == "tmp") {
+                fs::remove_file(path)?;
+            }
+        }
+        DiskService::open(dir, comparator)
+    }
+
     // dir:DISK_FILE
-    pub fn open(dir: &PathBuf) -> io::Result<DiskService> {
+    pub fn open(dir: &Path, comparator: Arc<dyn Comparator>) -> Result<DiskService> {
         let mut files = vec![];
-        let entries = fs::read_dir(dir).unwrap();
-        for entry in entries {
-            let path = entry.unwrap().path();
-            if path.extension().unwrap() == "dbf" {
-                let file = OpenOptions::new().read(true).write(true).open(&path).unwrap();
-                let mut reader = BufReader::new(file);
-                //let mut writer = BufWriter::new(&file);
-                let mut key_size = [0; 8];
-                reader.read_exact(&mut key_size).unwrap();
-                let min_key_size = usize::from_le_bytes(key_size);
-                reader.read_exact(&mut key_size).unwrap();
-                let max_key_size = usize::from_le_bytes(key_size);
-                let mut min_key = vec![0; min_key_size];
-                let mut max_key = vec![0; max_key_size];
-                reader.read_exact(&mut min_key).unwrap();
-                reader.read_exact(&mut max_key).unwrap();
-                files.push(FileService {
-                    min_size: min_key_size,
-                    max_size: max_key_size,
-                    min_key,
-                    max_key,
-                    file_path: path,
-                });
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == "dbf") {
+                files.push(FileService::open(path)?);
             }
         }
+        // files are named by their creation time, keep them oldest first.
+        files.sort_by(|a, b| a.file_path.cmp(&b.file_path));
 
         Ok(DiskService {
             dir: dir.to_owned(),
             files,
+            comparator,
         })
     }
 
-    pub fn range(&self, min_key: &[u8], max_key: &[u8]) -> Vec<MemTableEntry> {
-        vec![]
+    /// Latest entry of every key within the bounds on disk, tombstones included.
+    /// A key deleted by a range tombstone comes back as a point tombstone.
+    pub fn scan(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<Vec<MemTableEntry>> {
+        let comparator = &*self.comparator;
+        let mut entries = BTreeMap::new();
+        // oldest file first, so newer entries overwrite older ones.
+        for file in self.files.iter() {
+            if comparator::above_upper(comparator, upper, &file.min_key)
+                || comparator::below_lower(comparator, lower, &file.max_key) {
+                continue;
+            }
+            let mut points: Vec<MemTableEntry> = vec![];
+            for entry in file.iter()? {
+                let entry = entry?;
+                if comparator::above_upper(comparator, upper, &entry.key) {
+                    break;
+                }
+                if entry.is_range_tombstone() {
+                    self.delete_covered(&mut entries, &entry);
+                } else if !comparator::below_lower(comparator, lower, &entry.key)
+                    && !points.last().is_some_and(|newer| comparator.compare(&newer.key, &entry.key).is_eq()) {
+                    // older versions of a key follow its latest entry.
+                    points.push(entry);
+                }
+            }
+            // the point entries of a file are newer than its range tombstones.
+            for entry in points {
+                entries.insert(OrderedKey::new(&entry.key, &self.comparator), entry);
+            }
+        }
+        Ok(entries.into_values().collect())
+    }
+
+    /// Latest entry of `key` on disk, tombstones included.
+    pub fn get(&self, key: &[u8]) -> Result<Option<MemTableEntry>> {
+        let comparator = &*self.comparator;
+        for file in self.files.iter().rev() {
+            if !file.may_hold(comparator, key) {
+                continue;
+            }
+            let mut covered_at = None;
+            for entry in file.iter()? {
+                let entry = entry?;
+                if comparator.compare(&entry.key, key).is_gt() {
+                    break;
+                }
+                if entry.covers(key, comparator) {
+                    covered_at = covered_at.max(Some(entry.timestamp));
+                } else if comparator.compare(&entry.key, key).is_eq() && !entry.is_range_tombstone() {
+                    return Ok(Some(entry));
+                }
+            }
+            if let Some(timestamp) = covered_at {
+                return Ok(Some(deleted_at(key, timestamp)));
+            }
+        }
+        Ok(None)
+    }
+    /// `get` for many keys, which must be sorted and unique, reading each file at most once.
+    pub fn multi_get(&self, keys: &[&[u8]]) -> Result<Vec<Option<MemTableEntry>>> {
+        let comparator = &*self.comparator;
+        let mut results = vec![None; keys.len()];
+        let mut pending: Vec<usize> = (0..keys.len()).collect();
+        for file in self.files.iter().rev() {
+            let (in_file, rest): (Vec<usize>, Vec<usize>) = pending.into_iter()
+                .partition(|&i| file.may_hold(comparator, keys[i]));
+            pending = rest;
+            if in_file.is_empty() {
+                continue;
+            }
+
+            let mut range_tombstones = vec![];
+            let mut found = vec![None; in_file.len()];
+            let mut next = 0;
+            for entry in file.iter()? {
+                let entry = entry?;
+                while next < in_file.len() && comparator.compare(keys[in_file[next]], &entry.key).is_lt() {
+                    next += 1;
+                }
+                if next == in_file.len() {
+                    break;
+                }
+                if entry.is_range_tombstone() {
+                    range_tombstones.push(entry);
+                } else if comparator.compare(&entry.key, keys[in_file[next]]).is_eq() {
+                    found[next] = Some(entry);
+                    next += 1;
+                }
+            }
+            for (i, entry) in in_file.into_iter().zip(found) {
+                let covered_at = range_tombstones.iter()
+                    .filter(|tombstone| tombstone.covers(keys[i], comparator))
+                    .map(|tombstone| tombstone.timestamp)
+                    .max();
+                match (entry, covered_at) {
+                    (Some(entry), _) => results[i] = Some(entry),
+                    (None, Some(timestamp)) => results[i] = Some(deleted_at(keys[i], timestamp)),
+                    (None, None) => pending.push(i),
+                }
+            }
+            pending.sort_unstable();
+            if pending.is_empty() {
+                break;
+            }
+        }
+        Ok(results)
+    }
+
+    /// Merge every file into a single new one. The versions of a key that were replaced,
+    /// or expired, at `horizon` are dropped, as are the entries range tombstones cover, and
+    /// tombstones from before `horizon` with no older version left to shadow. With `horizon`
+    /// set to now, only the latest entry of each key is kept, unless it's gone too.
+    ///
+    /// The replaced files are returned instead of deleted, since readers of
+    /// a copy of this service taken before may still be using them.
+    pub fn compact(&mut self, horizon: u128) -> Result<Vec<PathBuf>> {
+        if self.files.is_empty() || (self.files.len() == 1 && !self.files[0].has_garbage(horizon)?) {
+            return Ok(vec![]);
+        }
+        let ordered = |key: &[u8]| OrderedKey::new(key, &self.comparator);
+        let mut versions: BTreeMap<OrderedKey, Vec<MemTableEntry>> = BTreeMap::new();
+        for file in self.files.iter() {
+            let mut points = vec![];
+            for entry in file.iter()? {
+                let entry = entry?;
+                if entry.is_range_tombstone() {
+                    let end = entry.range_end.as_ref().unwrap();
+                    if self.comparator.compare(&entry.key, end).is_lt() {
+                        for (key, key_versions) in versions.range_mut(ordered(&entry.key)..ordered(end)) {
+                            key_versions.push(deleted_at(&key.bytes, entry.timestamp));
+                        }
+                    }
+                } else {
+                    points.push(entry);
+                }
+            }
+            // the versions of a key are stored newest first.
+            for entry in points.into_iter().rev() {
+                versions.entry(ordered(&entry.key)).or_default().push(entry);
+            }
+        }
+
+        let mut mem_table = MemTable::new(self.comparator.clone()).keep_history(true);
+        for (OrderedKey { bytes: key, .. }, mut key_versions) in versions {
+            // a file's range tombstones may be newer than older versions stored with them.
+            key_versions.sort_by_key(|entry| entry.timestamp);
+            mem_table.set_versions(&key, retained_versions(key_versions, horizon));
+        }
+
+        let old_files = std::mem::take(&mut self.files);
+        if !mem_table.is_empty() {
+            self.write_mem_table_to_disk(&mem_table)?;
+        }
+        Ok(old_files.into_iter().map(|file| file.file_path).collect())
     }
 
-    pub fn get(&self, key: &[u8]) -> Option<&MemTableEntry> {
-        None
+    /// Every version of `key` on disk, oldest first, tombstones included.
+    /// A range tombstone covering the key comes back as a point tombstone.
+    pub fn history(&self, key: &[u8]) -> Result<Vec<MemTableEntry>> {
+        let comparator = &*self.comparator;
+        let mut versions = vec![];
+        for file in self.files.iter() {
+            if !file.may_hold(comparator, key) {
+                continue;
+            }
+            let mut points = vec![];
+            for entry in file.iter()? {
+                let entry = entry?;
+                if comparator.compare(&entry.key, key).is_gt() {
+                    break;
+                }
+                if entry.covers(key, comparator) {
+                    versions.push(deleted_at(key, entry.timestamp));
+                } else if comparator.compare(&entry.key, key).is_eq() && !entry.is_range_tombstone() {
+                    points.push(entry);
+                }
+            }
+            versions.extend(points.into_iter().rev());
+        }
+        versions.sort_by_key(|entry| entry.timestamp);
+        // a range delete also marks the keys it covers in its mem_table deleted.
+        versions.dedup_by(|newer, older| newer.deleted && older.deleted && newer.timestamp == older.timestamp);
+        Ok(versions)
     }
+
     //min_key_size--max_key-size--min_key--max_key
-    //key_size--tombstone--value_size--key--value--timestamp
-    pub fn write_mem_table_to_disk(&mut self, mem_table: &MemTable) -> io::Result<()> {
-        let timestamp = util::get_timestamp();
-        let path = self.dir.join(timestamp.to_string() + ".dbf");
-        let mut new_db_file = OpenOptions::new().write(true).create(true).open(&path).unwrap();
-
-        let mut iter = mem_table.iter();
-        let (min, _) = iter.next().unwrap();
-        let (max, _) = iter.last().unwrap();
+    //key_size--tombstone--value_size--key--value--timestamp[--expires_at]
+    //range tombstones are stored in key order among the entries, keyed by their start.
+    //the older versions of a key, if any, follow its latest entry, newest first.
+    pub fn write_mem_table_to_disk(&mut self, mem_table: &MemTable) -> Result<()> {
+        // files are named by their creation time and ordered by name, so names have
+        // to keep increasing and never clash, even when the clock doesn't.
+        let mut timestamp = util::get_timestamp();
+        if let Some(newest) = self.files.last().and_then(|file| file.file_path.file_stem()?.to_str()?.parse::<u128>().ok()) {
+            timestamp = timestamp.max(newest + 1);
+        }
+        let mut path = self.dir.join(timestamp.to_string() + ".dbf");
+        while path.exists() {
+            timestamp += 1;
+            path = self.dir.join(timestamp.to_string() + ".dbf");
+        }
+        // written under another name and renamed once complete, so a db open
+        // elsewhere never reads a file half written.
+        let tmp_path = path.with_extension("tmp");
+        let comparator = &*self.comparator;
+        let mut new_db_file = OpenOptions::new().write(true).create_new(true).open(&tmp_path)?;
+
+        let mut range_tombstones: Vec<MemTableEntry> = mem_table.range_tombstones().iter()
+            .map(|tombstone| MemTableEntry {
+                key: tombstone.start.to_owned(),
+                value: None,
+                timestamp: tombstone.timestamp,
+                deleted: true,
+                merge_operands: vec![],
+                expires_at: None,
+                range_end: Some(tombstone.end.to_owned()),
+            })
+            .collect();
+        range_tombstones.sort_by(|a, b| comparator.compare(&a.key, &b.key));
+
+        // a range tombstone covers keys up to its end, so the end bounds the file too.
+        // Only reads check the bounds, so the upper one can be any key past them.
+        let min = mem_table.iter().next().map(|(key, _)| key)
+            .into_iter()
+            .chain(range_tombstones.first().map(|tombstone| &tombstone.key))
+            .min_by(|a, b| comparator.compare(a, b))
+            .unwrap()
+            .to_owned();
+        let max = mem_table.iter().next_back().map(|(key, _)| key)
+            .into_iter()
+            .chain(range_tombstones.iter().filter_map(|tombstone| tombstone.range_end.as_ref()))
+            .max_by(|a, b| comparator.compare(a, b))
+            .map(|max| comparator.find_short_successor(max))
+            .unwrap();
         let min_size = min.len();
         let max_size = max.len();
-        new_db_file.write_all(&min_size.to_le_bytes());
-        new_db_file.write_all(&max_size.to_le_bytes());
-        new_db_file.write_all(&min.as_slice())?;
-        new_db_file.write_all(&max.as_slice())?;
-        let mut iter = mem_table.iter();
-        //todo fill min_key and max_key.
-        for (key, entry) in iter {
-            //if iter.last()
-            let key = entry.key.to_owned();
-            let deleted = entry.deleted;
-            let value = entry.value.to_owned().unwrap();
-            let timestamp = entry.timestamp;
-            new_db_file.write_all(&key.len().to_le_bytes())?;
-            new_db_file.write_all(&(deleted as u8).to_le_bytes())?;
-            if deleted {
-                new_db_file.write_all(key.as_slice())?;
-                new_db_file.write_all(&timestamp.to_le_bytes())?;
-            } else {
-                new_db_file.write_all((&value.len().to_le_bytes()))?;
-                new_db_file.write_all(key.as_slice())?;
-                new_db_file.write_all(&value)?;
-                new_db_file.write_all(&timestamp.to_le_bytes())?;
-            }
-        }
-        let mut reader = BufReader::new(new_db_file);
-        reader.seek(SeekFrom::Start((8 + 8 + min_size + max_size) as u64));
+        new_db_file.write_all(&min_size.to_le_bytes())?;
+        new_db_file.write_all(&max_size.to_le_bytes())?;
+        new_db_file.write_all(min.as_slice())?;
+        new_db_file.write_all(max.as_slice())?;
+
+        let mut tombstones = range_tombstones.iter().peekable();
+        for (key, entry) in mem_table.iter() {
+            while let Some(tombstone) = tombstones.next_if(|tombstone| comparator.compare(&tombstone.key, key).is_le()) {
+                write_entry(&mut new_db_file, tombstone)?;
+            }
+            write_entry(&mut new_db_file, entry)?;
+            for older in mem_table.older_versions(key).iter().rev() {
+                write_entry(&mut new_db_file, older)?;
+            }
+        }
+        for tombstone in tombstones {
+            write_entry(&mut new_db_file, tombstone)?;
+        }
+        fs::rename(&tmp_path, &path)?;
         self.files.push(FileService {
             min_size,
             max_size,
-            min_key: min.to_owned(),
-            max_key: max.to_owned(),
+            min_key: min,
+            max_key: max,
             file_path: path,
+            size: new_db_file.metadata()?.len(),
         });
         Ok(())
     }
+
+    // turn the entries `range_tombstone` covers into point tombstones.
+    fn delete_covered(&self, entries: &mut BTreeMap<OrderedKey, MemTableEntry>, range_tombstone: &MemTableEntry) {
+        let end = range_tombstone.range_end.as_ref().unwrap();
+        if self.comparator.compare(&range_tombstone.key, end).is_ge() {
+            return;
+        }
+        let start = OrderedKey::new(&range_tombstone.key, &self.comparator);
+        for (_, entry) in entries.range_mut(start..OrderedKey::new(end, &self.comparator)) {
+            entry.value = None;
+            entry.deleted = true;
+            entry.expires_at = None;
+            entry.timestamp = range_tombstone.timestamp;
+        }
+    }
+
+    pub fn file_count(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Bytes the next compaction has to read: all files, once there's more than one.
+    pub fn pending_compaction_bytes(&self) -> u64 {
+        if self.files.len() < 2 {
+            return 0;
+        }
+        self.files.iter().map(|file| file.size).sum()
+    }
+}
+
+fn write_entry(file: &mut File, entry: &MemTableEntry) -> io::Result<()> {
+    let key = entry.key.as_slice();
+    let tombstone = if entry.is_range_tombstone() {
+        ENTRY_DELETE_RANGE
+    } else if entry.deleted {
+        ENTRY_DELETE
+    } else if entry.expires_at.is_some() {
+        ENTRY_PUT_WITH_EXPIRY
+    } else {
+        ENTRY_PUT
+    };
+    file.write_all(&key.len().to_le_bytes())?;
+    file.write_all(&tombstone.to_le_bytes())?;
+    if tombstone == ENTRY_DELETE {
+        file.write_all(key)?;
+        file.write_all(&entry.timestamp.to_le_bytes())?;
+    } else {
+        let value = entry.range_end.as_ref().or(entry.value.as_ref()).unwrap();
+        file.write_all(&value.len().to_le_bytes())?;
+        file.write_all(key)?;
+        file.write_all(value)?;
+        file.write_all(&entry.timestamp.to_le_bytes())?;
+        if let Some(expires_at) = entry.expires_at {
+            file.write_all(&expires_at.to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+// the versions of a key, oldest first, that reads as of `horizon` or later may see:
+// those neither replaced nor expired by then. A tombstone is kept only while there's an
+// older version for it to shadow, or history may show it.
+fn retained_versions(versions: Vec<MemTableEntry>, horizon: u128) -> Vec<MemTableEntry> {
+    let replaced_at: Vec<Option<u128>> = versions.iter().skip(1)
+        .map(|newer| Some(newer.timestamp))
+        .chain(std::iter::once(None))
+        .collect();
+    let mut kept: Vec<MemTableEntry> = vec![];
+    for (entry, replaced_at) in versions.into_iter().zip(replaced_at) {
+        if replaced_at.is_some_and(|replaced_at| replaced_at <= horizon) || entry.is_expired(horizon) {
+            continue;
+        }
+        let shadows_nothing = match kept.last() {
+            Some(older) => older.deleted,
+            None => entry.timestamp <= horizon,
+        };
+        if entry.deleted && shadows_nothing {
+            continue;
+        }
+        kept.push(entry);
+    }
+    kept
+}
+
+// point tombstone standing for `key` being covered by a range tombstone.
+pub(crate) fn deleted_at(key: &[u8], timestamp: u128) -> MemTableEntry {
+    MemTableEntry {
+        key: key.to_owned(),
+        value: None,
+        timestamp,
+        deleted: true,
+        merge_operands: vec![],
+        expires_at: None,
+        range_end: None,
+    }
 }
 
+#[derive(Clone)]
 struct FileService {
     min_size: usize,
     max_size: usize,
     min_key: Vec<u8>,
     max_key: Vec<u8>,
     file_path: PathBuf,
+    size: u64,
 }
 
 impl FileService {
-    fn rebuild_mem_table_from_disk(&mut self) -> MemTable {
-        let mut new_mem_table = MemTable::new();
-        for entry in self.iter() {
-            if entry.deleted {
-                new_mem_table.delete(entry.key.as_slice(), entry.timestamp);
-            } else {
-                new_mem_table.put(entry.key.as_slice()
-                                  , entry.value.as_ref().unwrap()
-                                  , entry.timestamp);
-            }
+    //min_key_size--max_key-size--min_key--max_key
+    fn open(file_path: PathBuf) -> Result<FileService> {
+        let file = File::open(&file_path)?;
+        let len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let corrupted = |err: io::Error| match err.kind() {
+            ErrorKind::UnexpectedEof => Error::Corruption { file: file_path.clone(), offset: 0 },
+            _ => Error::Io(err),
+        };
+        let mut key_size = [0; 8];
+        reader.read_exact(&mut key_size).map_err(corrupted)?;
+        let min_size = usize::from_le_bytes(key_size);
+        reader.read_exact(&mut key_size).map_err(corrupted)?;
+        let max_size = usize::from_le_bytes(key_size);
+        if (min_size as u64).saturating_add(max_size as u64) > len {
+            return Err(Error::Corruption { file: file_path, offset: 0 });
         }
-        new_mem_table
+        let mut min_key = vec![0; min_size];
+        let mut max_key = vec![0; max_size];
+        reader.read_exact(&mut min_key).map_err(corrupted)?;
+        reader.read_exact(&mut max_key).map_err(corrupted)?;
+        Ok(FileService {
+            min_size,
+            max_size,
+            min_key,
+            max_key,
+            file_path,
+            size: len,
+        })
     }
 
-    fn iter(&self) -> DBFIterator {
-        DBFIterator::new(self.file_path.to_owned(),
-                         (8 + 8 + self.min_size + self.max_size)).unwrap()
+    // whether `key` lies within the bounds of the file.
+    fn may_hold(&self, comparator: &dyn Comparator, key: &[u8]) -> bool {
+        comparator.compare(key, &self.min_key).is_ge() && comparator.compare(key, &self.max_key).is_le()
     }
-}
 
-impl IntoIterator for FileService {
-    type Item = MemTableEntry;
-    type IntoIter = DBFIterator;
+    // whether compacting this file alone as of `horizon` would drop anything.
+    fn has_garbage(&self, horizon: u128) -> Result<bool> {
+        // key and timestamp of the previous entry, which an older version of the same key follows.
+        let mut newer: Option<(Vec<u8>, u128)> = None;
+        for entry in self.iter()? {
+            let entry = entry?;
+            let replaced = newer.as_ref().is_some_and(|(key, timestamp)| *key == entry.key && *timestamp <= horizon);
+            if replaced || (entry.deleted && entry.timestamp <= horizon) || entry.is_expired(horizon) {
+                return Ok(true);
+            }
+            newer = Some((entry.key, entry.timestamp));
+        }
+        Ok(false)
+    }
 
-    fn into_iter(self) -> DBFIterator {
-        DBFIterator::new(self.file_path, (8 + 8 + self.min_size + self.max_size)).unwrap()
+    fn iter(&self) -> Result<DBFIterator> {
+        DBFIterator::new(self.file_path.to_owned(), 8 + 8 + self.min_size + self.max_size)
     }
 }
 
 
+/// Reads the entries of a disk file in order. An entry that can't be decoded
+/// is returned as a `Corruption` error, after which the iterator ends.
 pub struct DBFIterator {
     reader: BufReader<File>,
+    path: PathBuf,
+    len: u64,
+    // offset of the entry being read, and of the next byte to read.
+    entry_offset: u64,
+    offset: u64,
+    failed: bool,
 }
 
 impl DBFIterator {
-    pub fn new(path: PathBuf, pos: usize) -> io::Result<DBFIterator> {
-        let file = OpenOptions::new().read(true).open(path)?;
+    pub fn new(path: PathBuf, pos: usize) -> Result<DBFIterator> {
+        let file = OpenOptions::new().read(true).open(&path)?;
+        let len = file.metadata()?.len();
         let mut reader = BufReader::new(file);
-        reader.seek(SeekFrom::Start(pos as u64));
+        reader.seek(SeekFrom::Start(pos as u64))?;
         Ok(DBFIterator {
-            reader
+            reader,
+            path,
+            len,
+            entry_offset: pos as u64,
+            offset: pos as u64,
+            failed: false,
         })
     }
-}
 
-impl Iterator for DBFIterator {
-    type Item = MemTableEntry;
+    fn corruption(&self) -> Error {
+        Error::Corruption { file: self.path.clone(), offset: self.entry_offset }
+    }
 
-    fn next(&mut self) -> Option<MemTableEntry> {
-        let mut key_len_buf = [0; 8];
-        if self.reader.read_exact(&mut key_len_buf).is_err() {
-            return None;
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        match self.reader.read_exact(buf) {
+            Ok(()) => {
+                self.offset += buf.len() as u64;
+                Ok(())
+            }
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => Err(self.corruption()),
+            Err(err) => Err(Error::Io(err)),
         }
-        let key_len = usize::from_le_bytes(key_len_buf);
+    }
+
+    // a size that has to fit in what's left of the file.
+    fn read_size(&mut self) -> Result<usize> {
+        let mut size_buf = [0; 8];
+        self.read_exact(&mut size_buf)?;
+        let size = u64::from_le_bytes(size_buf);
+        if size > self.len - self.offset {
+            return Err(self.corruption());
+        }
+        Ok(size as usize)
+    }
 
+    fn read_timestamp(&mut self) -> Result<u128> {
+        let mut timestamp_buf = [0; 16];
+        self.read_exact(&mut timestamp_buf)?;
+        Ok(u128::from_le_bytes(timestamp_buf))
+    }
+
+    //key_size--tombstone--value_size--key--value--timestamp[--expires_at]
+    fn read_entry(&mut self) -> Result<MemTableEntry> {
+        let key_len = self.read_size()?;
         let mut tombstone = [0; 1];
-        if self.reader.read_exact(&mut tombstone).is_err() {
-            return None;
+        self.read_exact(&mut tombstone)?;
+        let kind = tombstone[0];
+        if kind > ENTRY_DELETE_RANGE {
+            return Err(self.corruption());
         }
-        let deleted = tombstone[0] != 0;
+        let value_len = if kind == ENTRY_DELETE {
+            None
+        } else {
+            Some(self.read_size()?)
+        };
         let mut key = vec![0; key_len];
+        self.read_exact(&mut key)?;
         let mut value = None;
-        if deleted {
-            //let mut key = vec![0; key_len];
-            if self.reader.read_exact(&mut key).is_err() {
-                return None;
-            }
-        } else {
-            let mut value_size_buf = [0; 8];
-            if self.reader.read_exact(&mut value_size_buf).is_err() {
-                return None;
-            }
-            let value_len = usize::from_le_bytes(value_size_buf);
-
-            //let mut key = vec![0; key_len];
-            if self.reader.read_exact(&mut key).is_err() {
-                return None;
-            }
-
+        if let Some(value_len) = value_len {
             let mut value_buf = vec![0; value_len];
-            if self.reader.read_exact(&mut value_buf).is_err() {
-                return None;
-            }
+            self.read_exact(&mut value_buf)?;
             value = Some(value_buf);
         }
-        let mut timestamp_buf = [0; 16];
-        if self.reader.read_exact(&mut timestamp_buf).is_err() {
-            return None;
+        let timestamp = self.read_timestamp()?;
+        let mut expires_at = None;
+        if kind == ENTRY_PUT_WITH_EXPIRY {
+            expires_at = Some(self.read_timestamp()?);
         }
-
-        let timestamp = u128::from_le_bytes(timestamp_buf);
-        Some(MemTableEntry {
+        let mut range_end = None;
+        if kind == ENTRY_DELETE_RANGE {
+            range_end = value.take();
+        }
+        Ok(MemTableEntry {
             key,
             value,
             timestamp,
-            deleted,
+            deleted: kind == ENTRY_DELETE || kind == ENTRY_DELETE_RANGE,
+            merge_operands: vec![],
+            expires_at,
+            range_end,
         })
     }
 }
 
-#[cfg ! (test)]
+impl Iterator for DBFIterator {
+    type Item = Result<MemTableEntry>;
+
+    fn next(&mut self) -> Option<Result<MemTableEntry>> {
+        if self.failed {
+            return None;
+        }
+        match self.reader.fill_buf() {
+            Ok([]) => return None,
+            Ok(_) => {}
+            Err(err) => {
+                self.failed = true;
+                return Some(Err(Error::Io(err)));
+            }
+        }
+        self.entry_offset = self.offset;
+        let entry = self.read_entry();
+        self.failed = entry.is_err();
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
 mod test {
-    fn test_range(){
-        // TODO
+    use crate::comparator::{BytewiseComparator, Comparator, ReverseBytewiseComparator};
+    use crate::disk_service::DiskService;
+    use crate::error::Error;
+    use crate::mem_table::MemTable;
+    use std::fs::{self, OpenOptions};
+    use std::ops::Bound::{Excluded, Included};
+    use std::ops::Bound::Unbounded;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    fn bytewise() -> Arc<dyn Comparator> {
+        Arc::new(BytewiseComparator)
     }
 
-    fn test_new(){
-        // TODO
+    #[test]
+    fn test_get() {
+        let path = PathBuf::from(format!("./{}", "DISK_FILE_test_get"));
+        let mut disk_service = DiskService::new(&path, bytewise()).unwrap();
+
+        let mut table = MemTable::new(bytewise());
+        table.put(b"a", b"valueA", 1);
+        table.put(b"c", b"valueC", 2);
+        disk_service.write_mem_table_to_disk(&table).unwrap();
+
+        table = MemTable::new(bytewise());
+        table.put(b"c", b"valueC-2", 3);
+        table.delete(b"a", 4);
+        disk_service.write_mem_table_to_disk(&table).unwrap();
+
+        let disk_service = DiskService::open(&path, bytewise()).unwrap();
+        let a = disk_service.get(b"a").unwrap().unwrap();
+        assert!(a.deleted);
+        assert_eq!(a.timestamp, 4);
+        let c = disk_service.get(b"c").unwrap().unwrap();
+        assert_eq!(c.value.unwrap(), b"valueC-2");
+        assert!(disk_service.get(b"b").unwrap().is_none());
+
+        fs::remove_dir_all(&path).unwrap();
     }
 
-    fn test_next(){
-        // TODO
+    #[test]
+    fn test_compact() {
+        let path = PathBuf::from(format!("./{}", "DISK_FILE_test_compact"));
+        let mut disk_service = DiskService::new(&path, bytewise()).unwrap();
+
+        let mut table = MemTable::new(bytewise());
+        table.put(b"a", b"valueA", 1);
+        table.put(b"b", b"valueB", 2);
+        table.put_with_expiry(b"c", b"valueC", 3, 100);
+        table.put_with_expiry(b"d", b"valueD", 4, 1000);
+        disk_service.write_mem_table_to_disk(&table).unwrap();
+
+        table = MemTable::new(bytewise());
+        table.delete(b"a", 5);
+        table.put(b"b", b"valueB-2", 6);
+        disk_service.write_mem_table_to_disk(&table).unwrap();
+
+        for file in disk_service.compact(500).unwrap() {
+            fs::remove_file(file).unwrap();
+        }
+        assert_eq!(disk_service.files.len(), 1);
+        let entries: Vec<_> = disk_service.files[0].iter().unwrap().map(Result::unwrap).collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].key, b"b");
+        assert_eq!(entries[0].value.as_ref().unwrap(), b"valueB-2");
+        assert_eq!(entries[1].key, b"d");
+        assert_eq!(entries[1].expires_at, Some(1000));
+
+        for file in disk_service.compact(1000).unwrap() {
+            fs::remove_file(file).unwrap();
+        }
+        let disk_service = DiskService::open(&path, bytewise()).unwrap();
+        assert!(disk_service.get(b"d").unwrap().is_none());
+        assert_eq!(disk_service.get(b"b").unwrap().unwrap().value.unwrap(), b"valueB-2");
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_delete_range() {
+        let path = PathBuf::from(format!("./{}", "DISK_FILE_test_delete_range"));
+        let mut disk_service = DiskService::new(&path, bytewise()).unwrap();
+
+        let mut table = MemTable::new(bytewise());
+        table.put(b"a", b"valueA", 1);
+        table.put(b"b", b"valueB", 2);
+        table.put(b"c", b"valueC", 3);
+        table.put(b"d", b"valueD", 4);
+        disk_service.write_mem_table_to_disk(&table).unwrap();
+
+        table = MemTable::new(bytewise());
+        table.delete_range(b"b", b"d", 5);
+        table.put(b"c", b"valueC-2", 6);
+        disk_service.write_mem_table_to_disk(&table).unwrap();
+
+        let disk_service = DiskService::open(&path, bytewise()).unwrap();
+        assert_eq!(disk_service.get(b"a").unwrap().unwrap().value.unwrap(), b"valueA");
+        let b = disk_service.get(b"b").unwrap().unwrap();
+        assert!(b.deleted);
+        assert_eq!(b.timestamp, 5);
+        assert_eq!(disk_service.get(b"c").unwrap().unwrap().value.unwrap(), b"valueC-2");
+        assert_eq!(disk_service.get(b"d").unwrap().unwrap().value.unwrap(), b"valueD");
+        let live: Vec<_> = disk_service.scan(Included(b"a"), Excluded(b"e")).unwrap().into_iter()
+            .filter(|entry| !entry.deleted)
+            .map(|entry| entry.key)
+            .collect();
+        assert_eq!(live, vec![b"a".to_vec(), b"c".to_vec(), b"d".to_vec()]);
+
+        let mut disk_service = disk_service;
+        for file in disk_service.compact(6).unwrap() {
+            fs::remove_file(file).unwrap();
+        }
+        let keys: Vec<_> = disk_service.files[0].iter().unwrap().map(|entry| entry.unwrap().key).collect();
+        assert_eq!(keys, vec![b"a".to_vec(), b"c".to_vec(), b"d".to_vec()]);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_multi_get() {
+        let path = PathBuf::from(format!("./{}", "DISK_FILE_test_multi_get"));
+        let mut disk_service = DiskService::new(&path, bytewise()).unwrap();
+
+        let mut table = MemTable::new(bytewise());
+        table.put(b"a", b"valueA", 1);
+        table.put(b"b", b"valueB", 2);
+        table.put(b"d", b"valueD", 3);
+        disk_service.write_mem_table_to_disk(&table).unwrap();
+
+        table = MemTable::new(bytewise());
+        table.put(b"b", b"valueB-2", 4);
+        table.delete_range(b"c", b"e", 5);
+        disk_service.write_mem_table_to_disk(&table).unwrap();
+
+        let keys: Vec<&[u8]> = vec![b"a", b"b", b"c", b"d", b"z"];
+        let entries = disk_service.multi_get(&keys).unwrap();
+        assert_eq!(entries[0].as_ref().unwrap().value.as_ref().unwrap(), b"valueA");
+        assert_eq!(entries[1].as_ref().unwrap().value.as_ref().unwrap(), b"valueB-2");
+        assert!(entries[2].as_ref().unwrap().deleted);
+        assert_eq!(entries[3].as_ref().unwrap().timestamp, 5);
+        assert!(entries[4].is_none());
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_corruption() {
+        let path = PathBuf::from(format!("./{}", "DISK_FILE_test_corruption"));
+        let mut disk_service = DiskService::new(&path, bytewise()).unwrap();
+
+        let mut table = MemTable::new(bytewise());
+        table.put(b"a", b"valueA", 1);
+        table.put(b"b", b"valueB", 2);
+        disk_service.write_mem_table_to_disk(&table).unwrap();
+
+        // a torn last entry is reported where it starts, not read as garbage.
+        let file_path = disk_service.files[0].file_path.clone();
+        let len = fs::metadata(&file_path).unwrap().len();
+        OpenOptions::new().write(true).open(&file_path).unwrap().set_len(len - 3).unwrap();
+        let disk_service = DiskService::open(&path, bytewise()).unwrap();
+        match disk_service.get(b"b") {
+            Err(Error::Corruption { file, offset }) => {
+                assert_eq!(file, file_path);
+                assert!(offset > 0 && offset < len - 3);
+            }
+            _ => panic!("expected a corruption error"),
+        }
+
+        // a file too short to hold its header can't be opened at all.
+        fs::write(&file_path, [1, 2, 3]).unwrap();
+        assert!(matches!(DiskService::open(&path, bytewise()), Err(Error::Corruption { offset: 0, .. })));
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_recover() {
+        let path = PathBuf::from(format!("./{}", "DISK_FILE_test_recover"));
+        let mut disk_service = DiskService::new(&path, bytewise()).unwrap();
+
+        let mut table = MemTable::new(bytewise());
+        table.put(b"a", b"valueA", 1);
+        disk_service.write_mem_table_to_disk(&table).unwrap();
+
+        // what a crash left of a flush, and a file written while the clock ran ahead.
+        let file_path = disk_service.files[0].file_path.clone();
+        let stem = file_path.file_stem().unwrap().to_str().unwrap().parse::<u128>().unwrap();
+        let tmp_path = path.join(format!("{}.tmp", stem + 1));
+        fs::write(&tmp_path, [1, 2, 3]).unwrap();
+        let ahead = stem + 1_000_000_000_000;
+        fs::write(path.join(format!("{}.dbf", ahead)), fs::read(&file_path).unwrap()).unwrap();
+
+        // only the writer removes it, a reader leaves the directory as it is.
+        DiskService::open(&path, bytewise()).unwrap();
+        assert!(tmp_path.exists());
+        let mut disk_service = DiskService::recover(&path, bytewise()).unwrap();
+        assert!(!tmp_path.exists());
+
+        table = MemTable::new(bytewise());
+        table.put(b"a", b"valueA-2", 2);
+        disk_service.write_mem_table_to_disk(&table).unwrap();
+        assert_eq!(disk_service.files[2].file_path, path.join(format!("{}.dbf", ahead + 1)));
+        let disk_service = DiskService::open(&path, bytewise()).unwrap();
+        assert_eq!(disk_service.files.len(), 3);
+        assert_eq!(disk_service.get(b"a").unwrap().unwrap().value.unwrap(), b"valueA-2");
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_comparator() {
+        let path = PathBuf::from(format!("./{}", "DISK_FILE_test_comparator"));
+        let comparator: Arc<dyn Comparator> = Arc::new(ReverseBytewiseComparator);
+        let mut disk_service = DiskService::new(&path, comparator.clone()).unwrap();
+
+        let mut table = MemTable::new(comparator.clone());
+        table.put(b"a", b"valueA", 1);
+        table.put(b"b", b"valueB", 2);
+        table.put(b"c", b"valueC", 3);
+        disk_service.write_mem_table_to_disk(&table).unwrap();
+        table = MemTable::new(comparator.clone());
+        table.put(b"d", b"valueD", 4);
+        table.delete_range(b"c", b"a", 5);
+        disk_service.write_mem_table_to_disk(&table).unwrap();
+
+        // files are read back in the comparator's order.
+        let disk_service = DiskService::open(&path, comparator.clone()).unwrap();
+        assert!(disk_service.get(b"b").unwrap().unwrap().deleted);
+        assert_eq!(disk_service.get(b"a").unwrap().unwrap().value.unwrap(), b"valueA");
+        assert_eq!(disk_service.multi_get(&[b"d", b"b", b"a"]).unwrap().into_iter()
+                       .map(|entry| entry.unwrap().deleted)
+                       .collect::<Vec<_>>(), vec![false, true, false]);
+        let keys = |disk_service: &DiskService| disk_service.scan(Included(b"d"), Excluded(b"a")).unwrap()
+            .into_iter()
+            .map(|entry| (entry.key, entry.deleted))
+            .collect::<Vec<_>>();
+        assert_eq!(keys(&disk_service), vec![(b"d".to_vec(), false), (b"c".to_vec(), true), (b"b".to_vec(), true)]);
+
+        let mut disk_service = disk_service;
+        for file in disk_service.compact(u128::MAX).unwrap() {
+            fs::remove_file(file).unwrap();
+        }
+        let keys: Vec<_> = disk_service.scan(Unbounded, Unbounded).unwrap().into_iter().map(|entry| entry.key).collect();
+        assert_eq!(keys, vec![b"d".to_vec(), b"a".to_vec()]);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_history() {
+        let path = PathBuf::from(format!("./{}", "DISK_FILE_test_history"));
+        let mut disk_service = DiskService::new(&path, bytewise()).unwrap();
+
+        let mut table = MemTable::new(bytewise()).keep_history(true);
+        table.put(b"a", b"valueA", 1);
+        table.put(b"a", b"valueA-2", 2);
+        table.put(b"b", b"valueB", 3);
+        disk_service.write_mem_table_to_disk(&table).unwrap();
+
+        table = MemTable::new(bytewise()).keep_history(true);
+        table.put(b"a", b"valueA-3", 4);
+        table.delete_range(b"a", b"c", 5);
+        table.put(b"b", b"valueB-2", 6);
+        disk_service.write_mem_table_to_disk(&table).unwrap();
+
+        // reads see the latest version only.
+        let disk_service = DiskService::open(&path, bytewise()).unwrap();
+        assert_eq!(disk_service.get(b"b").unwrap().unwrap().value.unwrap(), b"valueB-2");
+        let keys: Vec<_> = disk_service.scan(Unbounded, Unbounded).unwrap().into_iter().map(|entry| entry.key).collect();
+        assert_eq!(keys, vec![b"a".to_vec(), b"b".to_vec()]);
+        let timestamps = |disk_service: &DiskService, key: &[u8]| -> Vec<u128> {
+            disk_service.history(key).unwrap().into_iter().map(|entry| entry.timestamp).collect()
+        };
+        assert_eq!(timestamps(&disk_service, b"a"), vec![1, 2, 4, 5]);
+        assert_eq!(timestamps(&disk_service, b"b"), vec![3, 5, 6]);
+
+        // versions replaced after the horizon survive compaction.
+        let mut disk_service = disk_service;
+        for file in disk_service.compact(3).unwrap() {
+            fs::remove_file(file).unwrap();
+        }
+        assert_eq!(disk_service.files.len(), 1);
+        assert_eq!(timestamps(&disk_service, b"a"), vec![2, 4, 5]);
+        assert_eq!(timestamps(&disk_service, b"b"), vec![3, 5, 6]);
+
+        for file in disk_service.compact(6).unwrap() {
+            fs::remove_file(file).unwrap();
+        }
+        assert!(disk_service.history(b"a").unwrap().is_empty());
+        assert_eq!(timestamps(&disk_service, b"b"), vec![6]);
+
+        fs::remove_dir_all(&path).unwrap();
     }
 }
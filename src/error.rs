@@ -0,0 +1,79 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Everything that can go wrong in a call to the engine.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing one of the db's files failed.
+    Io(io::Error),
+    /// `file` holds data that can't be decoded, starting at byte `offset`.
+    Corruption { file: PathBuf, offset: u64 },
+    /// The call makes no sense, e.g. a range whose start is after its end.
+    InvalidArgument(String),
+    /// The db, a column family or a prepared transaction doesn't exist.
+    NotFound(String),
+    /// The db, a column family or a prepared transaction exists already.
+    AlreadyExists(String),
+    /// The db is in use by someone else.
+    Busy(String),
+    /// An optimistic transaction read a key that was written after it began.
+    Conflict(String),
+    /// A pessimistic transaction waited longer than its lock timeout.
+    TimedOut(String),
+    /// Waiting for a lock would have closed a cycle of waiting transactions.
+    Deadlock(String),
+    /// The db can't do this, e.g. write while it's open read-only.
+    NotSupported(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "io error: {}", err),
+            Error::Corruption { file, offset } => {
+                write!(f, "corruption in {} at offset {}", file.display(), offset)
+            }
+            Error::InvalidArgument(message) => write!(f, "invalid argument: {}", message),
+            Error::NotFound(message) => write!(f, "not found: {}", message),
+            Error::AlreadyExists(message) => write!(f, "already exists: {}", message),
+            Error::Busy(message) => write!(f, "busy: {}", message),
+            Error::Conflict(message) => write!(f, "conflict: {}", message),
+            Error::TimedOut(message) => write!(f, "timed out: {}", message),
+            Error::Deadlock(message) => write!(f, "deadlock: {}", message),
+            Error::NotSupported(message) => write!(f, "not supported: {}", message),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+#[cfg(feature = "typed")]
+impl serde::ser::Error for Error {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        Error::InvalidArgument(message.to_string())
+    }
+}
+
+#[cfg(feature = "typed")]
+impl serde::de::Error for Error {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        Error::InvalidArgument(message.to_string())
+    }
+}
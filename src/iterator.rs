@@ -0,0 +1,35 @@
+use std::vec;
+
+/// Iterates over the live key-value pairs of a column family in key order.
+///
+/// The pairs are collected when the iterator is created, so writes made
+/// while iterating don't show up in it.
+pub struct DbIterator {
+    entries: vec::IntoIter<(Vec<u8>, Vec<u8>)>,
+}
+
+impl DbIterator {
+    pub(crate) fn new(entries: Vec<(Vec<u8>, Vec<u8>)>) -> DbIterator {
+        DbIterator {
+            entries: entries.into_iter(),
+        }
+    }
+}
+
+impl Iterator for DbIterator {
+    type Item = (Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.entries.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+impl DoubleEndedIterator for DbIterator {
+    fn next_back(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.entries.next_back()
+    }
+}
@@ -0,0 +1,263 @@
+use crate::error::{Error, Result};
+
+// bytes of an encoded byte string: 0x00 is escaped as 0x00 0xff, and the string ends with 0x00 0x01.
+const ESCAPE: u8 = 0x00;
+const ESCAPED_ZERO: u8 = 0xff;
+const TERMINATOR: u8 = 0x01;
+
+/// A value that encodes into key bytes whose bytewise order is the value's own order.
+///
+/// Integers are fixed width and big-endian, with the sign bit of signed ones flipped.
+/// Floats order like `total_cmp`. Byte strings and strings are escaped and terminated,
+/// so a tuple is simply its elements one after another, and the encoding of a tuple's
+/// first elements is a prefix of the whole tuple's, to scan by.
+pub trait EncodeKey {
+    fn encode_to(&self, out: &mut Vec<u8>);
+}
+
+/// A value that decodes back from the bytes `EncodeKey` made of it.
+pub trait DecodeKey: Sized {
+    /// Decodes a value off the front of `input`, and advances `input` past it.
+    fn decode_from(input: &mut &[u8]) -> Result<Self>;
+}
+
+/// Encodes `key` into key bytes, e.g. `encode_key(&("tenant", -5i64, 7u32))`.
+pub fn encode_key<K: EncodeKey + ?Sized>(key: &K) -> Vec<u8> {
+    let mut out = Vec::new();
+    key.encode_to(&mut out);
+    out
+}
+
+/// Decodes key bytes back into a `K`; bytes left over are an error.
+pub fn decode_key<K: DecodeKey>(mut bytes: &[u8]) -> Result<K> {
+    let key = K::decode_from(&mut bytes)?;
+    if !bytes.is_empty() {
+        return Err(Error::InvalidArgument(format!("{} bytes left after the encoded key", bytes.len())));
+    }
+    Ok(key)
+}
+
+fn take<'a>(input: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+    if input.len() < n {
+        return Err(Error::InvalidArgument("encoded key is truncated".to_string()));
+    }
+    let (head, tail) = input.split_at(n);
+    *input = tail;
+    Ok(head)
+}
+
+macro_rules! unsigned_key {
+    ($($t:ty),*) => {$(
+        impl EncodeKey for $t {
+            fn encode_to(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_be_bytes());
+            }
+        }
+
+        impl DecodeKey for $t {
+            fn decode_from(input: &mut &[u8]) -> Result<Self> {
+                let mut bytes = [0; std::mem::size_of::<$t>()];
+                bytes.copy_from_slice(take(input, std::mem::size_of::<$t>())?);
+                Ok(<$t>::from_be_bytes(bytes))
+            }
+        }
+    )*};
+}
+
+unsigned_key!(u8, u16, u32, u64, u128);
+
+macro_rules! signed_key {
+    ($($t:ty => $u:ty),*) => {$(
+        impl EncodeKey for $t {
+            fn encode_to(&self, out: &mut Vec<u8>) {
+                // flipping the sign bit puts negative numbers first, in order.
+                ((*self as $u) ^ (1 << (<$u>::BITS - 1))).encode_to(out);
+            }
+        }
+
+        impl DecodeKey for $t {
+            fn decode_from(input: &mut &[u8]) -> Result<Self> {
+                Ok((<$u>::decode_from(input)? ^ (1 << (<$u>::BITS - 1))) as $t)
+            }
+        }
+    )*};
+}
+
+signed_key!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+macro_rules! float_key {
+    ($($t:ty => $u:ty),*) => {$(
+        impl EncodeKey for $t {
+            fn encode_to(&self, out: &mut Vec<u8>) {
+                // negative floats have all their bits flipped, so larger magnitudes come first.
+                let bits = self.to_bits();
+                let sign = 1 << (<$u>::BITS - 1);
+                let bits = if bits & sign == 0 { bits ^ sign } else { !bits };
+                bits.encode_to(out);
+            }
+        }
+
+        impl DecodeKey for $t {
+            fn decode_from(input: &mut &[u8]) -> Result<Self> {
+                let bits = <$u>::decode_from(input)?;
+                let sign = 1 << (<$u>::BITS - 1);
+                let bits = if bits & sign == 0 { !bits } else { bits ^ sign };
+                Ok(<$t>::from_bits(bits))
+            }
+        }
+    )*};
+}
+
+float_key!(f32 => u32, f64 => u64);
+
+impl EncodeKey for bool {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+}
+
+impl DecodeKey for bool {
+    fn decode_from(input: &mut &[u8]) -> Result<Self> {
+        match take(input, 1)?[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            byte => Err(Error::InvalidArgument(format!("{} isn't an encoded bool", byte))),
+        }
+    }
+}
+
+impl EncodeKey for [u8] {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        for byte in self {
+            out.push(*byte);
+            if *byte == ESCAPE {
+                out.push(ESCAPED_ZERO);
+            }
+        }
+        out.extend_from_slice(&[ESCAPE, TERMINATOR]);
+    }
+}
+
+impl EncodeKey for Vec<u8> {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.as_slice().encode_to(out);
+    }
+}
+
+impl DecodeKey for Vec<u8> {
+    fn decode_from(input: &mut &[u8]) -> Result<Self> {
+        let mut bytes = Vec::new();
+        loop {
+            let byte = take(input, 1)?[0];
+            if byte != ESCAPE {
+                bytes.push(byte);
+                continue;
+            }
+            match take(input, 1)?[0] {
+                ESCAPED_ZERO => bytes.push(ESCAPE),
+                TERMINATOR => return Ok(bytes),
+                byte => return Err(Error::InvalidArgument(format!("0x00 followed by {:#04x} in an encoded key", byte))),
+            }
+        }
+    }
+}
+
+impl EncodeKey for str {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.as_bytes().encode_to(out);
+    }
+}
+
+impl EncodeKey for String {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        self.as_bytes().encode_to(out);
+    }
+}
+
+impl DecodeKey for String {
+    fn decode_from(input: &mut &[u8]) -> Result<Self> {
+        String::from_utf8(Vec::decode_from(input)?)
+            .map_err(|_| Error::InvalidArgument("encoded string isn't utf-8".to_string()))
+    }
+}
+
+impl<T: EncodeKey + ?Sized> EncodeKey for &T {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        (**self).encode_to(out);
+    }
+}
+
+macro_rules! tuple_key {
+    ($(($($name:ident),+)),*) => {$(
+        impl<$($name: EncodeKey),+> EncodeKey for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn encode_to(&self, out: &mut Vec<u8>) {
+                let ($($name,)+) = self;
+                $($name.encode_to(out);)+
+            }
+        }
+
+        impl<$($name: DecodeKey),+> DecodeKey for ($($name,)+) {
+            fn decode_from(input: &mut &[u8]) -> Result<Self> {
+                Ok(($($name::decode_from(input)?,)+))
+            }
+        }
+    )*};
+}
+
+tuple_key!((A), (A, B), (A, B, C), (A, B, C, D), (A, B, C, D, E), (A, B, C, D, E, F));
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::key_encoding::{decode_key, encode_key, EncodeKey};
+
+    fn assert_ordered<K: EncodeKey>(keys: &[K]) {
+        for pair in keys.windows(2) {
+            assert!(encode_key(&pair[0]) < encode_key(&pair[1]));
+        }
+    }
+
+    #[test]
+    fn test_numbers() {
+        assert_ordered(&[0u32, 1, 255, 256, u32::MAX]);
+        assert_ordered(&[i64::MIN, -256, -1, 0, 1, 255, i64::MAX]);
+        assert_ordered(&[f64::NEG_INFINITY, -1.5, -0.0, 0.0, 1e-9, 2.0, f64::INFINITY, f64::NAN]);
+        assert_eq!(encode_key(&-1i16), [0x7f, 0xff]);
+        for value in [i32::MIN, -7, 0, 7, i32::MAX].iter() {
+            assert_eq!(decode_key::<i32>(&encode_key(value)).unwrap(), *value);
+        }
+        for value in [-2.5f32, -0.0, 0.0, 3.25].iter() {
+            assert_eq!(decode_key::<f32>(&encode_key(value)).unwrap().to_bits(), value.to_bits());
+        }
+        assert_eq!(decode_key::<u128>(&encode_key(&u128::MAX)).unwrap(), u128::MAX);
+    }
+
+    #[test]
+    fn test_byte_strings() {
+        assert_ordered(&[&b""[..], b"\x00", b"\x00\x00", b"\x00\x01", b"a", b"a\x00", b"a\x00b", b"ab", b"\xff"]);
+        assert_ordered(&["", "a", "ab", "b"]);
+        assert_eq!(encode_key("a\0"), b"a\x00\xff\x00\x01");
+        let bytes = b"\x00\xff\x00\x01".to_vec();
+        assert_eq!(decode_key::<Vec<u8>>(&encode_key(&bytes)).unwrap(), bytes);
+        assert_eq!(decode_key::<String>(&encode_key("tenant")).unwrap(), "tenant");
+
+        assert!(matches!(decode_key::<Vec<u8>>(b"ab"), Err(Error::InvalidArgument(_))));
+        assert!(matches!(decode_key::<Vec<u8>>(b"a\x00\x02"), Err(Error::InvalidArgument(_))));
+        assert!(matches!(decode_key::<String>(b"\xff\x00\x01"), Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn test_tuples() {
+        // by tenant, then timestamp, then id; tenant names of any length.
+        assert_ordered(&[("a", -5i64, 2u32), ("a", -5, 10), ("a", 3, 0), ("a\0", i64::MIN, 0), ("ab", 0, 0)]);
+        let key = ("acme".to_string(), -42i64, 7u32, true);
+        let bytes = encode_key(&key);
+        assert_eq!(decode_key::<(String, i64, u32, bool)>(&bytes).unwrap(), key);
+        // a tuple's first elements encode to a prefix of it.
+        assert!(bytes.starts_with(&encode_key(&("acme", -42i64))));
+
+        assert!(matches!(decode_key::<(String, i64)>(&bytes), Err(Error::InvalidArgument(_))));
+        assert!(matches!(decode_key::<(String, i64, u32, bool, u8)>(&bytes), Err(Error::InvalidArgument(_))));
+    }
+}
@@ -0,0 +1,471 @@
+use crate::error::{Error, Result};
+use crate::key_encoding::{DecodeKey, EncodeKey};
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::ser::{self, Serialize};
+
+// the elements of seqs and maps each follow an ELEMENT byte, and END follows the last one,
+// so a sequence sorts before the longer ones it's a prefix of.
+const END: u8 = 0x00;
+const ELEMENT: u8 = 0x01;
+
+// encodes any Serde value into key bytes that sort like `key_encoding`'s: structs and tuples
+// field by field, enums by variant index, then their fields.
+pub(crate) fn to_key<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    let mut serializer = KeySerializer { out: Vec::new() };
+    value.serialize(&mut serializer)?;
+    Ok(serializer.out)
+}
+
+pub(crate) fn from_key<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    let mut deserializer = KeyDeserializer { input: bytes };
+    let value = T::deserialize(&mut deserializer)?;
+    if !deserializer.input.is_empty() {
+        return Err(Error::InvalidArgument(format!("{} bytes left after the encoded key", deserializer.input.len())));
+    }
+    Ok(value)
+}
+
+struct KeySerializer {
+    out: Vec<u8>,
+}
+
+macro_rules! serialize_encoded {
+    ($($method:ident: $t:ty),*) => {$(
+        fn $method(self, value: $t) -> Result<()> {
+            value.encode_to(&mut self.out);
+            Ok(())
+        }
+    )*};
+}
+
+impl<'a> ser::Serializer for &'a mut KeySerializer {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = MapSerializer<'a>;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    serialize_encoded!(serialize_bool: bool, serialize_i8: i8, serialize_i16: i16, serialize_i32: i32,
+                       serialize_i64: i64, serialize_i128: i128, serialize_u8: u8, serialize_u16: u16,
+                       serialize_u32: u32, serialize_u64: u64, serialize_u128: u128, serialize_f32: f32,
+                       serialize_f64: f64, serialize_str: &str, serialize_bytes: &[u8]);
+
+    fn serialize_char(self, value: char) -> Result<()> {
+        self.serialize_u32(value as u32)
+    }
+
+    fn serialize_none(self) -> Result<()> {
+        self.out.push(0);
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<()> {
+        self.out.push(1);
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<()> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, variant_index: u32, _variant: &'static str) -> Result<()> {
+        self.serialize_u32(variant_index)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _name: &'static str, variant_index: u32,
+                                                         _variant: &'static str, value: &T) -> Result<()> {
+        variant_index.encode_to(&mut self.out);
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, variant_index: u32, _variant: &'static str,
+                               _len: usize) -> Result<Self> {
+        variant_index.encode_to(&mut self.out);
+        Ok(self)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<MapSerializer<'a>> {
+        Ok(MapSerializer { serializer: self, entries: Vec::new() })
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(self, _name: &'static str, variant_index: u32, _variant: &'static str,
+                                _len: usize) -> Result<Self> {
+        variant_index.encode_to(&mut self.out);
+        Ok(self)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+impl ser::SerializeSeq for &mut KeySerializer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.out.push(ELEMENT);
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        self.out.push(END);
+        Ok(())
+    }
+}
+
+// map entries are written sorted by their encoded keys, so a map encodes the same
+// whatever order it iterates in, a `HashMap`'s included.
+struct MapSerializer<'a> {
+    serializer: &'a mut KeySerializer,
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl ser::SerializeMap for MapSerializer<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        self.entries.push((to_key(key)?, Vec::new()));
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let (_, encoded) = self.entries.last_mut()
+            .ok_or_else(|| Error::InvalidArgument("map value serialized before its key".to_owned()))?;
+        *encoded = to_key(value)?;
+        Ok(())
+    }
+
+    fn end(mut self) -> Result<()> {
+        self.entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let out = &mut self.serializer.out;
+        for (key, value) in self.entries {
+            out.push(ELEMENT);
+            out.extend(key);
+            out.extend(value);
+        }
+        out.push(END);
+        Ok(())
+    }
+}
+
+macro_rules! serialize_fields {
+    ($kind:ident: $method:ident($($key:ident: $key_type:ty)?)) => {
+        impl ser::$kind for &mut KeySerializer {
+            type Ok = ();
+            type Error = Error;
+
+            fn $method<T: Serialize + ?Sized>(&mut self, $($key: $key_type,)? value: &T) -> Result<()> {
+                value.serialize(&mut **self)
+            }
+
+            fn end(self) -> Result<()> {
+                Ok(())
+            }
+        }
+    };
+}
+
+serialize_fields!(SerializeTuple: serialize_element());
+serialize_fields!(SerializeTupleStruct: serialize_field());
+serialize_fields!(SerializeTupleVariant: serialize_field());
+serialize_fields!(SerializeStruct: serialize_field(_key: &'static str));
+serialize_fields!(SerializeStructVariant: serialize_field(_key: &'static str));
+
+struct KeyDeserializer<'i> {
+    input: &'i [u8],
+}
+
+impl<'i> KeyDeserializer<'i> {
+    fn decode<T: DecodeKey>(&mut self) -> Result<T> {
+        T::decode_from(&mut self.input)
+    }
+}
+
+macro_rules! deserialize_decoded {
+    ($($method:ident: $t:ty => $visit:ident),*) => {$(
+        fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+            visitor.$visit(self.decode::<$t>()?)
+        }
+    )*};
+}
+
+impl<'de, 'a, 'i> de::Deserializer<'de> for &'a mut KeyDeserializer<'i> {
+    type Error = Error;
+
+    deserialize_decoded!(deserialize_bool: bool => visit_bool, deserialize_i8: i8 => visit_i8,
+                         deserialize_i16: i16 => visit_i16, deserialize_i32: i32 => visit_i32,
+                         deserialize_i64: i64 => visit_i64, deserialize_i128: i128 => visit_i128,
+                         deserialize_u8: u8 => visit_u8, deserialize_u16: u16 => visit_u16,
+                         deserialize_u32: u32 => visit_u32, deserialize_u64: u64 => visit_u64,
+                         deserialize_u128: u128 => visit_u128, deserialize_f32: f32 => visit_f32,
+                         deserialize_f64: f64 => visit_f64, deserialize_str: String => visit_string,
+                         deserialize_string: String => visit_string, deserialize_bytes: Vec<u8> => visit_byte_buf,
+                         deserialize_byte_buf: Vec<u8> => visit_byte_buf, deserialize_identifier: u32 => visit_u32);
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value> {
+        Err(Error::NotSupported("encoded keys only decode into a known type".to_string()))
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_any(visitor)
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let code = self.decode::<u32>()?;
+        let char = std::char::from_u32(code)
+            .ok_or_else(|| Error::InvalidArgument(format!("{:#x} isn't an encoded char", code)))?;
+        visitor.visit_char(char)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.decode::<u8>()? {
+            0 => visitor.visit_none(),
+            1 => visitor.visit_some(self),
+            byte => Err(Error::InvalidArgument(format!("{} isn't an encoded option", byte))),
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_seq(Elements { de: self })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value> {
+        visitor.visit_seq(Fields { de: self, left: len })
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(self, _name: &'static str, len: usize,
+                                                 visitor: V) -> Result<V::Value> {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_map(Elements { de: self })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, _name: &'static str, fields: &'static [&'static str],
+                                           visitor: V) -> Result<V::Value> {
+        self.deserialize_tuple(fields.len(), visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str],
+                                         visitor: V) -> Result<V::Value> {
+        visitor.visit_enum(self)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+// the elements of a seq or map, up to END.
+struct Elements<'a, 'i> {
+    de: &'a mut KeyDeserializer<'i>,
+}
+
+impl<'a, 'i> Elements<'a, 'i> {
+    fn has_next(&mut self) -> Result<bool> {
+        match self.de.decode::<u8>()? {
+            END => Ok(false),
+            ELEMENT => Ok(true),
+            byte => Err(Error::InvalidArgument(format!("{} doesn't start an encoded element", byte))),
+        }
+    }
+}
+
+impl<'de, 'a, 'i> de::SeqAccess<'de> for Elements<'a, 'i> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        if !self.has_next()? {
+            return Ok(None);
+        }
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+}
+
+impl<'de, 'a, 'i> de::MapAccess<'de> for Elements<'a, 'i> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        if !self.has_next()? {
+            return Ok(None);
+        }
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        seed.deserialize(&mut *self.de)
+    }
+}
+
+// the `left` fields of a tuple or struct.
+struct Fields<'a, 'i> {
+    de: &'a mut KeyDeserializer<'i>,
+    left: usize,
+}
+
+impl<'de, 'a, 'i> de::SeqAccess<'de> for Fields<'a, 'i> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        self.left -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.left)
+    }
+}
+
+impl<'de, 'a, 'i> de::EnumAccess<'de> for &'a mut KeyDeserializer<'i> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self)> {
+        let index: de::value::U32Deserializer<Error> = self.decode::<u32>()?.into_deserializer();
+        Ok((seed.deserialize(index)?, self))
+    }
+}
+
+impl<'de, 'a, 'i> de::VariantAccess<'de> for &'a mut KeyDeserializer<'i> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value> {
+        de::Deserializer::deserialize_tuple(self, len, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, fields: &'static [&'static str], visitor: V) -> Result<V::Value> {
+        de::Deserializer::deserialize_tuple(self, fields.len(), visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::key_encoding::encode_key;
+    use crate::key_serde::{from_key, to_key};
+    use serde::{Deserialize, Serialize};
+    use std::collections::{BTreeMap, HashMap};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, PartialOrd)]
+    enum Kind {
+        Plain,
+        Tagged(String),
+        Point { x: i32, y: i32 },
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, PartialOrd)]
+    struct Event {
+        tenant: String,
+        at: i64,
+        kind: Kind,
+        labels: Vec<String>,
+        parent: Option<u32>,
+    }
+
+    fn event(tenant: &str, at: i64, kind: Kind, labels: &[&str], parent: Option<u32>) -> Event {
+        Event {
+            tenant: tenant.to_string(),
+            at,
+            kind,
+            labels: labels.iter().map(|label| label.to_string()).collect(),
+            parent,
+        }
+    }
+
+    #[test]
+    fn test_order_and_round_trip() {
+        // sorted as derived `PartialOrd` sorts them.
+        let events = vec![
+            event("a", -3, Kind::Point { x: 1, y: 2 }, &[], None),
+            event("a", 5, Kind::Plain, &["x"], Some(1)),
+            event("a", 5, Kind::Tagged("".to_string()), &[], None),
+            event("a", 5, Kind::Tagged("t".to_string()), &[], None),
+            event("a", 5, Kind::Point { x: -1, y: 0 }, &[], None),
+            event("a", 5, Kind::Point { x: -1, y: 0 }, &["x"], None),
+            event("a", 5, Kind::Point { x: -1, y: 0 }, &["x", ""], None),
+            event("a", 5, Kind::Point { x: -1, y: 0 }, &["y"], None),
+            event("a", 5, Kind::Point { x: -1, y: 0 }, &["y"], Some(0)),
+            event("ab", i64::MIN, Kind::Plain, &[], None),
+        ];
+        let keys: Vec<Vec<u8>> = events.iter().map(|event| to_key(event).unwrap()).collect();
+        for (pair, keys) in events.windows(2).zip(keys.windows(2)) {
+            assert!(pair[0] < pair[1]);
+            assert!(keys[0] < keys[1]);
+        }
+        for (event, key) in events.iter().zip(&keys) {
+            assert_eq!(&from_key::<Event>(key).unwrap(), event);
+        }
+
+        // plain values encode as `encode_key` does.
+        assert_eq!(to_key(&("tenant", -5i64, 7u32)).unwrap(), encode_key(&("tenant", -5i64, 7u32)));
+        assert_eq!(from_key::<(String, char)>(&to_key(&("é", 'ß')).unwrap()).unwrap(), ("é".to_string(), 'ß'));
+
+        // maps encode in key order, whatever order they iterate in.
+        let mut scores = HashMap::new();
+        for (i, name) in ["carol", "alice", "dave", "bob", "erin"].iter().enumerate() {
+            scores.insert(name.to_string(), i as u32);
+        }
+        let sorted: BTreeMap<String, u32> = scores.clone().into_iter().collect();
+        let key = to_key(&scores).unwrap();
+        assert_eq!(key, to_key(&sorted).unwrap());
+        assert_eq!(to_key(&scores.clone().into_iter().collect::<HashMap<_, _>>()).unwrap(), key);
+        assert_eq!(from_key::<HashMap<String, u32>>(&key).unwrap(), scores);
+
+        assert!(matches!(from_key::<Event>(&keys[0][..keys[0].len() - 1]), Err(Error::InvalidArgument(_))));
+        assert!(matches!(from_key::<(String, u8)>(&to_key(&("a", 1u16)).unwrap()), Err(Error::InvalidArgument(_))));
+    }
+}
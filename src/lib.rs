@@ -1,5 +1,39 @@
+//! An embedded key-value store built as a log-structured merge tree.
+//!
+//! Writes go to a write-ahead log and an in-memory table, which is flushed
+//! to sorted disk files once it grows past `Options::write_buffer_size`.
+
+mod column_family;
+mod comparator;
 mod db;
 mod disk_service;
+mod error;
+mod iterator;
+mod key_encoding;
+#[cfg(feature = "typed")]
+mod key_serde;
+mod lock_manager;
 mod mem_table;
+mod merge_operator;
+mod options;
+mod stats;
+mod transaction;
+#[cfg(feature = "typed")]
+mod tree;
 mod wal;
+mod write_batch;
 mod util;
+
+pub use crate::column_family::{ColumnFamilyHandle, ColumnFamilyOptions, Version};
+pub use crate::comparator::{BytewiseComparator, Comparator, ReverseBytewiseComparator};
+pub use crate::db::Db;
+pub use crate::error::{Error, Result};
+pub use crate::iterator::DbIterator;
+pub use crate::key_encoding::{decode_key, encode_key, DecodeKey, EncodeKey};
+pub use crate::merge_operator::MergeOperator;
+pub use crate::options::{Options, ReadOptions, WriteOptions};
+pub use crate::stats::{Stats, WriteStallCause, WriteStallCondition};
+pub use crate::transaction::{PessimisticTransaction, Transaction, TransactionOptions};
+#[cfg(feature = "typed")]
+pub use crate::tree::{Bincode, Codec, Json, Tree};
+pub use crate::write_batch::WriteBatch;
@@ -0,0 +1,150 @@
+use crate::error::{Error, Result};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Exclusive per-key locks for pessimistic transactions.
+///
+/// A transaction waits for at most one key at a time, so the wait-for graph
+/// is kept as a map from a waiting transaction to the lock owner it waits for.
+/// A lock request that would close a cycle in that graph fails right away.
+pub struct LockManager {
+    state: Mutex<LockState>,
+    released: Condvar,
+    next_txn_id: AtomicU64,
+}
+
+struct LockState {
+    // key -> id of the transaction holding it.
+    owners: HashMap<Vec<u8>, u64>,
+    // waiting transaction id -> id of the transaction it waits for.
+    waits_for: HashMap<u64, u64>,
+}
+
+impl LockManager {
+    pub fn new() -> LockManager {
+        LockManager {
+            state: Mutex::new(LockState {
+                owners: HashMap::new(),
+                waits_for: HashMap::new(),
+            }),
+            released: Condvar::new(),
+            next_txn_id: AtomicU64::new(1),
+        }
+    }
+
+    pub fn new_txn_id(&self) -> u64 {
+        self.next_txn_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Lock `key` for `txn_id`, waiting up to `timeout` for the current owner to release it.
+    /// Locking a key the transaction already holds succeeds immediately.
+    pub fn lock(&self, txn_id: u64, key: &[u8], timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let owner = match state.owners.get(key) {
+                Some(&owner) if owner != txn_id => owner,
+                Some(_) => return Ok(()),
+                None => {
+                    state.owners.insert(key.to_vec(), txn_id);
+                    state.waits_for.remove(&txn_id);
+                    return Ok(());
+                }
+            };
+
+            if state.leads_to(owner, txn_id) {
+                state.waits_for.remove(&txn_id);
+                return Err(Error::Deadlock(
+                    format!("locking key {} would deadlock", String::from_utf8_lossy(key)),
+                ));
+            }
+            state.waits_for.insert(txn_id, owner);
+
+            let now = Instant::now();
+            if now >= deadline {
+                state.waits_for.remove(&txn_id);
+                return Err(Error::TimedOut(
+                    format!("waiting for lock on key {}", String::from_utf8_lossy(key)),
+                ));
+            }
+            state = self.released.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Release every key in `keys` held by `txn_id` and wake up the waiters.
+    pub fn unlock(&self, txn_id: u64, keys: &[Vec<u8>]) {
+        let mut state = self.state.lock().unwrap();
+        for key in keys {
+            if state.owners.get(key) == Some(&txn_id) {
+                state.owners.remove(key);
+            }
+        }
+        state.waits_for.remove(&txn_id);
+        self.released.notify_all();
+    }
+}
+
+impl LockState {
+    // whether following the wait-for edges from `from` reaches `to`.
+    fn leads_to(&self, from: u64, to: u64) -> bool {
+        let mut current = from;
+        // every transaction has at most one outgoing edge, so a walk longer
+        // than the number of edges means we are going round a cycle without `to`.
+        for _ in 0..=self.waits_for.len() {
+            if current == to {
+                return true;
+            }
+            match self.waits_for.get(&current) {
+                Some(&next) => current = next,
+                None => return false,
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::lock_manager::LockManager;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_lock_timeout() {
+        let lock_manager = LockManager::new();
+        lock_manager.lock(1, b"a", Duration::from_millis(10)).unwrap();
+        lock_manager.lock(1, b"a", Duration::from_millis(10)).unwrap();
+
+        let err = lock_manager.lock(2, b"a", Duration::from_millis(10)).unwrap_err();
+        assert!(matches!(err, Error::TimedOut(_)));
+
+        lock_manager.unlock(1, &[b"a".to_vec()]);
+        lock_manager.lock(2, b"a", Duration::from_millis(10)).unwrap();
+    }
+
+    #[test]
+    fn test_deadlock() {
+        let lock_manager = Arc::new(LockManager::new());
+        lock_manager.lock(1, b"a", Duration::from_secs(1)).unwrap();
+        lock_manager.lock(2, b"b", Duration::from_secs(1)).unwrap();
+
+        let waiter = {
+            let lock_manager = lock_manager.clone();
+            thread::spawn(move || lock_manager.lock(1, b"b", Duration::from_secs(5)))
+        };
+        // wait until transaction 1 is blocked on transaction 2.
+        while !lock_manager.state.lock().unwrap().waits_for.contains_key(&1) {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let err = lock_manager.lock(2, b"a", Duration::from_secs(5)).unwrap_err();
+        assert!(matches!(err, Error::Deadlock(_)));
+
+        lock_manager.unlock(2, &[b"b".to_vec()]);
+        waiter.join().unwrap().unwrap();
+    }
+}
@@ -1,78 +1,201 @@
 use std::collections::{BTreeMap};
-use std::collections::btree_map::{Range, Iter};
-use std::ops::Bound::Included;
+use std::ops::Bound;
+use std::sync::Arc;
+use crate::comparator::{self, Comparator, OrderedKey};
+use crate::write_batch::BatchOp;
 
-const MAX_MEM_TABLE_SIZE: usize = 4096;
-
-/// +--------------+------------------------+-----------------+---------------+
-/// | key: Vec<u8> | value: Option<Vec<u8>> | timestamp: u128 | deleted: bool |
-/// +--------------+------------------------+-----------------+---------------+
+/// +--------------+------------------------+-----------------+---------------+------------------------------+----------------------------+
+/// | key: Vec<u8> | value: Option<Vec<u8>> | timestamp: u128 | deleted: bool | merge_operands: Vec<Vec<u8>> | expires_at: Option<u128>   |
+/// +--------------+------------------------+-----------------+---------------+------------------------------+----------------------------+
+/// Merge operands apply on top of `value`, or of nothing when `deleted` is set.
+/// An entry with neither a value nor a tombstone only holds operands, and its
+/// base value has to be looked up in older data.
+/// Entries read back from a disk file may be range tombstones, which have
+/// `range_end` set and delete every key in [key, range_end).
 #[derive(Clone)]
 pub struct MemTableEntry {
     pub key: Vec<u8>,
     pub value: Option<Vec<u8>>,
     pub timestamp: u128,
     pub deleted: bool,
+    pub merge_operands: Vec<Vec<u8>>,
+    pub expires_at: Option<u128>,
+    pub range_end: Option<Vec<u8>>,
+}
+
+impl MemTableEntry {
+    pub fn is_range_tombstone(&self) -> bool {
+        self.range_end.is_some()
+    }
+
+    /// Whether this is a range tombstone covering `key`.
+    pub fn covers(&self, key: &[u8], comparator: &dyn Comparator) -> bool {
+        match self.range_end.as_ref() {
+            Some(end) => comparator.compare(&self.key, key).is_le() && comparator.compare(key, end).is_lt(),
+            None => false,
+        }
+    }
+
+    pub fn is_expired(&self, now: u128) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    // bytes of the value and merge operands, counted towards the size of the mem_table.
+    fn payload_len(&self) -> usize {
+        self.value.as_ref().map_or(0, Vec::len) + self.merge_operands.iter().map(Vec::len).sum::<usize>()
+    }
+
+    /// The value, unless the entry has expired at `now`.
+    pub fn live_value(&self, now: u128) -> Option<&Vec<u8>> {
+        if self.is_expired(now) {
+            None
+        } else {
+            self.value.as_ref()
+        }
+    }
 }
 
 
-/// +-----------------------------+-------------+
-/// | BTreeMap<key,MemTableEntry> | size: usize |
-/// +-----------------------------+-------------+
+/// Deletes every key in [start, end) written before `timestamp`.
+#[derive(Clone)]
+pub struct RangeTombstone {
+    pub start: Vec<u8>,
+    pub end: Vec<u8>,
+    pub timestamp: u128,
+}
+
+/// +-----------------------------+------------------------------------+-------------+
+/// | BTreeMap<key,MemTableEntry> | range_tombstones: Vec<RangeTomb..> | size: usize |
+/// +-----------------------------+------------------------------------+-------------+
+/// Range tombstones only shadow older data: the entries they cover in the
+/// mem_table are turned into point deletes when the tombstone is added, so
+/// every entry left in the btree is newer than any tombstone covering it.
+/// Keys are kept in the order of `comparator`.
+/// If it keeps history, the entries writes replace are kept too, as older versions.
+#[derive(Clone)]
 pub struct MemTable {
-    btree: BTreeMap<Vec<u8>, MemTableEntry>,
+    btree: BTreeMap<OrderedKey, MemTableEntry>,
+    // older versions of the keys, oldest first. None unless history is kept.
+    history: Option<BTreeMap<OrderedKey, Vec<MemTableEntry>>>,
+    range_tombstones: Vec<RangeTombstone>,
     size: usize,
+    comparator: Arc<dyn Comparator>,
 }
 
 impl MemTable {
-    pub fn new() -> MemTable {
+    pub fn new(comparator: Arc<dyn Comparator>) -> MemTable {
         MemTable {
             btree: BTreeMap::new(),
+            history: None,
+            range_tombstones: vec![],
             size: 0,
+            comparator,
         }
     }
 
+    /// Keep the versions writes replace, or not.
+    pub fn keep_history(mut self, keep: bool) -> MemTable {
+        self.history = if keep { Some(BTreeMap::new()) } else { None };
+        self
+    }
+
+    pub fn keeps_history(&self) -> bool {
+        self.history.is_some()
+    }
+
+    pub fn comparator(&self) -> &Arc<dyn Comparator> {
+        &self.comparator
+    }
+
+    fn ordered(&self, key: &[u8]) -> OrderedKey {
+        OrderedKey::new(key, &self.comparator)
+    }
+
     pub fn put(&mut self, key: &[u8], value: &[u8], timestamp: u128) {
+        self.put_entry(key, value, timestamp, None);
+    }
+
+    /// Put a value that reads as absent from `expires_at` on.
+    pub fn put_with_expiry(&mut self, key: &[u8], value: &[u8], timestamp: u128, expires_at: u128) {
+        self.put_entry(key, value, timestamp, Some(expires_at));
+    }
+
+    fn put_entry(&mut self, key: &[u8], value: &[u8], timestamp: u128, expires_at: Option<u128>) {
         let entry = MemTableEntry {
             key: key.to_owned(),
             value: Some(value.to_owned()),
             timestamp,
             deleted: false,
+            merge_operands: vec![],
+            expires_at,
+            range_end: None,
         };
 
-        if self.btree.contains_key(key) {
-            let old_value = self.btree.get_mut(key).unwrap();
-            if let Some(v) = old_value.value.as_ref() {
-                let old_size = v.len();
-                let new_size = value.len();
-                if old_size < new_size {
-                    let gap = new_size - old_size;
-                    self.size += gap;
-                } else if old_size > new_size {
-                    let gap = old_size - new_size;
-                    self.size -= gap;
-                }
-            }
-            *old_value = entry;
-        } else {
-            self.size += key.len() + value.len() + 16 + 1;
-            self.btree.insert(key.to_vec(), entry);
-            // if let Some(v) = self.btree.get(&*key.to_vec()){
-            //     println!("{}",v.timestamp);
-            // }else{
-            //     println!("fuck");
-            // }
+        let key = self.ordered(key);
+        self.replace(key, entry);
+    }
+
+    // make `entry` the one of `key`, in place of the one it had if any.
+    fn replace(&mut self, key: OrderedKey, entry: MemTableEntry) {
+        self.retain_version(&key);
+        let key_len = key.bytes.len();
+        self.size += entry.payload_len();
+        match self.btree.insert(key, entry) {
+            Some(old_entry) => self.size -= old_entry.payload_len(),
+            None => self.size += key_len + 16 + 1,
         }
     }
 
     pub fn get(&self, key: &[u8]) -> Option<&MemTableEntry> {
-        self.btree.get(key)
+        self.btree.get(&self.ordered(key))
     }
 
+    /// The versions of `key` older than the one `get` returns, oldest first.
+    pub fn older_versions(&self, key: &[u8]) -> &[MemTableEntry] {
+        self.history.as_ref()
+            .and_then(|history| history.get(&self.ordered(key)))
+            .map_or(&[], Vec::as_slice)
+    }
+
+    /// Make `versions`, oldest first, the versions of `key`, as they are without
+    /// anything to count towards the size. Only the newest is kept without history.
+    pub fn set_versions(&mut self, key: &[u8], mut versions: Vec<MemTableEntry>) {
+        let key = self.ordered(key);
+        let Some(latest) = versions.pop() else {
+            return;
+        };
+        self.btree.insert(key.clone(), latest);
+        if let Some(history) = self.history.as_mut() {
+            if versions.is_empty() {
+                history.remove(&key);
+            } else {
+                history.insert(key, versions);
+            }
+        }
+    }
+
+    // keep the entry of `key` a write is about to replace as an older version, if history is kept.
+    fn retain_version(&mut self, key: &OrderedKey) {
+        let (Some(history), Some(entry)) = (self.history.as_mut(), self.btree.get(key)) else {
+            return;
+        };
+        self.size += key.bytes.len() + entry.payload_len() + 16 + 1;
+        history.entry(key.clone()).or_default().push(entry.clone());
+    }
+
+    #[cfg(test)]
     pub fn range(&self, min_key: &[u8], max_key: &[u8]) -> Vec<MemTableEntry> {
-        let range = self.btree.range((Included(min_key.to_owned()), Included(max_key.to_owned())));
+        self.scan(Bound::Included(min_key), Bound::Included(max_key))
+    }
+
+    pub fn scan(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Vec<MemTableEntry> {
+        if comparator::is_empty_range(&*self.comparator, lower, upper) {
+            return vec![];
+        }
+        let range = self.btree.range((comparator::ordered_bound(lower, &self.comparator),
+                                      comparator::ordered_bound(upper, &self.comparator)));
         let mut range_vec: Vec<MemTableEntry> = Vec::new();
-        for (key, entry) in range {
+        for (_, entry) in range {
             range_vec.push(entry.clone());
         }
         range_vec
@@ -84,48 +207,117 @@ impl MemTable {
             value: None,
             timestamp,
             deleted: true,
+            merge_operands: vec![],
+            expires_at: None,
+            range_end: None,
         };
 
-        if self.btree.contains_key(key) {
-            let old_value = self.btree.get_mut(key).unwrap();
-            if let Some(value) = old_value.value.as_ref() {
-                self.size -= value.len();
+        let key = self.ordered(key);
+        self.replace(key, entry);
+    }
+
+    /// Record a merge operand for `key` without resolving it.
+    pub fn merge(&mut self, key: &[u8], operand: &[u8], timestamp: u128) {
+        self.size += operand.len();
+        let ordered = self.ordered(key);
+        self.retain_version(&ordered);
+        if let Some(entry) = self.btree.get_mut(&ordered) {
+            // an expired value is gone, along with what was merged onto it,
+            // and leaves nothing for the operand to apply to.
+            if entry.is_expired(timestamp) {
+                self.size -= entry.payload_len();
+                entry.value = None;
+                entry.deleted = true;
+                entry.merge_operands.clear();
+                entry.expires_at = None;
             }
-            *old_value = entry;
+            entry.merge_operands.push(operand.to_owned());
+            entry.timestamp = timestamp;
         } else {
             self.size += key.len() + 16 + 1;
-            self.btree.insert(key.to_vec(), entry);
+            self.btree.insert(ordered, MemTableEntry {
+                key: key.to_owned(),
+                value: None,
+                timestamp,
+                deleted: false,
+                merge_operands: vec![operand.to_owned()],
+                expires_at: None,
+                range_end: None,
+            });
+        }
+    }
+
+    /// Delete every key in [start, end).
+    pub fn delete_range(&mut self, start: &[u8], end: &[u8], timestamp: u128) {
+        if self.comparator.compare(start, end).is_ge() {
+            return;
+        }
+        let covered: Vec<Vec<u8>> = self.btree.range(self.ordered(start)..self.ordered(end))
+            .map(|(key, _)| key.bytes.to_owned())
+            .collect();
+        for key in covered {
+            self.delete(&key, timestamp);
         }
+        self.size += start.len() + end.len() + 16 + 1;
+        self.range_tombstones.push(RangeTombstone {
+            start: start.to_owned(),
+            end: end.to_owned(),
+            timestamp,
+        });
+    }
+
+    /// Timestamp of the newest range tombstone covering `key`.
+    pub fn covering_tombstone(&self, key: &[u8]) -> Option<u128> {
+        self.range_tombstones.iter()
+            .filter(|tombstone| {
+                self.comparator.compare(&tombstone.start, key).is_le() && self.comparator.compare(key, &tombstone.end).is_lt()
+            })
+            .map(|tombstone| tombstone.timestamp)
+            .max()
     }
 
-    pub fn is_over_weight(&self) -> bool {
-        self.size >= 128
+    pub fn range_tombstones(&self) -> &[RangeTombstone] {
+        &self.range_tombstones
     }
 
-    pub fn clear(&mut self) {
-        self.btree.clear();
-        self.size = 0;
+    pub fn apply(&mut self, op: &BatchOp, timestamp: u128) {
+        match op {
+            BatchOp::Put { key, value, .. } => self.put(key, value, timestamp),
+            BatchOp::Delete { key, .. } => self.delete(key, timestamp),
+            BatchOp::Merge { key, operand, .. } => self.merge(key, operand, timestamp),
+            BatchOp::DeleteRange { start, end, .. } => self.delete_range(start, end, timestamp),
+            BatchOp::PutWithTtl { key, value, ttl, .. } => {
+                self.put_with_expiry(key, value, timestamp, timestamp.saturating_add(*ttl))
+            }
+        }
+    }
+
+    pub fn is_over_weight(&self, max_size: usize) -> bool {
+        self.size >= max_size
     }
 
     pub fn is_empty(& self)->bool{
-        self.btree.is_empty()
+        self.btree.is_empty() && self.range_tombstones.is_empty()
     }
 
-    pub fn iter(&self) -> Iter<'_, Vec<u8>, MemTableEntry> {
-        self.btree.iter()
+    /// Keys and their entries, in key order.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&Vec<u8>, &MemTableEntry)> {
+        self.btree.iter().map(|(key, entry)| (&key.bytes, entry))
     }
 }
 
 
 #[cfg(test)]
 mod tests {
-    use std::collections::BTreeMap;
     //use super::key;
+    use crate::comparator::{BytewiseComparator, ReverseBytewiseComparator};
     use crate::mem_table::MemTable;
+    use std::ops::Bound::Unbounded;
+    use std::sync::Arc;
 
     #[test]
     fn test_put_and_get() {
-        let mut table = MemTable::new();
+        let mut table = MemTable::new(Arc::new(BytewiseComparator));
         table.put(b"a", b"valueA", 1);
         table.put(b"a", b"valueA-2", 2);
         table.put(b"b", b"valueB", 3);
@@ -147,23 +339,99 @@ mod tests {
 
     #[test]
     fn test_range() {
-        let mut table = MemTable::new();
+        let mut table = MemTable::new(Arc::new(BytewiseComparator));
         table.put(b"a", b"valueA", 1);
         table.put(b"a", b"valueA-2", 2);
         table.put(b"b", b"valueB", 3);
         table.put(b"c", b"valueC", 4);
         table.put(b"c", b"valueC-2", 5);
 
-        let value_a = table.get(b"a").unwrap();
-        let value_b = table.get(b"b").unwrap();
-        let value_c = table.get(b"c").unwrap();
+        let _value_a = table.get(b"a").unwrap();
+        let _value_b = table.get(b"b").unwrap();
+        let _value_c = table.get(b"c").unwrap();
 
         let a = table.range(b"a", b"c");
         for aa in &a {
             println!("{}", aa.timestamp);
         }
     }
-}
 
+    #[test]
+    fn test_delete_range() {
+        let mut table = MemTable::new(Arc::new(BytewiseComparator));
+        table.put(b"a", b"valueA", 1);
+        table.put(b"b", b"valueB", 2);
+        table.put(b"c", b"valueC", 3);
+        table.delete_range(b"a", b"c", 4);
+        table.put(b"b", b"valueB-2", 5);
+
+        assert!(table.get(b"a").unwrap().deleted);
+        assert_eq!(table.get(b"b").unwrap().value.as_ref().unwrap(), b"valueB-2");
+        assert_eq!(table.get(b"c").unwrap().value.as_ref().unwrap(), b"valueC");
+        assert_eq!(table.covering_tombstone(b"b"), Some(4));
+        assert_eq!(table.covering_tombstone(b"c"), None);
+    }
 
+    #[test]
+    fn test_comparator() {
+        let mut table = MemTable::new(Arc::new(ReverseBytewiseComparator));
+        table.put(b"a", b"valueA", 1);
+        table.put(b"b", b"valueB", 2);
+        table.put(b"c", b"valueC", 3);
+        let keys: Vec<_> = table.scan(Unbounded, Unbounded).into_iter().map(|entry| entry.key).collect();
+        assert_eq!(keys, vec![b"c".to_vec(), b"b".to_vec(), b"a".to_vec()]);
 
+        // ranges run in the comparator's order too.
+        table.delete_range(b"c", b"a", 4);
+        assert!(table.get(b"c").unwrap().deleted);
+        assert!(table.get(b"b").unwrap().deleted);
+        assert!(!table.get(b"a").unwrap().deleted);
+        assert_eq!(table.covering_tombstone(b"b"), Some(4));
+        assert_eq!(table.covering_tombstone(b"a"), None);
+        table.delete_range(b"a", b"c", 5);
+        assert!(!table.get(b"a").unwrap().deleted);
+    }
+
+    #[test]
+    fn test_history() {
+        let mut table = MemTable::new(Arc::new(BytewiseComparator)).keep_history(true);
+        table.put(b"a", b"valueA", 1);
+        table.put(b"a", b"valueA-2", 2);
+        table.delete(b"a", 3);
+        table.put(b"b", b"valueB", 4);
+        assert!(table.get(b"a").unwrap().deleted);
+        let values: Vec<_> = table.older_versions(b"a").iter().map(|entry| entry.value.clone().unwrap()).collect();
+        assert_eq!(values, vec![b"valueA".to_vec(), b"valueA-2".to_vec()]);
+        assert!(table.older_versions(b"b").is_empty());
+
+        let versions = table.older_versions(b"a")[..1].to_vec();
+        table.set_versions(b"a", versions);
+        assert_eq!(table.get(b"a").unwrap().value.as_ref().unwrap(), b"valueA");
+        assert!(table.older_versions(b"a").is_empty());
+
+        // without history, replaced versions are gone.
+        let mut table = MemTable::new(Arc::new(BytewiseComparator));
+        table.put(b"a", b"valueA", 1);
+        table.put(b"a", b"valueA-2", 2);
+        assert!(table.older_versions(b"a").is_empty());
+    }
+
+    #[test]
+    fn test_size() {
+        let mut table = MemTable::new(Arc::new(BytewiseComparator));
+        table.put(b"k", b"a", 1);
+        table.delete(b"k", 2);
+        table.put(b"k", &[b'x'; 100], 3);
+        assert_eq!(table.size, 1 + 100 + 16 + 1);
+        table.delete(b"k", 4);
+        assert_eq!(table.size, 1 + 16 + 1);
+
+        // operands are dropped along with an expired value they were merged onto.
+        table.put_with_expiry(b"k", &[b'x'; 100], 5, 6);
+        table.merge(b"k", b"op-1", 5);
+        table.merge(b"k", b"op-2", 7);
+        assert_eq!(table.size, 1 + 4 + 16 + 1);
+        table.put(b"k", b"a", 8);
+        assert_eq!(table.size, 1 + 1 + 16 + 1);
+    }
+}
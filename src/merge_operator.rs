@@ -0,0 +1,11 @@
+/// Combines the operands written with `Db::merge` into a value.
+///
+/// Operands are kept as they are in the mem_table and only combined when the
+/// key is read, or when the mem_table is flushed, so a merge never needs a read.
+pub trait MergeOperator: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// `existing` is the value the operands apply to, None if the key has none.
+    /// `operands` are ordered oldest first.
+    fn full_merge(&self, key: &[u8], existing: Option<&[u8]>, operands: &[Vec<u8>]) -> Vec<u8>;
+}
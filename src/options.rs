@@ -0,0 +1,96 @@
+use crate::column_family::ColumnFamilyOptions;
+use crate::comparator::{BytewiseComparator, Comparator};
+use crate::merge_operator::MergeOperator;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Options of a `Db`, given every time it is created or opened.
+#[derive(Clone)]
+pub struct Options {
+    /// Order of the keys in every column family. The db remembers the name of the
+    /// comparator it was created with, and can't be opened with another one.
+    pub comparator: Arc<dyn Comparator>,
+    /// Operator combining the operands written by `merge` into the default column family.
+    /// Like every other option it isn't persisted, so it has to be passed on every open.
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+    /// Size in bytes the default column family's mem_table may grow to before it gets flushed.
+    pub write_buffer_size: usize,
+    /// Create the db when `Db::open` finds none at its path.
+    pub create_if_missing: bool,
+    /// Make `Db::open` fail with `AlreadyExists` when there's a db at its path already.
+    pub error_if_exists: bool,
+    /// Write the mem_tables out to disk files when the db is closed, so the
+    /// next open has no WAL to replay.
+    pub flush_on_close: bool,
+    /// Let the next group of writes go into the WAL while the previous one is
+    /// still being inserted into the mem_tables. On by default.
+    pub pipelined_write: bool,
+    /// Number of disk files in a column family that makes the background thread compact
+    /// it. 0 leaves compacting to `Db::compact`, unless a write stall limit needs it.
+    pub disk_file_compaction_trigger: usize,
+    /// Sealed mem_tables waiting for a flush at which every write group gets delayed.
+    /// Like all write stall limits, 0 means no limit.
+    pub slowdown_writes_immutable_mem_tables: usize,
+    /// Sealed mem_tables waiting for a flush at which writes stop until one is flushed.
+    pub stop_writes_immutable_mem_tables: usize,
+    /// Disk files in a column family at which every write group gets delayed.
+    pub slowdown_writes_disk_files: usize,
+    /// Disk files in a column family at which writes stop until it's compacted.
+    pub stop_writes_disk_files: usize,
+    /// Bytes waiting for compaction in a column family at which every write group gets delayed.
+    pub slowdown_writes_pending_compaction_bytes: u64,
+    /// Bytes waiting for compaction in a column family at which writes stop until it's compacted.
+    pub stop_writes_pending_compaction_bytes: u64,
+    /// Keep the versions writes replace for this long, through flushes and compactions,
+    /// for `Db::get_at` and `Db::history` to read. None keeps only the latest version.
+    pub history_retention: Option<Duration>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        let column_family = ColumnFamilyOptions::default();
+        Options {
+            comparator: Arc::new(BytewiseComparator),
+            merge_operator: column_family.merge_operator,
+            write_buffer_size: column_family.write_buffer_size,
+            create_if_missing: false,
+            error_if_exists: false,
+            flush_on_close: false,
+            pipelined_write: true,
+            disk_file_compaction_trigger: 4,
+            slowdown_writes_immutable_mem_tables: 4,
+            stop_writes_immutable_mem_tables: 8,
+            slowdown_writes_disk_files: 20,
+            stop_writes_disk_files: 36,
+            slowdown_writes_pending_compaction_bytes: 64 << 30,
+            stop_writes_pending_compaction_bytes: 256 << 30,
+            history_retention: None,
+        }
+    }
+}
+
+impl Options {
+    pub(crate) fn default_column_family(&self) -> ColumnFamilyOptions {
+        ColumnFamilyOptions {
+            merge_operator: self.merge_operator.clone(),
+            write_buffer_size: self.write_buffer_size,
+        }
+    }
+}
+
+/// Options of a single write.
+#[derive(Clone, Debug, Default)]
+pub struct WriteOptions {
+    /// Flush the WAL and sync it to disk before the write returns, so it survives
+    /// a crash of the machine. Otherwise it may sit in the WAL's buffer for a while.
+    pub sync: bool,
+}
+
+/// Options of a read.
+#[derive(Clone, Debug, Default)]
+pub struct ReadOptions {
+    /// Smallest key an iterator returns, unbounded if None.
+    pub iterate_lower_bound: Option<Vec<u8>>,
+    /// First key past the end of an iterator, unbounded if None.
+    pub iterate_upper_bound: Option<Vec<u8>>,
+}
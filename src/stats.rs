@@ -0,0 +1,126 @@
+use crate::options::Options;
+
+/// What a `Db` is up to, as returned by `Db::stats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Sealed mem_tables waiting to be flushed, in every column family.
+    pub immutable_mem_tables: usize,
+    /// Disk files of the column family with the most of them.
+    pub disk_files: usize,
+    /// Bytes the next compaction of the column family with the most of them has to read.
+    pub pending_compaction_bytes: u64,
+    /// Whether writes are currently held back, and why.
+    pub write_stall: WriteStallCondition,
+    /// Write groups that were delayed past a slowdown limit so far.
+    pub delayed_writes: u64,
+    /// Write groups that waited for background work past a stop limit so far.
+    pub stopped_writes: u64,
+    /// Time writes spent delayed or stopped, in microseconds.
+    pub stall_micros: u64,
+}
+
+/// How writes are let through while flushes and compactions catch up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WriteStallCondition {
+    #[default]
+    Normal,
+    /// Every write group is delayed a little.
+    Delayed(WriteStallCause),
+    /// Writes wait until background work brings the cause back under its limit.
+    Stopped(WriteStallCause),
+}
+
+/// The limit that stalls writes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WriteStallCause {
+    ImmutableMemTables,
+    DiskFiles,
+    PendingCompactionBytes,
+}
+
+// the soft and hard limits of `Options`, a limit of 0 being no limit.
+#[derive(Clone, Debug)]
+pub(crate) struct WriteStallLimits {
+    slowdown_immutable_mem_tables: usize,
+    stop_immutable_mem_tables: usize,
+    slowdown_disk_files: usize,
+    stop_disk_files: usize,
+    slowdown_pending_compaction_bytes: u64,
+    stop_pending_compaction_bytes: u64,
+}
+
+impl WriteStallLimits {
+    pub(crate) fn new(options: &Options) -> WriteStallLimits {
+        WriteStallLimits {
+            slowdown_immutable_mem_tables: options.slowdown_writes_immutable_mem_tables,
+            stop_immutable_mem_tables: options.stop_writes_immutable_mem_tables,
+            slowdown_disk_files: options.slowdown_writes_disk_files,
+            stop_disk_files: options.stop_writes_disk_files,
+            slowdown_pending_compaction_bytes: options.slowdown_writes_pending_compaction_bytes,
+            stop_pending_compaction_bytes: options.stop_writes_pending_compaction_bytes,
+        }
+    }
+
+    /// The condition for the sizes in `stats`. Stop limits are checked before slowdown ones.
+    pub(crate) fn condition(&self, stats: &Stats) -> WriteStallCondition {
+        let reached = |value: u64, limit: u64| limit > 0 && value >= limit;
+        let causes = [
+            (WriteStallCause::ImmutableMemTables, stats.immutable_mem_tables as u64,
+             self.slowdown_immutable_mem_tables as u64, self.stop_immutable_mem_tables as u64),
+            (WriteStallCause::DiskFiles, stats.disk_files as u64,
+             self.slowdown_disk_files as u64, self.stop_disk_files as u64),
+            (WriteStallCause::PendingCompactionBytes, stats.pending_compaction_bytes,
+             self.slowdown_pending_compaction_bytes, self.stop_pending_compaction_bytes),
+        ];
+        if let Some((cause, ..)) = causes.iter().find(|(_, value, _, stop)| reached(*value, *stop)) {
+            return WriteStallCondition::Stopped(*cause);
+        }
+        if let Some((cause, ..)) = causes.iter().find(|(_, value, slowdown, _)| reached(*value, *slowdown)) {
+            return WriteStallCondition::Delayed(*cause);
+        }
+        WriteStallCondition::Normal
+    }
+
+    // compacting is the only way down for the disk file and compaction byte limits.
+    pub(crate) fn needs_compaction(&self, stats: &Stats) -> bool {
+        matches!(self.condition(stats),
+            WriteStallCondition::Delayed(WriteStallCause::DiskFiles | WriteStallCause::PendingCompactionBytes)
+            | WriteStallCondition::Stopped(WriteStallCause::DiskFiles | WriteStallCause::PendingCompactionBytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::options::Options;
+    use crate::stats::{Stats, WriteStallCause, WriteStallCondition, WriteStallLimits};
+
+    #[test]
+    fn test_condition() {
+        let limits = WriteStallLimits::new(&Options {
+            slowdown_writes_immutable_mem_tables: 2,
+            stop_writes_immutable_mem_tables: 4,
+            slowdown_writes_disk_files: 8,
+            stop_writes_disk_files: 0,
+            slowdown_writes_pending_compaction_bytes: 0,
+            stop_writes_pending_compaction_bytes: 1000,
+            ..Options::default()
+        });
+        let condition = |immutable_mem_tables, disk_files, pending_compaction_bytes| limits.condition(&Stats {
+            immutable_mem_tables,
+            disk_files,
+            pending_compaction_bytes,
+            ..Stats::default()
+        });
+
+        assert_eq!(condition(1, 7, 999), WriteStallCondition::Normal);
+        assert_eq!(condition(2, 0, 0), WriteStallCondition::Delayed(WriteStallCause::ImmutableMemTables));
+        assert_eq!(condition(4, 0, 0), WriteStallCondition::Stopped(WriteStallCause::ImmutableMemTables));
+        // a disabled stop limit never stops, however far past the slowdown limit.
+        assert_eq!(condition(0, 100, 0), WriteStallCondition::Delayed(WriteStallCause::DiskFiles));
+        // stopping wins over slowing down.
+        assert_eq!(condition(2, 8, 1000), WriteStallCondition::Stopped(WriteStallCause::PendingCompactionBytes));
+
+        assert!(limits.needs_compaction(&Stats { disk_files: 8, ..Stats::default() }));
+        assert!(!limits.needs_compaction(&Stats { immutable_mem_tables: 4, ..Stats::default() }));
+    }
+}
@@ -0,0 +1,364 @@
+use crate::db::Db;
+use crate::error::{Error, Result};
+use crate::lock_manager::LockManager;
+use crate::write_batch::{BatchOp, WriteBatch};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// An optimistic transaction.
+///
+/// Writes are buffered locally and reads go through the buffer first, so a
+/// transaction sees its own writes. Every key read from the db is remembered;
+/// `commit` fails with a conflict if any of them was written after the
+/// transaction began, otherwise the buffered writes are applied as one `WriteBatch`.
+pub struct Transaction {
+    start_timestamp: u128,
+    read_keys: BTreeSet<Vec<u8>>,
+    // None marks a buffered delete.
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl Transaction {
+    pub(crate) fn new(start_timestamp: u128) -> Transaction {
+        Transaction {
+            start_timestamp,
+            read_keys: BTreeSet::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&mut self, db: &Db, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        let key = key.as_ref();
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        self.read_keys.insert(key.to_vec());
+        db.get(key)
+    }
+
+    pub fn put(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) {
+        self.writes.insert(key.as_ref().to_vec(), Some(value.as_ref().to_vec()));
+    }
+
+    pub fn delete(&mut self, key: impl AsRef<[u8]>) {
+        self.writes.insert(key.as_ref().to_vec(), None);
+    }
+
+    /// Write the buffered writes, failing with `Conflict` if a key read by the
+    /// transaction was written after it began. No other write can slip in between.
+    pub fn commit(self, db: &Db) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes.iter() {
+            match value {
+                Some(value) => batch.put(key, value),
+                None => batch.delete(key),
+            }
+        }
+        db.write_if(batch, |db| {
+            for key in self.read_keys.iter() {
+                if let Some(timestamp) = db.latest_timestamp(key)? {
+                    if timestamp > self.start_timestamp {
+                        return Err(Error::Conflict(
+                            format!("key {} was written after the transaction began", String::from_utf8_lossy(key)),
+                        ));
+                    }
+                }
+            }
+            Ok(true)
+        })?;
+        Ok(())
+    }
+
+    pub fn rollback(self) {}
+}
+
+pub struct TransactionOptions {
+    /// How long a pessimistic transaction waits for a key locked by another one.
+    pub lock_timeout: Duration,
+}
+
+impl Default for TransactionOptions {
+    fn default() -> TransactionOptions {
+        TransactionOptions {
+            lock_timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+/// A pessimistic transaction.
+///
+/// Every written key, and every key read with `get_for_update`, is locked
+/// until the transaction commits or rolls back, so other pessimistic
+/// transactions touching the same keys wait instead of retrying. Waiting
+/// fails after `lock_timeout`, or immediately if it would deadlock.
+pub struct PessimisticTransaction {
+    id: u64,
+    lock_manager: Arc<LockManager>,
+    lock_timeout: Duration,
+    locked_keys: Vec<Vec<u8>>,
+    // None marks a buffered delete.
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl PessimisticTransaction {
+    pub(crate) fn new(lock_manager: Arc<LockManager>, options: TransactionOptions) -> PessimisticTransaction {
+        PessimisticTransaction {
+            id: lock_manager.new_txn_id(),
+            lock_manager,
+            lock_timeout: options.lock_timeout,
+            locked_keys: vec![],
+            writes: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&self, db: &Db, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        let key = key.as_ref();
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        db.get(key)
+    }
+
+    /// Lock `key` and read it, so nobody else can change it before this transaction ends.
+    pub fn get_for_update(&mut self, db: &Db, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        let key = key.as_ref();
+        self.lock(key)?;
+        self.get(db, key)
+    }
+
+    pub fn put(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<()> {
+        let key = key.as_ref();
+        self.lock(key)?;
+        self.writes.insert(key.to_vec(), Some(value.as_ref().to_vec()));
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: impl AsRef<[u8]>) -> Result<()> {
+        let key = key.as_ref();
+        self.lock(key)?;
+        self.writes.insert(key.to_vec(), None);
+        Ok(())
+    }
+
+    /// Write the buffered changes through `db`, then release the locks.
+    pub fn commit(self, db: &Db) -> Result<()> {
+        db.write(self.write_batch())
+    }
+
+    pub fn rollback(self) {}
+
+    /// First phase of a two-phase commit: durably log the buffered changes under `name`.
+    /// The locks stay held until `db.commit_prepared(name)` or `db.rollback_prepared(name)`,
+    /// and the transaction is restored under the same name when the db is reopened.
+    pub fn prepare(self, db: &Db, name: &str) -> Result<()> {
+        db.prepare_transaction(name, self)
+    }
+
+    // rebuild a prepared transaction found in the wal, taking its locks again.
+    pub(crate) fn from_write_batch(lock_manager: Arc<LockManager>, batch: &WriteBatch) -> Result<PessimisticTransaction> {
+        let mut txn = PessimisticTransaction::new(lock_manager, TransactionOptions::default());
+        for op in batch.iter() {
+            match op {
+                BatchOp::Put { key, value, .. } => {
+                    txn.lock(key)?;
+                    txn.writes.insert(key.to_owned(), Some(value.to_owned()));
+                }
+                BatchOp::Delete { key, .. } => {
+                    txn.lock(key)?;
+                    txn.writes.insert(key.to_owned(), None);
+                }
+                // transactions don't buffer merges, expiring puts or range deletes,
+                // so a prepared batch never holds one.
+                BatchOp::Merge { key, .. } | BatchOp::PutWithTtl { key, .. } => txn.lock(key)?,
+                BatchOp::DeleteRange { .. } => {}
+            }
+        }
+        Ok(txn)
+    }
+
+    pub(crate) fn write_batch(&self) -> WriteBatch {
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes.iter() {
+            match value {
+                Some(value) => batch.put(key, value),
+                None => batch.delete(key),
+            }
+        }
+        batch
+    }
+
+    fn lock(&mut self, key: &[u8]) -> Result<()> {
+        if self.locked_keys.iter().any(|locked| locked.as_slice() == key) {
+            return Ok(());
+        }
+        self.lock_manager.lock(self.id, key, self.lock_timeout)?;
+        self.locked_keys.push(key.to_vec());
+        Ok(())
+    }
+}
+
+impl Drop for PessimisticTransaction {
+    fn drop(&mut self) {
+        self.lock_manager.unlock(self.id, &self.locked_keys);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::Db;
+    use crate::error::Error;
+    use crate::options::Options;
+    use crate::transaction::TransactionOptions;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    #[test]
+    fn test_commit() {
+        let path = PathBuf::from("./DB_test_txn_commit");
+        let handler_db = Db::create(&path, Options::default()).unwrap();
+        handler_db.put("counter", "1").unwrap();
+
+        let mut txn = handler_db.begin_transaction();
+        let counter = txn.get(&handler_db, "counter").unwrap().unwrap();
+        let counter: u32 = String::from_utf8(counter).unwrap().parse().unwrap();
+        txn.put("counter", (counter + 1).to_string());
+        txn.delete("other");
+        assert_eq!(txn.get(&handler_db, "counter").unwrap().unwrap(), b"2");
+        assert_eq!(handler_db.get("counter").unwrap().unwrap(), b"1");
+        txn.commit(&handler_db).unwrap();
+
+        assert_eq!(handler_db.get("counter").unwrap().unwrap(), b"2");
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_conflict() {
+        let path = PathBuf::from("./DB_test_txn_conflict");
+        let handler_db = Db::create(&path, Options::default()).unwrap();
+        handler_db.put("counter", "1").unwrap();
+
+        let mut txn = handler_db.begin_transaction();
+        txn.get(&handler_db, "counter").unwrap();
+        txn.put("counter", "2");
+        // written behind the transaction's back.
+        handler_db.put("counter", "10").unwrap();
+        assert!(txn.commit(&handler_db).is_err());
+        assert_eq!(handler_db.get("counter").unwrap().unwrap(), b"10");
+
+        // blind writes and reads of untouched keys don't conflict.
+        let mut txn = handler_db.begin_transaction();
+        txn.get(&handler_db, "missing").unwrap();
+        txn.put("counter", "3");
+        handler_db.put("unrelated", "x").unwrap();
+        txn.commit(&handler_db).unwrap();
+        assert_eq!(handler_db.get("counter").unwrap().unwrap(), b"3");
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_commit_after_reopen() {
+        let path = PathBuf::from("./DB_test_txn_commit_after_reopen");
+        let options = Options {
+            flush_on_close: true,
+            ..Options::default()
+        };
+        let handler_db = Db::create(&path, options.clone()).unwrap();
+        handler_db.put("k", "value-k").unwrap();
+        handler_db.close().unwrap();
+
+        // "k" is only on disk now, with an empty WAL to replay.
+        let handler_db = Db::open(&path, options).unwrap();
+        let mut txn = handler_db.begin_transaction();
+        assert_eq!(txn.get(&handler_db, "k").unwrap().unwrap(), b"value-k");
+        txn.put("k", "value-k2");
+        txn.commit(&handler_db).unwrap();
+        assert_eq!(handler_db.get("k").unwrap().unwrap(), b"value-k2");
+
+        handler_db.close().unwrap();
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_pessimistic_commit() {
+        let path = PathBuf::from("./DB_test_txn_pessimistic_commit");
+        let handler_db = Db::create(&path, Options::default()).unwrap();
+        handler_db.put("counter", "1").unwrap();
+
+        let mut txn = handler_db.begin_pessimistic_transaction(TransactionOptions::default());
+        let counter = txn.get_for_update(&handler_db, "counter").unwrap().unwrap();
+        let counter: u32 = String::from_utf8(counter).unwrap().parse().unwrap();
+        txn.put("counter", (counter + 1).to_string()).unwrap();
+
+        // the key stays locked until the first transaction is done.
+        let mut other = handler_db.begin_pessimistic_transaction(TransactionOptions {
+            lock_timeout: Duration::from_millis(10),
+        });
+        let err = other.put("counter", "100").unwrap_err();
+        assert!(matches!(err, Error::TimedOut(_)));
+
+        txn.commit(&handler_db).unwrap();
+        assert_eq!(handler_db.get("counter").unwrap().unwrap(), b"2");
+
+        other.put("counter", "100").unwrap();
+        other.rollback();
+        assert_eq!(handler_db.get("counter").unwrap().unwrap(), b"2");
+
+        let mut txn = handler_db.begin_pessimistic_transaction(TransactionOptions::default());
+        txn.delete("counter").unwrap();
+        txn.commit(&handler_db).unwrap();
+        assert!(handler_db.get("counter").unwrap().is_none());
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_prepare_survives_restart() {
+        let path = PathBuf::from("./DB_test_txn_prepare");
+        let handler_db = Db::create(&path, Options::default()).unwrap();
+        handler_db.put("a", "value-a").unwrap();
+
+        let mut txn = handler_db.begin_pessimistic_transaction(TransactionOptions::default());
+        txn.put("a", "value-a2").unwrap();
+        txn.put("b", "value-b").unwrap();
+        txn.prepare(&handler_db, "xid-1").unwrap();
+
+        let mut txn = handler_db.begin_pessimistic_transaction(TransactionOptions {
+            lock_timeout: Duration::from_millis(10),
+        });
+        txn.delete("a").unwrap_err();
+        txn.put("c", "value-c").unwrap();
+        txn.prepare(&handler_db, "xid-2").unwrap();
+
+        // prepared writes are invisible until committed.
+        assert_eq!(handler_db.get("a").unwrap().unwrap(), b"value-a");
+        assert!(handler_db.get("b").unwrap().is_none());
+        handler_db.close().unwrap();
+
+        let handler_db = Db::open(&path, Options::default()).unwrap();
+        assert_eq!(handler_db.prepared_transactions(), vec!["xid-1", "xid-2"]);
+        // the recovered transaction still holds its locks.
+        let mut other = handler_db.begin_pessimistic_transaction(TransactionOptions {
+            lock_timeout: Duration::from_millis(10),
+        });
+        other.put("b", "value-b2").unwrap_err();
+
+        handler_db.commit_prepared("xid-1").unwrap();
+        handler_db.rollback_prepared("xid-2").unwrap();
+        assert!(handler_db.commit_prepared("xid-2").is_err());
+        assert!(handler_db.prepared_transactions().is_empty());
+        other.put("b", "value-b2").unwrap();
+        other.rollback();
+        handler_db.close().unwrap();
+
+        let handler_db = Db::open(&path, Options::default()).unwrap();
+        assert!(handler_db.prepared_transactions().is_empty());
+        assert_eq!(handler_db.get("a").unwrap().unwrap(), b"value-a2");
+        assert_eq!(handler_db.get("b").unwrap().unwrap(), b"value-b");
+        assert!(handler_db.get("c").unwrap().is_none());
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,257 @@
+use crate::column_family::{ColumnFamilyHandle, DEFAULT_COLUMN_FAMILY, DEFAULT_COLUMN_FAMILY_NAME};
+use crate::comparator::{BytewiseComparator, Comparator};
+use crate::db::Db;
+use crate::error::{Error, Result};
+use crate::key_serde::{from_key, to_key};
+use crate::options::ReadOptions;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+
+/// Turns the values of a `Tree` into bytes and back.
+pub trait Codec {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>>;
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T>;
+}
+
+/// Compact binary values, as `bincode` encodes them.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Bincode;
+
+impl Codec for Bincode {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>> {
+        bincode::serialize(value).map_err(|err| Error::InvalidArgument(format!("bincode: {}", err)))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        bincode::deserialize(bytes).map_err(|err| Error::InvalidArgument(format!("bincode: {}", err)))
+    }
+}
+
+/// JSON values, readable by other tools.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Json;
+
+impl Codec for Json {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>> {
+        serde_json::to_vec(value).map_err(|err| Error::InvalidArgument(format!("json: {}", err)))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        serde_json::from_slice(bytes).map_err(|err| Error::InvalidArgument(format!("json: {}", err)))
+    }
+}
+
+/// A typed view of the keys under a prefix of a column family.
+///
+/// Keys are encoded to sort like `K` itself, by fields, variants and elements in order,
+/// which `range` relies on. That takes the db's default bytewise comparator, a tree
+/// can't be made on a db ordered by another one.
+/// Values are encoded by the `Codec`.
+pub struct Tree<'a, K, V, C = Bincode> {
+    db: &'a Db,
+    cf: ColumnFamilyHandle,
+    prefix: Vec<u8>,
+    codec: C,
+    types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<'a, K, V, C> Tree<'a, K, V, C>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    /// The keys under `prefix` in the default column family.
+    pub fn new(db: &'a Db, prefix: impl AsRef<[u8]>, codec: C) -> Result<Tree<'a, K, V, C>> {
+        check_comparator(db)?;
+        let cf = ColumnFamilyHandle::new(DEFAULT_COLUMN_FAMILY, DEFAULT_COLUMN_FAMILY_NAME);
+        Ok(Tree { db, cf, prefix: prefix.as_ref().to_vec(), codec, types: PhantomData })
+    }
+
+    /// All the keys of `cf`.
+    pub fn new_cf(db: &'a Db, cf: &ColumnFamilyHandle, codec: C) -> Result<Tree<'a, K, V, C>> {
+        check_comparator(db)?;
+        Ok(Tree { db, cf: cf.clone(), prefix: Vec::new(), codec, types: PhantomData })
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        match self.db.get_cf(&self.cf, self.encode_key(key)?)? {
+            Some(value) => Ok(Some(self.codec.decode(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn insert(&self, key: &K, value: &V) -> Result<()> {
+        self.db.put_cf(&self.cf, self.encode_key(key)?, self.codec.encode(value)?)
+    }
+
+    pub fn remove(&self, key: &K) -> Result<()> {
+        self.db.delete_cf(&self.cf, self.encode_key(key)?)
+    }
+
+    /// The entries whose keys lie in `range`, in key order; `..` for all of them.
+    pub fn range(&self, range: impl RangeBounds<K>) -> Result<Vec<(K, V)>> {
+        // keys of one type are never a prefix of one another, so the only key
+        // in [key, key + 0x00) is key itself.
+        let lower = match range.start_bound() {
+            Bound::Included(key) => Some(self.encode_key(key)?),
+            Bound::Excluded(key) => Some(self.encode_key_successor(key)?),
+            Bound::Unbounded => Some(self.prefix.clone()),
+        };
+        let upper = match range.end_bound() {
+            Bound::Included(key) => Some(self.encode_key_successor(key)?),
+            Bound::Excluded(key) => Some(self.encode_key(key)?),
+            Bound::Unbounded => self.prefix_end(),
+        };
+        let options = ReadOptions {
+            iterate_lower_bound: lower,
+            iterate_upper_bound: upper,
+        };
+        self.db.iter_cf(&self.cf, &options)?
+            .take_while(|(key, _)| key.starts_with(&self.prefix))
+            .map(|(key, value)| Ok((from_key(&key[self.prefix.len()..])?, self.codec.decode(&value)?)))
+            .collect()
+    }
+
+    fn encode_key(&self, key: &K) -> Result<Vec<u8>> {
+        let mut bytes = self.prefix.clone();
+        bytes.extend(to_key(key)?);
+        Ok(bytes)
+    }
+
+    fn encode_key_successor(&self, key: &K) -> Result<Vec<u8>> {
+        let mut bytes = self.encode_key(key)?;
+        bytes.push(0);
+        Ok(bytes)
+    }
+
+    // the first key past all those starting with the prefix, if any.
+    fn prefix_end(&self) -> Option<Vec<u8>> {
+        let end = BytewiseComparator.find_short_successor(&self.prefix);
+        if end == self.prefix {
+            None
+        } else {
+            Some(end)
+        }
+    }
+}
+
+// encoded keys, the prefix and the bounds of `range` are all ordered bytewise.
+fn check_comparator(db: &Db) -> Result<()> {
+    let name = db.comparator().name();
+    if name != BytewiseComparator.name() {
+        return Err(Error::InvalidArgument(format!("a tree needs {}, the db is ordered by {}",
+                                                  BytewiseComparator.name(), name)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::column_family::ColumnFamilyOptions;
+    use crate::comparator::ReverseBytewiseComparator;
+    use crate::db::Db;
+    use crate::error::Error;
+    use crate::key_encoding::encode_key;
+    use crate::options::Options;
+    use crate::tree::{Bincode, Json, Tree};
+    use serde::{Deserialize, Serialize};
+    use std::fs;
+    use std::ops::Bound::{Excluded, Unbounded};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+    struct User {
+        name: String,
+        age: u32,
+        emails: Vec<String>,
+    }
+
+    fn user(name: &str, age: u32) -> User {
+        User { name: name.to_string(), age, emails: vec![format!("{}@example.com", name)] }
+    }
+
+    #[test]
+    fn test_tree() {
+        let path = PathBuf::from("./DB_test_tree");
+        let handler_db = Db::create(&path, Options::default()).unwrap();
+        handler_db.put("user", "not in any tree").unwrap();
+        handler_db.put("users~", "past the prefix").unwrap();
+
+        let users: Tree<(String, i64), User> = Tree::new(&handler_db, "users/", Bincode).unwrap();
+        for (i, name) in ["carol", "alice", "bob", "alice"].iter().enumerate() {
+            users.insert(&(name.to_string(), 10 - 5 * i as i64), &user(name, i as u32)).unwrap();
+        }
+        assert_eq!(users.get(&("bob".to_string(), 0)).unwrap(), Some(user("bob", 2)));
+        assert_eq!(users.get(&("bob".to_string(), 1)).unwrap(), None);
+
+        // by name, then by the signed id.
+        let keys = |entries: Vec<((String, i64), User)>| -> Vec<(String, i64)> {
+            entries.into_iter().map(|(key, _)| key).collect()
+        };
+        let alice = |id: i64| ("alice".to_string(), id);
+        assert_eq!(keys(users.range(..).unwrap()),
+                   vec![alice(-5), alice(5), ("bob".to_string(), 0), ("carol".to_string(), 10)]);
+        assert_eq!(keys(users.range(alice(-5)..=("bob".to_string(), 0)).unwrap()),
+                   vec![alice(-5), alice(5), ("bob".to_string(), 0)]);
+        assert_eq!(keys(users.range((Excluded(alice(-5)), Unbounded)).unwrap()),
+                   vec![alice(5), ("bob".to_string(), 0), ("carol".to_string(), 10)]);
+        assert_eq!(keys(users.range(..alice(5)).unwrap()), vec![alice(-5)]);
+
+        users.remove(&alice(5)).unwrap();
+        assert_eq!(users.range(..("b".to_string(), 0)).unwrap(), vec![(alice(-5), user("alice", 3))]);
+        assert_eq!(handler_db.get("user").unwrap().unwrap(), b"not in any tree");
+
+        // entries of other types don't decode.
+        let ages: Tree<String, u32> = Tree::new(&handler_db, "users/", Bincode).unwrap();
+        assert!(matches!(ages.range(..), Err(Error::InvalidArgument(_))));
+
+        handler_db.close().unwrap();
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_tree_cf() {
+        let path = PathBuf::from("./DB_test_tree_cf");
+        let handler_db = Db::create(&path, Options::default()).unwrap();
+        let scores = handler_db.create_column_family("scores", ColumnFamilyOptions::default()).unwrap();
+        let tree: Tree<i32, Vec<f64>, Json> = Tree::new_cf(&handler_db, &scores, Json).unwrap();
+        tree.insert(&3, &vec![1.5]).unwrap();
+        tree.insert(&-2, &vec![]).unwrap();
+        tree.insert(&0, &vec![0.5, 2.0]).unwrap();
+        assert_eq!(handler_db.get_cf(&scores, encode_key(&0i32)).unwrap().unwrap(), b"[0.5,2.0]");
+        assert_eq!(tree.range(-2..3).unwrap(), vec![(-2, vec![]), (0, vec![0.5, 2.0])]);
+        handler_db.close().unwrap();
+
+        let handler_db = Db::open(&path, Options::default()).unwrap();
+        let scores = handler_db.column_family("scores").unwrap();
+        let tree: Tree<i32, Vec<f64>, Json> = Tree::new_cf(&handler_db, &scores, Json).unwrap();
+        assert_eq!(tree.get(&3).unwrap(), Some(vec![1.5]));
+        assert!(handler_db.get(encode_key(&3i32)).unwrap().is_none());
+
+        handler_db.close().unwrap();
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_tree_comparator() {
+        let path = PathBuf::from("./DB_test_tree_comparator");
+        let options = Options {
+            comparator: Arc::new(ReverseBytewiseComparator),
+            ..Options::default()
+        };
+        let handler_db = Db::create(&path, options).unwrap();
+        let scores = handler_db.create_column_family("scores", ColumnFamilyOptions::default()).unwrap();
+        let tree = Tree::<i32, i32>::new(&handler_db, "scores/", Bincode);
+        assert!(matches!(tree, Err(Error::InvalidArgument(_))));
+        let tree = Tree::<i32, i32>::new_cf(&handler_db, &scores, Bincode);
+        assert!(matches!(tree, Err(Error::InvalidArgument(_))));
+
+        handler_db.close().unwrap();
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -1,29 +1,77 @@
+use std::collections::{BTreeMap, VecDeque};
 use std::fs::{self, File, OpenOptions};
-use std::path::PathBuf;
-use std::io::{self, BufReader, Read, BufWriter, Write};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::path::{Path, PathBuf};
+use std::io::{BufReader, ErrorKind, Read, BufWriter, Seek, SeekFrom, Write};
+use std::sync::Arc;
+use crate::column_family::DEFAULT_COLUMN_FAMILY;
+use crate::comparator::Comparator;
+use crate::error::{Error, Result};
 use crate::mem_table::MemTable;
 use crate::util;
+use crate::write_batch::{BatchOp, WriteBatch};
+
+// names the newest wal file that was flushed, see `WAL::mark_flushed`.
+const FLUSHED: &str = "FLUSHED";
+
+// The byte following the leading length field tells what kind of record follows.
+const RECORD_PUT: u8 = 0;
+const RECORD_DELETE: u8 = 1;
+// len = payload size, payload = the batch's put/delete records back to back.
+const RECORD_BATCH: u8 = 2;
+// len = payload size, payload = name_size--name--put/delete records.
+const RECORD_PREPARE: u8 = 3;
+// len = name size, followed by name--timestamp.
+const RECORD_COMMIT: u8 = 4;
+// len = name size, followed by name.
+const RECORD_ROLLBACK: u8 = 5;
+// laid out like RECORD_PUT, the value being the merge operand.
+const RECORD_MERGE: u8 = 6;
+// a RECORD_PUT followed by the expiry time.
+const RECORD_PUT_WITH_EXPIRY: u8 = 7;
+// len = size of the wrapped record, followed by column_family_id--record.
+// Records of the default column family are never wrapped.
+const RECORD_COLUMN_FAMILY: u8 = 8;
+// laid out like RECORD_PUT, the key being the start and the value the end of the range.
+const RECORD_DELETE_RANGE: u8 = 9;
 
 pub struct WALEntry {
     key: Vec<u8>,
     value: Option<Vec<u8>>,
     timestamp: u128,
     deleted: bool,
+    // the value is a merge operand rather than a full value.
+    merge: bool,
+    expires_at: Option<u128>,
+    // the entry deletes [key, value).
+    delete_range: bool,
+    column_family: u32,
+}
+
+pub enum WALRecord {
+    Entry(WALEntry),
+    Prepare { name: String, entries: Vec<WALEntry> },
+    Commit { name: String, timestamp: u128 },
+    Rollback { name: String },
 }
 
+#[allow(clippy::upper_case_acronyms)]
 pub struct WAL {
     path: PathBuf,
     writer: BufWriter<File>,
+    // prepared transactions that are neither committed nor rolled back yet.
+    prepared: BTreeMap<String, WriteBatch>,
 }
 
 
 impl WAL {
-    pub fn new(dir: &PathBuf) -> io::Result<WAL> {
+    #[cfg(test)]
+    pub fn new(dir: &Path) -> Result<WAL> {
         let timestamp = util::get_timestamp();
-        let path = dir.join(timestamp.to_string() + ".wal");
+        WAL::create(dir.join(timestamp.to_string() + ".wal"))
+    }
+
+    fn create(path: PathBuf) -> Result<WAL> {
         let file = OpenOptions::new()
-            .write(true)
             .append(true)
             .create(true)
             .open(&path)?;
@@ -31,163 +79,729 @@ impl WAL {
         Ok(WAL {
             path,
             writer,
+            prepared: BTreeMap::new(),
         })
     }
 
-    // todo when current mem_table is full,it need to be written to a db file.
-    // at the same time current wal need to be deleted and fresh.
-    pub fn fresh(&mut self) -> io::Result<()> {
+    /// Switch to a new wal file, carrying undecided prepared transactions over, and
+    /// return the path of the old one. It has to stay until the mem_tables it logged
+    /// are on disk, so deleting it is up to the caller.
+    pub fn roll(&mut self) -> Result<PathBuf> {
+        self.writer.flush()?;
         let dir = self.path.parent().unwrap();
-        fs::remove_file(&self.path)?;
-        let timestamp = util::get_timestamp();
-        let path = dir.join(timestamp.to_string() + ".wal");
+        let mut timestamp = util::get_timestamp();
+        let mut path = dir.join(timestamp.to_string() + ".wal");
+        // file names have to keep increasing, as recovery replays them in name order.
+        while path <= self.path {
+            timestamp += 1;
+            path = dir.join(timestamp.to_string() + ".wal");
+        }
         let file = OpenOptions::new()
-            .write(true)
             .append(true)
             .create(true)
             .open(&path)?;
         let writer = BufWriter::new(file);
+        let old_path = std::mem::replace(&mut self.path, path);
         self.writer = writer;
-        self.path = path;
+        if !self.prepared.is_empty() {
+            let mut buf = vec![];
+            for (name, batch) in self.prepared.iter() {
+                encode_prepare(&mut buf, name, batch);
+            }
+            self.writer.write_all(&buf)?;
+            self.sync()?;
+        }
+        Ok(old_path)
+    }
+
+    /// Replay every wal file in `dir`, oldest first, into new mem_tables, one per column
+    /// family found in the wal, ordered by `comparator` and keeping history if `keep_history`
+    /// is set, and a new wal file. Prepared transactions without a commit or rollback
+    /// record are kept in `prepared`.
+    pub fn recover_column_families(dir: &Path, comparator: &Arc<dyn Comparator>, keep_history: bool)
+                                   -> Result<(WAL, BTreeMap<u32, MemTable>)> {
+        finish_recovery(dir)?;
+        let wal_paths = list_wal_files(dir)?;
+        // the new wal is written under a temporary name and marked complete once synced,
+        // so a crash never leaves both it and the files it replaces to be replayed.
+        let mut timestamp = util::get_timestamp();
+        // a wal named at or before the flushed one would never be replayed.
+        if let Some(flushed) = read_flushed(dir)? {
+            let flushed = flushed.trim_end_matches(".wal").parse::<u128>().unwrap_or(0);
+            timestamp = timestamp.max(flushed + 1);
+        }
+        let mut new_wal = WAL::create(dir.join(timestamp.to_string() + ".wal.tmp"))?;
+        let (new_mem_tables, prepared) = replay(&wal_paths, comparator, keep_history, &mut new_wal)?;
+        let mut buf = vec![];
+        for (name, batch) in prepared.iter() {
+            encode_prepare(&mut buf, name, batch);
+        }
+        new_wal.writer.write_all(&buf)?;
+        new_wal.prepared = prepared;
+        new_wal.sync()?;
+        let recovered_path = new_wal.path.with_extension("recovered");
+        fs::rename(&new_wal.path, &recovered_path)?;
+        new_wal.path = recovered_path;
+        finish_recovery(dir)?;
+        new_wal.path = new_wal.path.with_extension("");
+
+        Ok((new_wal, new_mem_tables))
+    }
+
+    /// Record that the mem_tables logged in `wal_path` and every older wal file are on
+    /// disk, before the files are deleted: replaying one that a crash left behind would
+    /// apply its merge operands a second time.
+    pub fn mark_flushed(wal_path: &Path) -> Result<()> {
+        let dir = wal_path.parent().unwrap();
+        let name = wal_path.file_name().unwrap().to_string_lossy();
+        let tmp_path = dir.join(FLUSHED).with_extension("tmp");
+        fs::write(&tmp_path, name.as_bytes())?;
+        File::open(&tmp_path)?.sync_all()?;
+        fs::rename(tmp_path, dir.join(FLUSHED))?;
         Ok(())
     }
 
-    fn from_path(path: &PathBuf) -> io::Result<WAL> {
-        let file = OpenOptions::new().append(true).create(true).open(path)?;
-        let writer = BufWriter::new(file);
-        Ok(WAL {
-            path: path.to_owned(),
-            writer,
-        })
+    /// Log every operation of `batch` as a single record. The record is only
+    /// replayed when its whole payload could be read back, which makes the batch atomic.
+    pub fn write_batch(&mut self, batch: &WriteBatch, timestamp: u128) -> Result<()> {
+        let mut buf = vec![];
+        encode_batch(&mut buf, batch, timestamp);
+        self.writer.write_all(&buf)?;
+        Ok(())
+    }
+
+    /// Log a group of batches, each under its own timestamp, with a single write
+    /// and, if `sync` is set, a single sync. A batch of one operation is logged as
+    /// a plain record, which is just as atomic. Even unsynced, the group is handed to
+    /// the OS right away, for secondaries tailing the file to see it.
+    pub fn write_group(&mut self, batches: &[(&WriteBatch, u128)], sync: bool) -> Result<()> {
+        let mut buf = vec![];
+        for (batch, timestamp) in batches.iter() {
+            if batch.len() == 1 {
+                encode_batch_ops(&mut buf, batch, *timestamp);
+            } else {
+                encode_batch(&mut buf, batch, *timestamp);
+            }
+        }
+        self.writer.write_all(&buf)?;
+        if sync {
+            self.sync()?;
+        } else {
+            self.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Durably log `batch` as the prepared transaction `name`. Nothing is applied
+    /// until `commit_prepared` is called, possibly after a restart.
+    pub fn prepare(&mut self, name: &str, batch: &WriteBatch) -> Result<()> {
+        if self.prepared.contains_key(name) {
+            return Err(Error::AlreadyExists(format!("prepared transaction {}", name)));
+        }
+        let mut buf = vec![];
+        encode_prepare(&mut buf, name, batch);
+        self.writer.write_all(&buf)?;
+        self.sync()?;
+        self.prepared.insert(name.to_owned(), batch.clone());
+        Ok(())
+    }
+
+    /// Log the commit of the prepared transaction `name` and hand back its writes.
+    pub fn commit_prepared(&mut self, name: &str, timestamp: u128) -> Result<WriteBatch> {
+        if !self.prepared.contains_key(name) {
+            return Err(not_prepared(name));
+        }
+        let mut buf = vec![];
+        buf.extend_from_slice(&name.len().to_le_bytes());
+        buf.push(RECORD_COMMIT);
+        buf.extend_from_slice(name.as_bytes());
+        buf.extend_from_slice(&timestamp.to_le_bytes());
+        self.writer.write_all(&buf)?;
+        self.sync()?;
+        Ok(self.prepared.remove(name).unwrap())
+    }
+
+    pub fn rollback_prepared(&mut self, name: &str) -> Result<()> {
+        if !self.prepared.contains_key(name) {
+            return Err(not_prepared(name));
+        }
+        let mut buf = vec![];
+        buf.extend_from_slice(&name.len().to_le_bytes());
+        buf.push(RECORD_ROLLBACK);
+        buf.extend_from_slice(name.as_bytes());
+        self.writer.write_all(&buf)?;
+        self.sync()?;
+        self.prepared.remove(name);
+        Ok(())
     }
 
-    pub fn recover(dir: &PathBuf) -> io::Result<(WAL, MemTable)> {
-        let mut wal_path = PathBuf::new();
-        let dir_entry = fs::read_dir(&dir)?;
-        for entry in dir_entry {
-            let path = entry.unwrap().path();
-            if path.extension().unwrap() == "wal" {
-                wal_path = path;
-                break;
+    pub fn prepared(&self) -> &BTreeMap<String, WriteBatch> {
+        &self.prepared
+    }
+
+    pub(crate) fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+}
+
+// deal with what a crash left behind: wal files flushed but not deleted yet are
+// deleted, and of a recovery, a wal it had not finished writing is dropped, while
+// one it had completed replaces the wal files it was recovered from.
+fn finish_recovery(dir: &Path) -> Result<()> {
+    if let Some(flushed) = read_flushed(dir)? {
+        for wal_path in all_wal_files(dir)? {
+            if is_flushed(&wal_path, &flushed) {
+                fs::remove_file(wal_path)?;
             }
         }
+    }
+    let mut recovered = None;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        match path.extension() {
+            Some(extension) if extension == "tmp" => fs::remove_file(&path)?,
+            Some(extension) if extension == "recovered" => recovered = Some(path),
+            _ => {}
+        }
+    }
+    if let Some(recovered) = recovered {
+        for wal_path in list_wal_files(dir)? {
+            fs::remove_file(wal_path)?;
+        }
+        fs::rename(&recovered, recovered.with_extension(""))?;
+    }
+    Ok(())
+}
+
+// wal files in `dir` still to be replayed, oldest first.
+fn list_wal_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut wal_paths = all_wal_files(dir)?;
+    if let Some(flushed) = read_flushed(dir)? {
+        wal_paths.retain(|wal_path| !is_flushed(wal_path, &flushed));
+    }
+    Ok(wal_paths)
+}
+
+// name of the newest wal file whose mem_tables are on disk, see `WAL::mark_flushed`.
+fn read_flushed(dir: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(dir.join(FLUSHED)) {
+        Ok(name) => Ok(Some(name)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn is_flushed(wal_path: &Path, flushed: &str) -> bool {
+    wal_path.file_name().is_some_and(|name| name.to_string_lossy().as_ref() <= flushed)
+}
+
+fn all_wal_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut wal_paths = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "wal") {
+            wal_paths.push(path);
+        }
+    }
+    wal_paths.sort();
+    Ok(wal_paths)
+}
 
-        let mut new_mem_table = MemTable::new();
-        let mut new_wal = WAL::new(&dir).unwrap();
-        if let Ok(wal) = WAL::from_path(&wal_path) {
-            for wal_entry in wal.into_iter() {
-                if wal_entry.deleted {
+// mem_tables by column family and prepared transactions by name.
+type Replayed = (BTreeMap<u32, MemTable>, BTreeMap<String, WriteBatch>);
+
+// replay `wal_paths` into new mem_tables, copying every applied record to `new_wal`.
+fn replay(wal_paths: &[PathBuf], comparator: &Arc<dyn Comparator>, keep_history: bool,
+          new_wal: &mut WAL) -> Result<Replayed> {
+    let mut replayed = Replayed::default();
+    for wal_path in wal_paths.iter() {
+        let mut records = WALIterator::new(wal_path.to_owned())?;
+        replay_records(&mut records, &mut replayed, comparator, keep_history, Some(new_wal))?;
+    }
+    Ok(replayed)
+}
+
+// apply what's left of `records` to `replayed`, copying every applied record to `new_wal` if there's one.
+fn replay_records(records: &mut WALIterator, (new_mem_tables, prepared): &mut Replayed,
+                  comparator: &Arc<dyn Comparator>, keep_history: bool,
+                  mut new_wal: Option<&mut WAL>) -> Result<()> {
+    let new_mem_table = || MemTable::new(comparator.clone()).keep_history(keep_history);
+    for record in records {
+        match record? {
+            WALRecord::Entry(wal_entry) => {
+                let new_mem_table = new_mem_tables.entry(wal_entry.column_family)
+                    .or_insert_with(new_mem_table);
+                if wal_entry.delete_range {
+                    let end = wal_entry.value.as_ref().unwrap();
+                    new_mem_table.delete_range(wal_entry.key.as_slice(), end, wal_entry.timestamp);
+                } else if wal_entry.merge {
+                    let operand = wal_entry.value.as_ref().unwrap();
+                    new_mem_table.merge(wal_entry.key.as_slice(), operand, wal_entry.timestamp);
+                } else if let Some(expires_at) = wal_entry.expires_at {
+                    let value = wal_entry.value.as_ref().unwrap();
+                    new_mem_table.put_with_expiry(wal_entry.key.as_slice(), value,
+                                                  wal_entry.timestamp, expires_at);
+                } else if wal_entry.deleted {
                     new_mem_table.delete(wal_entry.key.as_slice(), wal_entry.timestamp);
-                    new_wal.delete(wal_entry.key.as_slice(), wal_entry.timestamp);
                 } else {
                     new_mem_table.put(wal_entry.key.as_slice(), wal_entry.value.as_ref().unwrap()
                         .as_slice(), wal_entry.timestamp);
-                    new_wal.put(wal_entry.key.as_slice(), wal_entry.value.as_ref().unwrap()
-                        .as_slice(), wal_entry.timestamp);
                 }
+                if let Some(new_wal) = new_wal.as_mut() {
+                    let mut buf = vec![];
+                    encode_entry(&mut buf, &wal_entry);
+                    new_wal.writer.write_all(&buf)?;
+                }
+            }
+            WALRecord::Prepare { name, entries } => {
+                let mut batch = WriteBatch::new();
+                for wal_entry in entries {
+                    let column_family = wal_entry.column_family;
+                    match wal_entry.value {
+                        Some(operand) if wal_entry.merge => batch.merge_in(column_family, &wal_entry.key, &operand),
+                        Some(end) if wal_entry.delete_range => batch.delete_range_in(column_family, &wal_entry.key, &end),
+                        Some(value) => batch.put_in(column_family, &wal_entry.key, &value),
+                        None => batch.delete_in(column_family, &wal_entry.key),
+                    }
+                }
+                prepared.insert(name, batch);
+            }
+            WALRecord::Commit { name, timestamp } => {
+                if let Some(batch) = prepared.remove(&name) {
+                    for op in batch.iter() {
+                        new_mem_tables.entry(op.column_family())
+                            .or_insert_with(new_mem_table)
+                            .apply(op, timestamp);
+                    }
+                    if let Some(new_wal) = new_wal.as_mut() {
+                        new_wal.write_batch(&batch, timestamp)?;
+                    }
+                }
+            }
+            WALRecord::Rollback { name } => {
+                prepared.remove(&name);
             }
         }
-        new_wal.writer.flush().unwrap();
-        fs::remove_file(wal_path).unwrap();
+    }
+    Ok(())
+}
+
+/// Follows the wal files of a db that may be open elsewhere, without changing them.
+/// The first read replays every file; later ones only what was appended to the
+/// newest file since, as long as no file came or went in between.
+pub struct WALTail {
+    dir: PathBuf,
+    comparator: Arc<dyn Comparator>,
+    keep_history: bool,
+    // the files replayed so far, oldest first.
+    files: Vec<PathBuf>,
+    // where the first record of the newest file that wasn't replayed yet starts.
+    offset: u64,
+    // prepared transactions that are neither committed nor rolled back yet.
+    prepared: BTreeMap<String, WriteBatch>,
+}
 
-        Ok((new_wal, new_mem_table))
+impl WALTail {
+    /// The mem_tables replayed are ordered by `comparator`, and keep history if `keep_history` is set.
+    pub fn new(dir: &Path, comparator: Arc<dyn Comparator>, keep_history: bool) -> WALTail {
+        WALTail {
+            dir: dir.to_owned(),
+            comparator,
+            keep_history,
+            files: vec![],
+            offset: 0,
+            prepared: BTreeMap::new(),
+        }
     }
 
-    pub fn put(&mut self, key: &[u8], value: &[u8], timestamp: u128) -> io::Result<()> {
-        self.writer.write_all(&key.len().to_le_bytes())?;
-        self.writer.write_all(&(false as u8).to_le_bytes())?;
-        self.writer.write_all(&value.len().to_le_bytes())?;
-        self.writer.write_all(key)?;
-        self.writer.write_all(value)?;
-        self.writer.write_all(&timestamp.to_le_bytes())?;
-        Ok(())
+    /// Replay every wal file into new mem_tables, one per column family found in the wal.
+    /// Files deleted while reading are skipped, as a live db deletes them once their
+    /// mem_tables are on disk.
+    pub fn replay(&mut self) -> Result<BTreeMap<u32, MemTable>> {
+        let mut replayed = Replayed::default();
+        let mut files = vec![];
+        let mut offset = 0;
+        for wal_path in list_wal_files(&self.dir)? {
+            let mut records = match WALIterator::new(wal_path.clone()) {
+                Err(Error::Io(err)) if err.kind() == ErrorKind::NotFound => continue,
+                records => records?,
+            };
+            replay_records(&mut records, &mut replayed, &self.comparator, self.keep_history, None)?;
+            offset = records.end();
+            files.push(wal_path);
+        }
+        let (mem_tables, prepared) = replayed;
+        self.files = files;
+        self.offset = offset;
+        self.prepared = prepared;
+        Ok(mem_tables)
     }
 
-    pub fn delete(&mut self, key: &[u8], timestamp: u128) -> io::Result<()> {
-        self.writer.write_all(&key.len().to_le_bytes())?;
-        self.writer.write_all(&(true as u8).to_le_bytes())?;
-        self.writer.write_all(key)?;
-        self.writer.write_all(&timestamp.to_le_bytes())?;
-        Ok(())
+    /// Replay into `mem_tables` what was appended to the newest wal file since the last
+    /// read. Returns false, replaying nothing, if a file came or went since: then
+    /// everything has to be replayed again.
+    pub fn replay_appended(&mut self, mem_tables: &mut BTreeMap<u32, MemTable>) -> Result<bool> {
+        if list_wal_files(&self.dir)? != self.files {
+            return Ok(false);
+        }
+        let Some(wal_path) = self.files.last() else {
+            return Ok(true);
+        };
+        let mut records = match WALIterator::open_at(wal_path.clone(), self.offset) {
+            Err(Error::Io(err)) if err.kind() == ErrorKind::NotFound => return Ok(false),
+            records => records?,
+        };
+        let mut replayed = (std::mem::take(mem_tables), std::mem::take(&mut self.prepared));
+        let result = replay_records(&mut records, &mut replayed, &self.comparator, self.keep_history, None);
+        (*mem_tables, self.prepared) = replayed;
+        self.offset = records.end();
+        result.map(|()| true)
+    }
+
+    pub fn prepared(&self) -> &BTreeMap<String, WriteBatch> {
+        &self.prepared
+    }
+}
+
+fn not_prepared(name: &str) -> Error {
+    Error::NotFound(format!("prepared transaction {}", name))
+}
+
+//key_size--tombstone--value_size--key--value--timestamp
+fn encode_put(buf: &mut Vec<u8>, key: &[u8], value: &[u8], timestamp: u128) {
+    encode_value(buf, RECORD_PUT, key, value, timestamp);
+}
+
+//key_size--kind--operand_size--key--operand--timestamp
+fn encode_merge(buf: &mut Vec<u8>, key: &[u8], operand: &[u8], timestamp: u128) {
+    encode_value(buf, RECORD_MERGE, key, operand, timestamp);
+}
+
+//key_size--kind--value_size--key--value--timestamp--expires_at
+fn encode_put_with_expiry(buf: &mut Vec<u8>, key: &[u8], value: &[u8], timestamp: u128, expires_at: u128) {
+    encode_value(buf, RECORD_PUT_WITH_EXPIRY, key, value, timestamp);
+    buf.extend_from_slice(&expires_at.to_le_bytes());
+}
+
+//start_size--kind--end_size--start--end--timestamp
+fn encode_delete_range(buf: &mut Vec<u8>, start: &[u8], end: &[u8], timestamp: u128) {
+    encode_value(buf, RECORD_DELETE_RANGE, start, end, timestamp);
+}
+
+fn encode_value(buf: &mut Vec<u8>, kind: u8, key: &[u8], value: &[u8], timestamp: u128) {
+    buf.extend_from_slice(&key.len().to_le_bytes());
+    buf.push(kind);
+    buf.extend_from_slice(&value.len().to_le_bytes());
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(value);
+    buf.extend_from_slice(&timestamp.to_le_bytes());
+}
+
+//key_size--tombstone--key--timestamp
+fn encode_delete(buf: &mut Vec<u8>, key: &[u8], timestamp: u128) {
+    buf.extend_from_slice(&key.len().to_le_bytes());
+    buf.push(RECORD_DELETE);
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(&timestamp.to_le_bytes());
+}
+
+//payload_size--kind--payload
+fn encode_batch(buf: &mut Vec<u8>, batch: &WriteBatch, timestamp: u128) {
+    let mut payload = vec![];
+    encode_batch_ops(&mut payload, batch, timestamp);
+    buf.extend_from_slice(&payload.len().to_le_bytes());
+    buf.push(RECORD_BATCH);
+    buf.extend_from_slice(&payload);
+}
+
+fn encode_batch_ops(buf: &mut Vec<u8>, batch: &WriteBatch, timestamp: u128) {
+    for op in batch.iter() {
+        let mut record = vec![];
+        match op {
+            BatchOp::Put { key, value, .. } => encode_put(&mut record, key, value, timestamp),
+            BatchOp::Delete { key, .. } => encode_delete(&mut record, key, timestamp),
+            BatchOp::Merge { key, operand, .. } => encode_merge(&mut record, key, operand, timestamp),
+            BatchOp::DeleteRange { start, end, .. } => encode_delete_range(&mut record, start, end, timestamp),
+            BatchOp::PutWithTtl { key, value, ttl, .. } => {
+                encode_put_with_expiry(&mut record, key, value, timestamp, timestamp.saturating_add(*ttl))
+            }
+        }
+        encode_column_family(buf, op.column_family(), &record);
     }
 }
 
-impl IntoIterator for WAL {
-    type Item = WALEntry;
-    type IntoIter = WALIterator;
+fn encode_entry(buf: &mut Vec<u8>, entry: &WALEntry) {
+    let mut record = vec![];
+    if entry.deleted {
+        encode_delete(&mut record, &entry.key, entry.timestamp);
+    } else {
+        let value = entry.value.as_ref().unwrap();
+        if entry.delete_range {
+            encode_delete_range(&mut record, &entry.key, value, entry.timestamp);
+        } else if entry.merge {
+            encode_merge(&mut record, &entry.key, value, entry.timestamp);
+        } else if let Some(expires_at) = entry.expires_at {
+            encode_put_with_expiry(&mut record, &entry.key, value, entry.timestamp, expires_at);
+        } else {
+            encode_put(&mut record, &entry.key, value, entry.timestamp);
+        }
+    }
+    encode_column_family(buf, entry.column_family, &record);
+}
 
-    fn into_iter(self) -> WALIterator {
-        WALIterator::new(self.path).unwrap()
+//record_size--kind--column_family_id--record
+fn encode_column_family(buf: &mut Vec<u8>, column_family: u32, record: &[u8]) {
+    if column_family != DEFAULT_COLUMN_FAMILY {
+        buf.extend_from_slice(&record.len().to_le_bytes());
+        buf.push(RECORD_COLUMN_FAMILY);
+        buf.extend_from_slice(&column_family.to_le_bytes());
     }
+    buf.extend_from_slice(record);
 }
 
+fn encode_prepare(buf: &mut Vec<u8>, name: &str, batch: &WriteBatch) {
+    let mut payload = vec![];
+    payload.extend_from_slice(&name.len().to_le_bytes());
+    payload.extend_from_slice(name.as_bytes());
+    // the real timestamp is only known once the commit record is written.
+    encode_batch_ops(&mut payload, batch, 0);
+    buf.extend_from_slice(&payload.len().to_le_bytes());
+    buf.push(RECORD_PREPARE);
+    buf.extend_from_slice(&payload);
+}
+
+/// Reads the records of a wal file in order. A record cut off by a crash ends
+/// the iteration, while a record of unknown kind is returned as a `Corruption` error.
 pub struct WALIterator {
     reader: BufReader<File>,
+    path: PathBuf,
+    len: u64,
+    // entries of a batch record that have been read but not yet returned.
+    pending: VecDeque<WALEntry>,
+    failed: bool,
+    // where the record following the last one read in full starts.
+    end: u64,
 }
 
 impl WALIterator {
-    pub fn new(path: PathBuf) -> io::Result<WALIterator> {
-        let file = OpenOptions::new().read(true).open(path)?;
-        let reader = BufReader::new(file);
+    pub fn new(path: PathBuf) -> Result<WALIterator> {
+        WALIterator::open_at(path, 0)
+    }
+
+    /// Read the records starting at `offset`, which has to be where one starts.
+    pub fn open_at(path: PathBuf, offset: u64) -> Result<WALIterator> {
+        let file = OpenOptions::new().read(true).open(&path)?;
+        let len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        reader.seek(SeekFrom::Start(offset))?;
         Ok(WALIterator {
-            reader
+            reader,
+            path,
+            len,
+            pending: VecDeque::new(),
+            failed: false,
+            end: offset,
         })
     }
+
+    /// Where the record following the last one read in full starts. Once the iteration
+    /// is over, that's the end of the file, or the start of a record cut off, e.g. by
+    /// a writer still writing it.
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    // bytes left to read in the file.
+    fn remaining(&mut self) -> Option<u64> {
+        let position = self.reader.stream_position().ok()?;
+        Some(self.len.saturating_sub(position))
+    }
+
+    // a name of `len` bytes followed by `trailing` more bytes of the record; a length
+    // past the end of the file is a torn record.
+    fn read_name(&mut self, len: usize, trailing: u64) -> Option<String> {
+        if (len as u64).saturating_add(trailing) > self.remaining()? {
+            return None;
+        }
+        let mut name = vec![0; len];
+        if self.reader.read_exact(&mut name).is_err() {
+            return None;
+        }
+        String::from_utf8(name).ok()
+    }
 }
 
 impl Iterator for WALIterator {
-    type Item = WALEntry;
+    type Item = Result<WALRecord>;
 
-    fn next(&mut self) -> Option<WALEntry> {
-        let mut key_len_buf = [0; 8];
-        if self.reader.read_exact(&mut key_len_buf).is_err() {
+    fn next(&mut self) -> Option<Result<WALRecord>> {
+        if let Some(entry) = self.pending.pop_front() {
+            return Some(Ok(WALRecord::Entry(entry)));
+        }
+        if self.failed {
             return None;
         }
 
-        let key_len = usize::from_le_bytes(key_len_buf);
+        let offset = self.reader.stream_position().ok()?;
+        self.end = offset;
+        let mut len_buf = [0; 8];
+        if self.reader.read_exact(&mut len_buf).is_err() {
+            return None;
+        }
+        let len = usize::from_le_bytes(len_buf);
 
-        let mut tombstone = [0; 1];
-        if self.reader.read_exact(&mut tombstone).is_err() {
+        let mut kind = [0; 1];
+        if self.reader.read_exact(&mut kind).is_err() {
             return None;
         }
-        let deleted = tombstone[0] != 0;
-        let mut key = vec![0; key_len];
-        let mut value = None;
-        if deleted {
-            //let mut key = vec![0; key_len];
-            if self.reader.read_exact(&mut key).is_err() {
-                return None;
+
+        let remaining = self.remaining()?;
+        match kind[0] {
+            RECORD_BATCH | RECORD_PREPARE => {
+                // a torn batch is dropped as a whole.
+                if len as u64 > remaining {
+                    return None;
+                }
+                let mut payload = vec![0; len];
+                if self.reader.read_exact(&mut payload).is_err() {
+                    return None;
+                }
+                let mut payload = payload.as_slice();
+                if kind[0] == RECORD_BATCH {
+                    while let Some(entry) = read_entry(&mut payload, len as u64) {
+                        self.pending.push_back(entry);
+                    }
+                    return self.next();
+                }
+
+                let mut name_len_buf = [0; 8];
+                payload.read_exact(&mut name_len_buf).ok()?;
+                let name_len = usize::from_le_bytes(name_len_buf);
+                if name_len > payload.len() {
+                    return None;
+                }
+                let mut name = vec![0; name_len];
+                payload.read_exact(&mut name).ok()?;
+                let mut entries = vec![];
+                while let Some(entry) = read_entry(&mut payload, len as u64) {
+                    entries.push(entry);
+                }
+                Some(Ok(WALRecord::Prepare {
+                    name: String::from_utf8(name).ok()?,
+                    entries,
+                }))
             }
-        } else {
-            let mut value_size_buf = [0; 8];
-            if self.reader.read_exact(&mut value_size_buf).is_err() {
-                return None;
+            RECORD_COMMIT => {
+                let name = self.read_name(len, 16)?;
+                let mut timestamp_buf = [0; 16];
+                if self.reader.read_exact(&mut timestamp_buf).is_err() {
+                    return None;
+                }
+                Some(Ok(WALRecord::Commit {
+                    name,
+                    timestamp: u128::from_le_bytes(timestamp_buf),
+                }))
             }
-            let value_len = usize::from_le_bytes(value_size_buf);
-
-            //let mut key = vec![0; key_len];
-            if self.reader.read_exact(&mut key).is_err() {
-                return None;
+            RECORD_ROLLBACK => {
+                let name = self.read_name(len, 0)?;
+                Some(Ok(WALRecord::Rollback { name }))
             }
-
-            let mut value_buf = vec![0; value_len];
-            if self.reader.read_exact(&mut value_buf).is_err() {
-                return None;
+            kind if is_entry_kind(kind) => {
+                read_entry_body(&mut self.reader, len, kind, remaining).map(|entry| Ok(WALRecord::Entry(entry)))
+            }
+            _ => {
+                self.failed = true;
+                Some(Err(Error::Corruption { file: self.path.clone(), offset }))
             }
-            value = Some(value_buf);
         }
-        let mut timestamp_buf = [0; 16];
-        if self.reader.read_exact(&mut timestamp_buf).is_err() {
+    }
+}
+
+fn is_entry_kind(kind: u8) -> bool {
+    matches!(kind, RECORD_PUT | RECORD_DELETE | RECORD_MERGE | RECORD_PUT_WITH_EXPIRY
+        | RECORD_COLUMN_FAMILY | RECORD_DELETE_RANGE)
+}
+
+// `limit` is the number of bytes left in `reader`, which the entry must fit in.
+fn read_entry<R: Read>(reader: &mut R, limit: u64) -> Option<WALEntry> {
+    let mut key_len_buf = [0; 8];
+    if reader.read_exact(&mut key_len_buf).is_err() {
+        return None;
+    }
+    let key_len = usize::from_le_bytes(key_len_buf);
+
+    let mut tombstone = [0; 1];
+    if reader.read_exact(&mut tombstone).is_err() {
+        return None;
+    }
+    read_entry_body(reader, key_len, tombstone[0], limit.saturating_sub(9))
+}
+
+fn read_entry_body<R: Read>(reader: &mut R, key_len: usize, kind: u8, limit: u64) -> Option<WALEntry> {
+    if !is_entry_kind(kind) || key_len as u64 > limit {
+        return None;
+    }
+    if kind == RECORD_COLUMN_FAMILY {
+        let mut column_family_buf = [0; 4];
+        if reader.read_exact(&mut column_family_buf).is_err() {
             return None;
         }
-        let timestamp = u128::from_le_bytes(timestamp_buf);
-        Some(WALEntry {
-            key,
-            value,
-            timestamp,
-            deleted,
-        })
+        let mut entry = read_entry(reader, limit.saturating_sub(4))?;
+        entry.column_family = u32::from_le_bytes(column_family_buf);
+        return Some(entry);
+    }
+
+    let deleted = kind == RECORD_DELETE;
+    let mut key = vec![0; key_len];
+    let mut value = None;
+    if deleted {
+        if reader.read_exact(&mut key).is_err() {
+            return None;
+        }
+    } else {
+        let mut value_size_buf = [0; 8];
+        if reader.read_exact(&mut value_size_buf).is_err() {
+            return None;
+        }
+        let value_len = usize::from_le_bytes(value_size_buf);
+        if (key_len as u64).saturating_add(value_len as u64) > limit {
+            return None;
+        }
+
+        if reader.read_exact(&mut key).is_err() {
+            return None;
+        }
+
+        let mut value_buf = vec![0; value_len];
+        if reader.read_exact(&mut value_buf).is_err() {
+            return None;
+        }
+        value = Some(value_buf);
+    }
+    let mut timestamp_buf = [0; 16];
+    if reader.read_exact(&mut timestamp_buf).is_err() {
+        return None;
     }
+    let timestamp = u128::from_le_bytes(timestamp_buf);
+    let mut expires_at = None;
+    if kind == RECORD_PUT_WITH_EXPIRY {
+        if reader.read_exact(&mut timestamp_buf).is_err() {
+            return None;
+        }
+        expires_at = Some(u128::from_le_bytes(timestamp_buf));
+    }
+    Some(WALEntry {
+        key,
+        value,
+        timestamp,
+        deleted,
+        merge: kind == RECORD_MERGE,
+        expires_at,
+        delete_range: kind == RECORD_DELETE_RANGE,
+        column_family: DEFAULT_COLUMN_FAMILY,
+    })
 }
 
 #[cfg(test)]
@@ -196,8 +810,34 @@ mod tests {
     use std::fs::{File, OpenOptions};
     use std::fs;
     use std::time::{SystemTime, UNIX_EPOCH};
-    use crate::wal::WAL;
-    use std::path::PathBuf;
+    use crate::column_family::DEFAULT_COLUMN_FAMILY;
+    use crate::comparator::{BytewiseComparator, Comparator};
+    use crate::mem_table::MemTable;
+    use crate::util;
+    use crate::wal::{WALTail, RECORD_COMMIT, RECORD_ROLLBACK, WAL};
+    use crate::write_batch::WriteBatch;
+    use crate::error::{Error, Result};
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    fn recover(dir: &Path) -> Result<(WAL, MemTable)> {
+        let comparator: Arc<dyn Comparator> = Arc::new(BytewiseComparator);
+        let (wal, mut mem_tables) = WAL::recover_column_families(dir, &comparator, false)?;
+        let mem_table = mem_tables.remove(&DEFAULT_COLUMN_FAMILY).unwrap_or_else(|| MemTable::new(comparator));
+        Ok((wal, mem_table))
+    }
+
+    fn put(wal: &mut WAL, key: &[u8], value: &[u8], timestamp: u128) {
+        let mut batch = WriteBatch::new();
+        batch.put(key, value);
+        wal.write_group(&[(&batch, timestamp)], false).unwrap();
+    }
+
+    fn delete(wal: &mut WAL, key: &[u8], timestamp: u128) {
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        wal.write_group(&[(&batch, timestamp)], false).unwrap();
+    }
 
     fn check_entry(
         reader: &mut BufReader<File>,
@@ -240,8 +880,8 @@ mod tests {
 
     #[test]
     fn test_put() {
-        let path = PathBuf::from(format!("./{}", "WAL"));
-        fs::create_dir(&path);
+        let path = PathBuf::from(format!("./{}", "WAL_test_put"));
+        fs::create_dir(&path).unwrap();
 
         let timestamp = SystemTime::now()
             .duration_since(UNIX_EPOCH)
@@ -258,7 +898,7 @@ mod tests {
         let mut wal = WAL::new(&path).unwrap();
 
         for val in test_value.iter() {
-            wal.put(val.0, val.1.unwrap(), timestamp).unwrap();
+            put(&mut wal, val.0, val.1.unwrap(), timestamp);
         }
         wal.writer.flush().unwrap();
 
@@ -274,8 +914,8 @@ mod tests {
 
     #[test]
     fn test_delete() {
-        let path = PathBuf::from(format!("./{}", "WAL"));
-        fs::create_dir(&path);
+        let path = PathBuf::from(format!("./{}", "WAL_test_delete"));
+        fs::create_dir(&path).unwrap();
 
         let timestamp = SystemTime::now()
             .duration_since(UNIX_EPOCH)
@@ -290,7 +930,7 @@ mod tests {
         ];
         let mut wal = WAL::new(&path).unwrap();
         for val in test_value.iter() {
-            wal.delete(val.0, timestamp);
+            delete(&mut wal, val.0, timestamp);
         }
         wal.writer.flush().unwrap();
 
@@ -315,8 +955,8 @@ mod tests {
     // But the internal Btree will rewrite it's value when encounter the same key.
     #[test]
     fn test_read_wal() {
-        let path = PathBuf::from(format!("./{}", "WAL"));
-        fs::create_dir(&path);
+        let path = PathBuf::from(format!("./{}", "WAL_test_read_wal"));
+        fs::create_dir(&path).unwrap();
 
         let test_value: Vec<(&[u8], Option<&[u8]>)> = vec![
             (b"Apple", Some(b"Apple Smoothie")),
@@ -326,11 +966,11 @@ mod tests {
 
         let mut wal = WAL::new(&path).unwrap();
         for (i, val) in test_value.iter().enumerate() {
-            wal.put(val.0, val.1.unwrap(), i as u128).unwrap();
+            put(&mut wal, val.0, val.1.unwrap(), i as u128);
         }
         wal.writer.flush().unwrap();
 
-        let (new_wal, new_mem_table) = WAL::recover(&path).unwrap();
+        let (new_wal, new_mem_table) = recover(&path).unwrap();
 
         let file = OpenOptions::new().read(true).open(&new_wal.path).unwrap();
         let mut reader = BufReader::new(file);
@@ -346,4 +986,240 @@ mod tests {
 
         fs::remove_dir_all(&path).unwrap();
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_write_batch() {
+        let path = PathBuf::from(format!("./{}", "WAL_test_write_batch"));
+        fs::create_dir(&path).unwrap();
+
+        let mut wal = WAL::new(&path).unwrap();
+        put(&mut wal, b"a", b"value_a", 1);
+        let mut batch = WriteBatch::new();
+        batch.put(b"b", b"value_b");
+        batch.delete(b"a");
+        wal.write_batch(&batch, 2).unwrap();
+        wal.writer.flush().unwrap();
+
+        let (new_wal, new_mem_table) = recover(&path).unwrap();
+        let a = new_mem_table.get(b"a").unwrap();
+        assert!(a.deleted);
+        assert_eq!(a.timestamp, 2);
+        let b = new_mem_table.get(b"b").unwrap();
+        assert_eq!(b.value.as_ref().unwrap().as_slice(), b"value_b");
+        assert_eq!(b.timestamp, 2);
+
+        // a batch cut off in the middle must not be replayed at all.
+        let mut new_wal = new_wal;
+        let mut batch = WriteBatch::new();
+        batch.put(b"c", b"value_c");
+        batch.put(b"d", b"value_d");
+        new_wal.write_batch(&batch, 3).unwrap();
+        new_wal.writer.flush().unwrap();
+        let file = OpenOptions::new().write(true).open(&new_wal.path).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - 4).unwrap();
+        drop(new_wal);
+
+        let (_, new_mem_table) = recover(&path).unwrap();
+        assert!(new_mem_table.get(b"b").is_some());
+        assert!(new_mem_table.get(b"c").is_none());
+        assert!(new_mem_table.get(b"d").is_none());
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_delete_range() {
+        let path = PathBuf::from(format!("./{}", "WAL_test_delete_range"));
+        fs::create_dir(&path).unwrap();
+
+        let mut wal = WAL::new(&path).unwrap();
+        put(&mut wal, b"a", b"value_a", 1);
+        put(&mut wal, b"b", b"value_b", 2);
+        let mut batch = WriteBatch::new();
+        batch.delete_range(b"a", b"b");
+        wal.write_batch(&batch, 3).unwrap();
+        wal.writer.flush().unwrap();
+        drop(wal);
+
+        let (_, new_mem_table) = recover(&path).unwrap();
+        assert!(new_mem_table.get(b"a").unwrap().deleted);
+        assert!(!new_mem_table.get(b"b").unwrap().deleted);
+        assert_eq!(new_mem_table.covering_tombstone(b"a"), Some(3));
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_corruption() {
+        let path = PathBuf::from(format!("./{}", "WAL_test_corruption"));
+        fs::create_dir(&path).unwrap();
+
+        let mut wal = WAL::new(&path).unwrap();
+        put(&mut wal, b"a", b"value_a", 1);
+        wal.writer.flush().unwrap();
+        let wal_path = wal.path.clone();
+        drop(wal);
+
+        // a record cut off by a crash is dropped silently.
+        let mut file = OpenOptions::new().append(true).open(&wal_path).unwrap();
+        file.write_all(&3usize.to_le_bytes()).unwrap();
+        let (wal, mem_table) = recover(&path).unwrap();
+        assert!(mem_table.get(b"a").is_some());
+        let mut wal_path = wal.path.clone();
+        drop(wal);
+
+        // so is a commit or rollback whose name length runs past the end of the file.
+        for kind in [RECORD_COMMIT, RECORD_ROLLBACK].iter() {
+            let mut file = OpenOptions::new().append(true).open(&wal_path).unwrap();
+            file.write_all(&(usize::MAX >> 1).to_le_bytes()).unwrap();
+            file.write_all(&[*kind, b'x']).unwrap();
+            drop(file);
+            let (wal, mem_table) = recover(&path).unwrap();
+            assert!(mem_table.get(b"a").is_some());
+            wal_path = wal.path.clone();
+        }
+
+        // while a complete record of unknown kind is corruption.
+        let offset = fs::metadata(&wal_path).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&wal_path).unwrap();
+        file.write_all(&3usize.to_le_bytes()).unwrap();
+        file.write_all(&[42, b'x', b'y', b'z']).unwrap();
+        match recover(&path) {
+            Err(Error::Corruption { file, offset: at }) => {
+                assert_eq!(file, wal_path);
+                assert_eq!(at, offset);
+            }
+            _ => panic!("expected a corruption error"),
+        }
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_interrupted_recovery() {
+        let path = PathBuf::from(format!("./{}", "WAL_test_interrupted_recovery"));
+        fs::create_dir(&path).unwrap();
+
+        let mut wal = WAL::new(&path).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.merge(b"counter", b"1");
+        batch.merge(b"counter", b"2");
+        wal.write_group(&[(&batch, 1)], true).unwrap();
+        let wal_path = wal.path.clone();
+        drop(wal);
+
+        // a recovery that completed its wal but crashed before removing the old one,
+        // and another that crashed while writing.
+        let recovered_path = path.join(format!("{}.wal.recovered", util::get_timestamp()));
+        fs::copy(&wal_path, &recovered_path).unwrap();
+        fs::write(path.join(format!("{}.wal.tmp", util::get_timestamp())), b"torn").unwrap();
+
+        let (wal, mem_table) = recover(&path).unwrap();
+        assert_eq!(mem_table.get(b"counter").unwrap().merge_operands, vec![b"1".to_vec(), b"2".to_vec()]);
+        let files: Vec<_> = fs::read_dir(&path).unwrap().map(|entry| entry.unwrap().path()).collect();
+        assert_eq!(files, vec![wal.path.clone()]);
+        drop(wal);
+
+        let (_, mem_table) = recover(&path).unwrap();
+        assert_eq!(mem_table.get(b"counter").unwrap().merge_operands.len(), 2);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_roll() {
+        let path = PathBuf::from(format!("./{}", "WAL_test_roll"));
+        fs::create_dir(&path).unwrap();
+
+        let mut wal = WAL::new(&path).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(b"b", b"value_b");
+        wal.prepare("xid", &batch).unwrap();
+        put(&mut wal, b"a", b"value_a", 1);
+        let old_path = wal.roll().unwrap();
+        assert!(wal.path > old_path);
+        put(&mut wal, b"c", b"value_c", 2);
+        drop(wal);
+
+        // the old file is left in place, and replaying both gives every record once.
+        fs::remove_file(&old_path).unwrap();
+        let (wal, mem_table) = recover(&path).unwrap();
+        assert!(mem_table.get(b"a").is_none());
+        assert_eq!(mem_table.get(b"c").unwrap().value.as_ref().unwrap().as_slice(), b"value_c");
+        assert_eq!(wal.prepared().keys().collect::<Vec<_>>(), vec!["xid"]);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_tail() {
+        let path = PathBuf::from(format!("./{}", "WAL_test_tail"));
+        fs::create_dir(&path).unwrap();
+
+        let mut wal = WAL::new(&path).unwrap();
+        put(&mut wal, b"a", b"value_a", 1);
+        wal.sync().unwrap();
+        let mut tail = WALTail::new(&path, Arc::new(BytewiseComparator), false);
+        let mut mem_tables = tail.replay().unwrap();
+        assert!(mem_tables[&DEFAULT_COLUMN_FAMILY].get(b"a").is_some());
+
+        // a record cut off is left for the next read, which gets it once complete.
+        put(&mut wal, b"b", b"value_b", 2);
+        let mut buf = vec![];
+        super::encode_put(&mut buf, b"c", b"value_c", 3);
+        wal.writer.write_all(&buf[..buf.len() / 2]).unwrap();
+        wal.sync().unwrap();
+        assert!(tail.replay_appended(&mut mem_tables).unwrap());
+        assert!(mem_tables[&DEFAULT_COLUMN_FAMILY].get(b"b").is_some());
+        assert!(mem_tables[&DEFAULT_COLUMN_FAMILY].get(b"c").is_none());
+        wal.writer.write_all(&buf[buf.len() / 2..]).unwrap();
+        wal.sync().unwrap();
+        assert!(tail.replay_appended(&mut mem_tables).unwrap());
+        assert_eq!(mem_tables[&DEFAULT_COLUMN_FAMILY].get(b"c").unwrap().timestamp, 3);
+
+        // once the wal rolls, everything has to be replayed again.
+        let mut batch = WriteBatch::new();
+        batch.put(b"d", b"value_d");
+        wal.prepare("xid", &batch).unwrap();
+        wal.roll().unwrap();
+        assert!(!tail.replay_appended(&mut mem_tables).unwrap());
+        let mem_tables = tail.replay().unwrap();
+        assert_eq!(mem_tables[&DEFAULT_COLUMN_FAMILY].get(b"c").unwrap().timestamp, 3);
+        assert_eq!(tail.prepared().keys().collect::<Vec<_>>(), vec!["xid"]);
+        drop(wal);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_write_group() {
+        let path = PathBuf::from(format!("./{}", "WAL_test_write_group"));
+        fs::create_dir(&path).unwrap();
+
+        let mut wal = WAL::new(&path).unwrap();
+        let mut single = WriteBatch::new();
+        single.put(b"a", b"value_a");
+        let mut several = WriteBatch::new();
+        several.put(b"b", b"value_b");
+        several.delete(b"a");
+        wal.write_group(&[(&single, 1), (&several, 2)], true).unwrap();
+
+        // one op is logged as a plain record, more as a batch record.
+        let file = OpenOptions::new().read(true).open(&wal.path).unwrap();
+        let mut reader = BufReader::new(file);
+        check_entry(&mut reader, b"a", Some(b"value_a"), 1, false);
+        let mut kind = [0; 9];
+        reader.read_exact(&mut kind).unwrap();
+        assert_eq!(kind[8], super::RECORD_BATCH);
+        drop(wal);
+
+        let (_, mem_table) = recover(&path).unwrap();
+        let a = mem_table.get(b"a").unwrap();
+        assert!(a.deleted);
+        assert_eq!(a.timestamp, 2);
+        assert_eq!(mem_table.get(b"b").unwrap().timestamp, 2);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,135 @@
+use crate::column_family::{ColumnFamilyHandle, DEFAULT_COLUMN_FAMILY};
+
+/// A single operation buffered inside a `WriteBatch`.
+#[derive(Clone)]
+pub enum BatchOp {
+    Put { column_family: u32, key: Vec<u8>, value: Vec<u8> },
+    Delete { column_family: u32, key: Vec<u8> },
+    Merge { column_family: u32, key: Vec<u8>, operand: Vec<u8> },
+    /// Deletes every key in [start, end).
+    DeleteRange { column_family: u32, start: Vec<u8>, end: Vec<u8> },
+    /// Puts a value that expires `ttl` microseconds after the timestamp of the write.
+    PutWithTtl { column_family: u32, key: Vec<u8>, value: Vec<u8>, ttl: u128 },
+}
+
+impl BatchOp {
+    pub fn column_family(&self) -> u32 {
+        match self {
+            BatchOp::Put { column_family, .. }
+            | BatchOp::Delete { column_family, .. }
+            | BatchOp::Merge { column_family, .. }
+            | BatchOp::DeleteRange { column_family, .. }
+            | BatchOp::PutWithTtl { column_family, .. } => *column_family,
+        }
+    }
+}
+
+/// A group of writes that is logged to the WAL as one record and applied
+/// to the mem_table together, so either all of them survive a crash or none.
+/// The writes may go to different column families.
+#[derive(Clone, Default)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch { ops: vec![] }
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.put_in(DEFAULT_COLUMN_FAMILY, key, value);
+    }
+
+    pub fn put_cf(&mut self, cf: &ColumnFamilyHandle, key: &[u8], value: &[u8]) {
+        self.put_in(cf.id, key, value);
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.delete_in(DEFAULT_COLUMN_FAMILY, key);
+    }
+
+    pub fn delete_cf(&mut self, cf: &ColumnFamilyHandle, key: &[u8]) {
+        self.delete_in(cf.id, key);
+    }
+
+    pub fn merge(&mut self, key: &[u8], operand: &[u8]) {
+        self.merge_in(DEFAULT_COLUMN_FAMILY, key, operand);
+    }
+
+    pub fn merge_cf(&mut self, cf: &ColumnFamilyHandle, key: &[u8], operand: &[u8]) {
+        self.merge_in(cf.id, key, operand);
+    }
+
+    pub fn delete_range(&mut self, start: &[u8], end: &[u8]) {
+        self.delete_range_in(DEFAULT_COLUMN_FAMILY, start, end);
+    }
+
+    pub fn delete_range_cf(&mut self, cf: &ColumnFamilyHandle, start: &[u8], end: &[u8]) {
+        self.delete_range_in(cf.id, start, end);
+    }
+
+    pub(crate) fn put_in(&mut self, column_family: u32, key: &[u8], value: &[u8]) {
+        self.ops.push(BatchOp::Put {
+            column_family,
+            key: key.to_owned(),
+            value: value.to_owned(),
+        });
+    }
+
+    pub(crate) fn delete_in(&mut self, column_family: u32, key: &[u8]) {
+        self.ops.push(BatchOp::Delete {
+            column_family,
+            key: key.to_owned(),
+        });
+    }
+
+    pub(crate) fn merge_in(&mut self, column_family: u32, key: &[u8], operand: &[u8]) {
+        self.ops.push(BatchOp::Merge {
+            column_family,
+            key: key.to_owned(),
+            operand: operand.to_owned(),
+        });
+    }
+
+    pub(crate) fn delete_range_in(&mut self, column_family: u32, start: &[u8], end: &[u8]) {
+        self.ops.push(BatchOp::DeleteRange {
+            column_family,
+            start: start.to_owned(),
+            end: end.to_owned(),
+        });
+    }
+
+    pub(crate) fn put_with_ttl_in(&mut self, column_family: u32, key: &[u8], value: &[u8], ttl: u128) {
+        self.ops.push(BatchOp::PutWithTtl {
+            column_family,
+            key: key.to_owned(),
+            value: value.to_owned(),
+            ttl,
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Bytes of keys and values in the batch, roughly what it takes up in the WAL.
+    pub(crate) fn data_size(&self) -> usize {
+        self.ops.iter()
+            .map(|op| match op {
+                BatchOp::Put { key, value, .. } | BatchOp::PutWithTtl { key, value, .. } => key.len() + value.len(),
+                BatchOp::Delete { key, .. } => key.len(),
+                BatchOp::Merge { key, operand, .. } => key.len() + operand.len(),
+                BatchOp::DeleteRange { start, end, .. } => start.len() + end.len(),
+            })
+            .sum()
+    }
+
+    pub(crate) fn iter(&self) -> std::slice::Iter<'_, BatchOp> {
+        self.ops.iter()
+    }
+}
@@ -0,0 +1,185 @@
+use pipengine::{
+    ColumnFamilyOptions, Db, Error, MergeOperator, Options, ReadOptions, TransactionOptions, WriteBatch,
+    WriteOptions,
+};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+fn fresh_dir(name: &str) -> &Path {
+    let path = Path::new(name);
+    if path.exists() {
+        fs::remove_dir_all(path).unwrap();
+    }
+    path
+}
+
+#[test]
+fn put_get_delete_survive_reopen() {
+    let path = fresh_dir("./DB_it_put_get_delete");
+    let db = Db::create(path, Options::default()).unwrap();
+    db.put("a", "value-a").unwrap();
+    db.put(b"\x00\xff", [1u8, 2, 3]).unwrap();
+    db.put("b", "value-b").unwrap();
+    db.delete("b").unwrap();
+    assert_eq!(db.get("a").unwrap().unwrap(), b"value-a");
+    assert_eq!(db.get(b"\x00\xff").unwrap().unwrap(), [1, 2, 3]);
+    assert!(db.get("b").unwrap().is_none());
+    db.close().unwrap();
+
+    let db = Db::open(path, Options::default()).unwrap();
+    assert_eq!(db.get_string("a").unwrap().as_deref(), Some("value-a"));
+    assert_eq!(db.get(b"\x00\xff").unwrap().unwrap(), [1, 2, 3]);
+    assert!(db.get("b").unwrap().is_none());
+    db.close().unwrap();
+
+    fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn errors_are_typed() {
+    let path = fresh_dir("./DB_it_errors");
+    assert!(matches!(Db::open(path, Options::default()), Err(Error::NotFound(_))));
+
+    let db = Db::create(path, Options::default()).unwrap();
+    assert!(matches!(db.merge("k", "v"), Err(Error::InvalidArgument(_))));
+    assert!(matches!(db.delete_range("z", "a"), Err(Error::InvalidArgument(_))));
+    db.create_column_family("cf", ColumnFamilyOptions::default()).unwrap();
+    let err = db.create_column_family("cf", ColumnFamilyOptions::default()).unwrap_err();
+    assert!(matches!(err, Error::AlreadyExists(_)));
+    assert_eq!(err.to_string(), "already exists: column family cf");
+    db.close().unwrap();
+
+    fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn databases_live_side_by_side() {
+    let root = fresh_dir("./DB_it_side_by_side");
+    let options = Options {
+        create_if_missing: true,
+        ..Options::default()
+    };
+    let first = Db::open(root.join("first"), options.clone()).unwrap();
+    let second = Db::open(root.join("nested").join("second"), options.clone()).unwrap();
+    first.put("k", "first").unwrap();
+    second.put("k", "second").unwrap();
+    first.close().unwrap();
+    second.close().unwrap();
+
+    let first = Db::open(root.join("first"), options.clone()).unwrap();
+    let second = Db::open(root.join("nested").join("second"), options).unwrap();
+    assert_eq!(first.get("k").unwrap().unwrap(), b"first");
+    assert_eq!(second.get("k").unwrap().unwrap(), b"second");
+    first.close().unwrap();
+    second.close().unwrap();
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn iterator_respects_bounds() {
+    let path = fresh_dir("./DB_it_iterator");
+    let db = Db::create(path, Options::default()).unwrap();
+    for key in ["a", "b", "c", "d"].iter() {
+        db.put(key, format!("value-{}", key)).unwrap();
+    }
+
+    let keys: Vec<Vec<u8>> = db.iter(&ReadOptions::default()).unwrap().map(|(key, _)| key).collect();
+    assert_eq!(keys, vec![b"a", b"b", b"c", b"d"]);
+
+    let options = ReadOptions {
+        iterate_lower_bound: Some(b"b".to_vec()),
+        iterate_upper_bound: Some(b"d".to_vec()),
+    };
+    let entries: Vec<(Vec<u8>, Vec<u8>)> = db.iter(&options).unwrap().collect();
+    assert_eq!(entries, vec![
+        (b"b".to_vec(), b"value-b".to_vec()),
+        (b"c".to_vec(), b"value-c".to_vec()),
+    ]);
+    let last = db.iter(&ReadOptions::default()).unwrap().next_back().unwrap();
+    assert_eq!(last.0, b"d");
+
+    fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn synced_write_batch_is_atomic_and_durable() {
+    let path = fresh_dir("./DB_it_write_batch");
+    let db = Db::create(path, Options::default()).unwrap();
+    db.put("old-1", "x").unwrap();
+    db.put("old-2", "x").unwrap();
+
+    let mut batch = WriteBatch::new();
+    batch.put(b"new", b"y");
+    batch.delete_range(b"old-1", b"old-3");
+    db.write_opt(batch, &WriteOptions { sync: true }).unwrap();
+    drop(db);
+
+    let db = Db::open(path, Options::default()).unwrap();
+    assert_eq!(db.range_strings("a", "z").unwrap(), vec!["y"]);
+
+    fs::remove_dir_all(path).unwrap();
+}
+
+struct Concat;
+
+impl MergeOperator for Concat {
+    fn name(&self) -> &str {
+        "concat"
+    }
+
+    fn full_merge(&self, _key: &[u8], existing: Option<&[u8]>, operands: &[Vec<u8>]) -> Vec<u8> {
+        let mut value = existing.unwrap_or_default().to_vec();
+        for operand in operands {
+            value.extend_from_slice(operand);
+        }
+        value
+    }
+}
+
+#[test]
+fn merge_operator_from_options() {
+    let path = fresh_dir("./DB_it_merge");
+    let options = Options {
+        merge_operator: Some(Arc::new(Concat)),
+        ..Options::default()
+    };
+    let db = Db::create(path, options.clone()).unwrap();
+    db.merge("log", "a").unwrap();
+    db.merge("log", "b").unwrap();
+    db.close().unwrap();
+
+    let db = Db::open(path, options).unwrap();
+    db.merge("log", "c").unwrap();
+    assert_eq!(db.get("log").unwrap().unwrap(), b"abc");
+
+    fs::remove_dir_all(path).unwrap();
+}
+
+#[test]
+fn transactions_and_column_families() {
+    let path = fresh_dir("./DB_it_transactions");
+    let db = Db::create(path, Options::default()).unwrap();
+    let users = db.create_column_family("users", ColumnFamilyOptions::default()).unwrap();
+    db.put_cf(&users, "alice", "1").unwrap();
+    assert!(db.get("alice").unwrap().is_none());
+
+    let mut txn = db.begin_transaction();
+    assert!(txn.get(&db, "balance").unwrap().is_none());
+    txn.put("balance", "10");
+    txn.commit(&db).unwrap();
+    assert_eq!(db.get("balance").unwrap().unwrap(), b"10");
+
+    let mut txn = db.begin_pessimistic_transaction(TransactionOptions::default());
+    txn.put("balance", "20").unwrap();
+    txn.commit(&db).unwrap();
+    db.close().unwrap();
+
+    let db = Db::open(path, Options::default()).unwrap();
+    let users = db.column_family("users").unwrap();
+    assert_eq!(db.get_cf(&users, "alice").unwrap().unwrap(), b"1");
+    assert_eq!(db.get("balance").unwrap().unwrap(), b"20");
+
+    fs::remove_dir_all(path).unwrap();
+}